@@ -1,6 +1,15 @@
+use std::sync::Arc;
+
 use mycelium_matrix_chat::{
     bridge::{MatrixMyceliumBridge},
     config::BridgeConfig,
+    consumer::run_mycelium_consumer,
+    database::{create_pool, run_migrations, Database},
+    edu::run_edu_relay,
+    gossip::run_route_gossip,
+    health::run_route_prober,
+    outbox::run_outbox_worker,
+    routes::run_route_sync,
     server::start_bridge_server,
 };
 
@@ -14,11 +23,37 @@ async fn main() -> anyhow::Result<()> {
     // Load configuration
     let config = BridgeConfig::from_env()?;
 
+    // Connect to the database backing federation routes and the outbox
+    let pool = create_pool(&config.database_url).await?;
+    run_migrations(&pool).await?;
+
     // Create and initialize bridge
-    let bridge = MatrixMyceliumBridge::new(config).await?;
+    let bridge = Arc::new(
+        MatrixMyceliumBridge::new(config).await?
+            .with_database(Database::new(pool).await)
+    );
+    let route_count = bridge.load_federation_routes().await?;
+    tracing::info!("Loaded {} federation routes", route_count);
+    let policy_count = bridge.load_transport_policies().await?;
+    tracing::info!("Loaded {} stored transport policies", policy_count);
+
+    // Start background workers
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    let outbox_worker = tokio::spawn(run_outbox_worker(bridge.clone(), shutdown_rx.clone()));
+    let mycelium_consumer = tokio::spawn(run_mycelium_consumer(bridge.clone(), shutdown_rx.clone()));
+    let route_sync = tokio::spawn(run_route_sync(bridge.clone(), shutdown_rx.clone()));
+    let route_gossip = tokio::spawn(run_route_gossip(bridge.clone(), shutdown_rx.clone()));
+    let route_prober = tokio::spawn(run_route_prober(bridge.clone(), shutdown_rx.clone()));
+    let edu_relay = tokio::spawn(run_edu_relay(bridge.clone(), shutdown_rx));
 
     // Start server
-    start_bridge_server(bridge).await?;
+    tokio::select! {
+        result = start_bridge_server(bridge) => result?,
+        _ = tokio::signal::ctrl_c() => tracing::info!("Shutting down"),
+    }
+
+    let _ = shutdown_tx.send(true);
+    let _ = tokio::join!(outbox_worker, mycelium_consumer, route_sync, route_gossip, route_prober, edu_relay);
 
     Ok(())
 }
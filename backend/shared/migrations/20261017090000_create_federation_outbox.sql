@@ -0,0 +1,20 @@
+-- Durable outbox for outgoing federation requests
+
+CREATE TABLE federation_outbox (
+    id BIGSERIAL PRIMARY KEY,
+    destination VARCHAR(255) NOT NULL,
+    method VARCHAR(16) NOT NULL,
+    path TEXT NOT NULL,
+    body JSONB,
+    headers JSONB NOT NULL DEFAULT '{}',
+    -- pending, sending or failed; delivered entries are deleted
+    state VARCHAR(20) NOT NULL DEFAULT 'pending',
+    attempts INTEGER NOT NULL DEFAULT 0,
+    last_error TEXT,
+    next_attempt_at BIGINT NOT NULL,
+    updated_at BIGINT NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT NOW()
+);
+
+CREATE INDEX idx_federation_outbox_due ON federation_outbox(state, next_attempt_at);
+CREATE INDEX idx_federation_outbox_destination ON federation_outbox(destination, id);
@@ -0,0 +1,16 @@
+-- Tell other bridge instances sharing this database when a route changes,
+-- so their in-memory route caches stay coherent.
+CREATE OR REPLACE FUNCTION notify_federation_route_change() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP = 'DELETE' THEN
+        PERFORM pg_notify('federation_routes', OLD.destination_server);
+    ELSE
+        PERFORM pg_notify('federation_routes', NEW.destination_server);
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER federation_routes_notify
+AFTER INSERT OR UPDATE OR DELETE ON federation_routes
+FOR EACH ROW EXECUTE FUNCTION notify_federation_route_change();
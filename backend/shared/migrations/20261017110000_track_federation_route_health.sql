@@ -0,0 +1,8 @@
+-- Health of each federation route as measured by the route prober
+
+ALTER TABLE federation_routes
+    ADD COLUMN consecutive_failures INTEGER NOT NULL DEFAULT 0,
+    ADD COLUMN healthy BOOLEAN NOT NULL DEFAULT TRUE,
+    -- Latency of classic federation to the same server, for comparison
+    ADD COLUMN matrix_latency_ms BIGINT,
+    ADD COLUMN last_probed BIGINT;
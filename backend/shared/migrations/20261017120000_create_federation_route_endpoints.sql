@@ -0,0 +1,16 @@
+-- Several Mycelium nodes per destination server. federation_routes.mycelium_key
+-- stays the preferred endpoint so older readers keep working.
+
+CREATE TABLE federation_route_endpoints (
+    destination_server VARCHAR(255) NOT NULL
+        REFERENCES federation_routes (destination_server) ON DELETE CASCADE,
+    mycelium_key VARCHAR(255) NOT NULL,
+    -- Lower priorities are tried first
+    priority INTEGER NOT NULL DEFAULT 0,
+    -- Share of the load among endpoints with the same priority
+    weight INTEGER NOT NULL DEFAULT 1 CHECK (weight > 0),
+    PRIMARY KEY (destination_server, mycelium_key)
+);
+
+INSERT INTO federation_route_endpoints (destination_server, mycelium_key)
+SELECT destination_server, mycelium_key FROM federation_routes;
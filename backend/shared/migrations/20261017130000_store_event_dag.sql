@@ -0,0 +1,51 @@
+-- Keep the full PDU of each event, and its place in the room DAG
+
+ALTER TABLE matrix_events
+    ADD COLUMN depth BIGINT NOT NULL DEFAULT 0,
+    -- As received, in order; event_edges and event_auth_edges index them
+    ADD COLUMN prev_events JSONB NOT NULL DEFAULT '[]',
+    ADD COLUMN auth_events JSONB NOT NULL DEFAULT '[]',
+    ADD COLUMN hashes JSONB,
+    ADD COLUMN signatures JSONB,
+    ADD COLUMN unsigned JSONB,
+    ADD COLUMN redacts VARCHAR(255);
+
+-- Events stored before the DAG was tracked get depths from their order in the room
+UPDATE matrix_events
+SET depth = ranked.depth
+FROM (
+    SELECT event_id, ROW_NUMBER() OVER (PARTITION BY room_id ORDER BY origin_server_ts, event_id) AS depth
+    FROM matrix_events
+) AS ranked
+WHERE matrix_events.event_id = ranked.event_id;
+
+CREATE INDEX idx_matrix_events_room_depth ON matrix_events(room_id, depth);
+
+-- prev_events edges; the referenced event may not have been received yet
+CREATE TABLE event_edges (
+    event_id VARCHAR(255) NOT NULL REFERENCES matrix_events(event_id) ON DELETE CASCADE,
+    prev_event_id VARCHAR(255) NOT NULL,
+    room_id VARCHAR(255) NOT NULL,
+    PRIMARY KEY (event_id, prev_event_id)
+);
+
+CREATE INDEX idx_event_edges_prev_event_id ON event_edges(prev_event_id);
+
+CREATE TABLE event_auth_edges (
+    event_id VARCHAR(255) NOT NULL REFERENCES matrix_events(event_id) ON DELETE CASCADE,
+    auth_event_id VARCHAR(255) NOT NULL,
+    PRIMARY KEY (event_id, auth_event_id)
+);
+
+-- Events of each room no other stored event lists in its prev_events
+CREATE TABLE room_forward_extremities (
+    room_id VARCHAR(255) NOT NULL,
+    event_id VARCHAR(255) NOT NULL REFERENCES matrix_events(event_id) ON DELETE CASCADE,
+    PRIMARY KEY (room_id, event_id)
+);
+
+-- Without edges, the latest existing event of each room is its extremity
+INSERT INTO room_forward_extremities (room_id, event_id)
+SELECT DISTINCT ON (room_id) room_id, event_id
+FROM matrix_events
+ORDER BY room_id, origin_server_ts DESC, event_id DESC;
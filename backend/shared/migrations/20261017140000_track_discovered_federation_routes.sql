@@ -0,0 +1,5 @@
+-- Routes learned through route discovery, as opposed to configured ones, are
+-- checked again against what their server advertises after a restart
+
+ALTER TABLE federation_routes
+    ADD COLUMN discovered BOOLEAN NOT NULL DEFAULT FALSE;
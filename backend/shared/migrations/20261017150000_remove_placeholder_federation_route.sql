@@ -0,0 +1,5 @@
+-- The initial schema seeded a placeholder route to matrix.org whose key is
+-- no Mycelium key, which would send all federation with it nowhere
+
+DELETE FROM federation_routes
+WHERE destination_server = 'matrix.org' AND mycelium_key = 'default-key-1';
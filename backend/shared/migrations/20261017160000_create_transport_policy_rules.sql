@@ -0,0 +1,24 @@
+-- Transport policies set through the bridge API. They override configured
+-- rules with the same pattern and are shared by every bridge instance.
+
+CREATE TABLE transport_policy_rules (
+    pattern VARCHAR(255) PRIMARY KEY,
+    policy VARCHAR(32) NOT NULL
+        CHECK (policy IN ('mycelium_only', 'matrix_only', 'prefer_mycelium', 'blocked'))
+);
+
+-- Tell other bridge instances to reload their policies when a rule changes
+CREATE OR REPLACE FUNCTION notify_transport_policy_change() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP = 'DELETE' THEN
+        PERFORM pg_notify('transport_policies', OLD.pattern);
+    ELSE
+        PERFORM pg_notify('transport_policies', NEW.pattern);
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER transport_policy_rules_notify
+AFTER INSERT OR UPDATE OR DELETE ON transport_policy_rules
+FOR EACH ROW EXECUTE FUNCTION notify_transport_policy_change();
@@ -0,0 +1,5 @@
+-- Nodes learned from route announcements are only kept while their server
+-- keeps announcing them; configured and discovered nodes have no expiry
+
+ALTER TABLE federation_route_endpoints
+    ADD COLUMN expires_at BIGINT;
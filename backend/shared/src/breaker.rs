@@ -0,0 +1,147 @@
+use serde::{Deserialize, Serialize};
+use tokio::time::{Duration, Instant};
+
+/// Where a destination's circuit breaker stands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    /// Requests go over Mycelium as usual.
+    Closed,
+    /// Mycelium failed too often; requests skip it until the cool-down passes.
+    Open,
+    /// The cool-down passed and one trial request is finding out whether Mycelium recovered.
+    HalfOpen,
+}
+
+/// A breaker's state as reported by the status and routes APIs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CircuitStatus {
+    pub state: CircuitState,
+    /// Mycelium requests that failed in a row.
+    pub consecutive_failures: u32,
+    /// Seconds until an open circuit lets a trial request through.
+    pub retry_in: Option<u64>,
+}
+
+/// Circuit breaker for the Mycelium transport to one destination server. After
+/// `failure_threshold` failures in a row it opens and requests fail fast to
+/// classic federation; once `open_duration` has passed a single trial request
+/// is let through, closing the circuit if it succeeds and reopening it if not.
+#[derive(Debug, Clone)]
+pub struct CircuitBreaker {
+    state: CircuitState,
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+    failure_threshold: u32,
+    open_duration: Duration,
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, open_duration: Duration) -> Self {
+        Self {
+            state: CircuitState::Closed,
+            consecutive_failures: 0,
+            opened_at: None,
+            failure_threshold: failure_threshold.max(1),
+            open_duration,
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        self.state
+    }
+
+    /// Whether a request may go over Mycelium now. An open circuit whose
+    /// cool-down has passed turns half-open and admits one trial; should the
+    /// trial never report back, another is admitted after a further cool-down.
+    pub fn allow_request(&mut self) -> bool {
+        match self.state {
+            CircuitState::Closed => true,
+            CircuitState::Open | CircuitState::HalfOpen if self.cool_down_passed() => {
+                self.state = CircuitState::HalfOpen;
+                self.opened_at = Some(Instant::now());
+                true
+            }
+            CircuitState::Open | CircuitState::HalfOpen => false,
+        }
+    }
+
+    pub fn record_success(&mut self) {
+        self.state = CircuitState::Closed;
+        self.consecutive_failures = 0;
+        self.opened_at = None;
+    }
+
+    /// Count a failed request; returns true if this opened the circuit.
+    pub fn record_failure(&mut self) -> bool {
+        self.consecutive_failures += 1;
+        let opens = match self.state {
+            CircuitState::HalfOpen => true,
+            CircuitState::Closed => self.consecutive_failures >= self.failure_threshold,
+            CircuitState::Open => false,
+        };
+        if opens {
+            self.state = CircuitState::Open;
+            self.opened_at = Some(Instant::now());
+        }
+        opens
+    }
+
+    pub fn status(&self) -> CircuitStatus {
+        let retry_in = match (self.state, self.opened_at) {
+            (CircuitState::Open, Some(opened_at)) => Some(
+                self.open_duration.saturating_sub(opened_at.elapsed()).as_secs()
+            ),
+            _ => None,
+        };
+        CircuitStatus {
+            state: self.state,
+            consecutive_failures: self.consecutive_failures,
+            retry_in,
+        }
+    }
+
+    fn cool_down_passed(&self) -> bool {
+        self.opened_at.is_none_or(|opened_at| opened_at.elapsed() >= self.open_duration)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_circuit_breaker_transitions() {
+        let mut breaker = CircuitBreaker::new(3, Duration::from_millis(20));
+        assert!(breaker.allow_request());
+        assert!(!breaker.record_failure());
+        assert!(!breaker.record_failure());
+        breaker.record_success();
+        assert_eq!(breaker.status().consecutive_failures, 0);
+
+        assert!(!breaker.record_failure());
+        assert!(!breaker.record_failure());
+        assert!(breaker.record_failure());
+        assert_eq!(breaker.state(), CircuitState::Open);
+
+        // With the cool-down over only one trial gets through
+        assert!(!breaker.allow_request());
+        std::thread::sleep(Duration::from_millis(25));
+        assert!(breaker.allow_request());
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        assert!(!breaker.allow_request());
+
+        // A failed trial reopens the circuit, a successful one closes it
+        assert!(breaker.record_failure());
+        assert_eq!(breaker.state(), CircuitState::Open);
+        std::thread::sleep(Duration::from_millis(25));
+        assert!(breaker.allow_request());
+        breaker.record_success();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+
+        let mut breaker = CircuitBreaker::new(1, Duration::from_secs(60));
+        breaker.record_failure();
+        assert!(!breaker.allow_request());
+        assert!(breaker.status().retry_in.is_some_and(|secs| secs <= 60));
+    }
+}
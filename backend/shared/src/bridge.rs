@@ -1,18 +1,72 @@
+use std::collections::HashSet;
 use std::time::SystemTime;
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::Arc;
 use tokio::sync::Mutex;
 use tokio::time::{timeout, Duration};
 
+use crate::breaker::{CircuitBreaker, CircuitState, CircuitStatus};
 use crate::config::BridgeConfig;
+use crate::consumer::decode_inbound_payload;
+use crate::envelope::{encode_topic, flags_accept, Compression, Envelope, EnvelopeKind};
+use crate::fragment::{fragment_envelope, Fragment, Reassembler, ReplyTarget, FRAGMENT_TOPIC};
+use crate::database::Database;
+use crate::discovery::{Discovery, RouteDiscovery, RouteResolver};
+use crate::edu::{edu_topic, EduQueue, EDU_TOPIC_PREFIX};
 use crate::error::{BridgeError, Result};
+use crate::gossip::{RouteAnnouncement, ANNOUNCEMENT_MISSES, ANNOUNCE_TOPIC, MAX_ANNOUNCEMENT_AGE};
+use crate::health::{RouteHealth, PROBE_PATH};
+use crate::keys::{resolve_federation_url, ServerKeyStore};
+use crate::policy::{glob_match, TransportPolicies, TransportPolicy};
+use crate::pubkey::{parse_overlay_address, parse_public_key, PubkeyResolver};
+use crate::transaction::{check_transaction_limits, TransactionCache};
+use crate::state::{auth_chain_from_state, server_in_room};
+use crate::signing::{content_hash, redact_pdu, reference_event_id, verify_json, verify_request, RoomVersion, ServerSigningKey, XMatrixAuth};
 use crate::types::*;
 
 pub struct MatrixMyceliumBridge {
     pub config: BridgeConfig,
     matrix_client: reqwest::Client,
     mycelium_client: Option<reqwest::Client>,
+    signing_key: Arc<ServerSigningKey>,
+    key_store: Arc<ServerKeyStore>,
+    database: Option<Arc<Database>>,
     server_discovery: Arc<Mutex<std::collections::HashMap<String, FederationRoute>>>,
-    pending_messages: Arc<Mutex<std::collections::HashMap<String, tokio::sync::oneshot::Sender<FederationResponse>>>>,
+    /// Requests sent over Mycelium that are still waiting for their reply.
+    pending_messages: AtomicU32,
+    /// Fragments of oversized Mycelium messages waiting for the rest of their message.
+    reassembler: Reassembler,
+    /// Latest envelope flags seen from each Mycelium peer, for negotiating compression.
+    peer_envelope_flags: Mutex<std::collections::HashMap<String, u16>>,
+    /// Finds routes for servers nobody configured one for.
+    route_discovery: RouteDiscovery,
+    /// Our own Mycelium public key, once configured or learned from the node.
+    local_mycelium_pubkey: Mutex<Option<String>>,
+    /// Rolling probe results per destination server.
+    route_health: Mutex<std::collections::HashMap<String, RouteHealth>>,
+    /// Public keys of the overlay addresses routes point at.
+    pubkey_resolver: PubkeyResolver,
+    /// Which transports each destination may use.
+    transport_policies: TransportPolicies,
+    /// Per-destination circuit breakers over the Mycelium transport.
+    circuit_breakers: Mutex<std::collections::HashMap<String, CircuitBreaker>>,
+    /// Responses to recently received transactions, for answering retries.
+    transactions: TransactionCache,
+    /// EDUs waiting to be relayed over Mycelium.
+    edu_queue: EduQueue,
+    /// Versions of the rooms we've seen create events of, which never change.
+    room_versions: Mutex<std::collections::HashMap<String, RoomVersion>>,
+}
+
+/// What `send_via_mycelium` does when Mycelium can't deliver a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MyceliumFailure {
+    /// Queue transactions for retry on timeout, fall back to Matrix otherwise.
+    QueueOrFallback,
+    /// Fall back to Matrix, leaving retries to the caller.
+    Fallback,
+    /// Report the failure; probes measure Mycelium itself.
+    Report,
 }
 
 impl MatrixMyceliumBridge {
@@ -35,22 +89,90 @@ impl MatrixMyceliumBridge {
             None
         };
 
+        let signing_key = match &config.signing_key_path {
+            Some(path) => ServerSigningKey::load_or_generate(path)?,
+            None => {
+                tracing::warn!("No signing_key_path configured, using an ephemeral server signing key");
+                ServerSigningKey::generate()
+            }
+        };
+
+        let key_client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .map_err(|e| BridgeError::Config {
+                message: format!("Failed to create key client: {}", e),
+            })?;
+        let route_discovery = RouteDiscovery::from_config(&config, key_client.clone());
+        let key_store = ServerKeyStore::new(key_client, config.trusted_key_servers.clone());
+        // Requests we sign ourselves must verify without a network round trip
+        key_store
+            .insert_verify_key(&config.server_name, &signing_key.key_id(), signing_key.verifying_key(), u64::MAX)
+            .await;
+
+        let reassembler = Reassembler::new(
+            Duration::from_secs(config.mycelium_reassembly_timeout),
+            MAX_REASSEMBLED_MESSAGE_SIZE,
+            config.mycelium_reassembly_memory_limit,
+        );
+
+        let local_mycelium_pubkey = config.mycelium_public_key.clone();
+        let transport_policies = TransportPolicies::new(config.default_transport_policy, config.transport_policies.clone());
+
         Ok(Self {
             config,
             matrix_client,
             mycelium_client,
+            signing_key: Arc::new(signing_key),
+            key_store: Arc::new(key_store),
+            database: None,
             server_discovery: Arc::new(Mutex::new(std::collections::HashMap::new())),
-            pending_messages: Arc::new(Mutex::new(std::collections::HashMap::new())),
+            pending_messages: AtomicU32::new(0),
+            reassembler,
+            peer_envelope_flags: Mutex::new(std::collections::HashMap::new()),
+            route_discovery,
+            local_mycelium_pubkey: Mutex::new(local_mycelium_pubkey),
+            route_health: Mutex::new(std::collections::HashMap::new()),
+            pubkey_resolver: PubkeyResolver::new(),
+            transport_policies,
+            circuit_breakers: Mutex::new(std::collections::HashMap::new()),
+            transactions: TransactionCache::new(),
+            edu_queue: EduQueue::new(),
+            room_versions: Mutex::new(std::collections::HashMap::new()),
         })
     }
 
+    /// Attach the database used for the durable outbox.
+    pub fn with_database(mut self, database: Database) -> Self {
+        self.database = Some(Arc::new(database));
+        self
+    }
+
+    /// Replace the configured route discovery resolvers.
+    pub fn with_route_resolvers(mut self, resolvers: Vec<Box<dyn RouteResolver>>) -> Self {
+        self.route_discovery = RouteDiscovery::new(
+            resolvers,
+            Duration::from_secs(self.config.route_discovery_ttl),
+            Duration::from_secs(self.config.route_discovery_negative_ttl),
+        );
+        self
+    }
+
     pub async fn handle_federation_request(
         &self,
         request: FederationRequest
     ) -> Result<FederationResponse> {
         // Check if we can use Mycelium for this request
-        if let Some(destination) = self.extract_server_name(&request.path) {
-            if self.should_use_mycelium(&destination).await {
+        if let Some(destination) = self.extract_server_name(&request) {
+            if self.route_via_mycelium(&destination).await? {
+                let Some(request) = self.split_off_edus(request, &destination).await? else {
+                    // Nothing but EDUs, all of which are relayed on their own topics
+                    return Ok(FederationResponse {
+                        status_code: 200,
+                        body: serde_json::json!({"pdus": {}}),
+                        headers: std::collections::HashMap::new(),
+                    });
+                };
                 return self.handle_via_mycelium(request, destination).await;
             }
         }
@@ -59,6 +181,158 @@ impl MatrixMyceliumBridge {
         self.handle_via_matrix(request).await
     }
 
+    /// Queue the EDUs of an outgoing transaction that have a Mycelium topic of
+    /// their own, returning the request without them, or `None` if nothing is left.
+    async fn split_off_edus(&self, mut request: FederationRequest, destination: &str) -> Result<Option<FederationRequest>> {
+        let is_transaction = request.method.eq_ignore_ascii_case("PUT")
+            && request.path.starts_with("/_matrix/federation/v1/send/");
+        let Some(body) = request.body.as_mut().filter(|_| is_transaction && self.config.edu_flush_interval > 0) else {
+            return Ok(Some(request));
+        };
+        let Some(edus) = body.get_mut("edus").filter(|edus| edus.is_array()).map(serde_json::Value::take) else {
+            return Ok(Some(request));
+        };
+
+        let edus: Vec<Edu> = serde_json::from_value(edus)?;
+        let (relayed, kept): (Vec<Edu>, Vec<Edu>) = edus.into_iter()
+            .partition(|edu| edu_topic(&edu.edu_type).is_some());
+        for edu in relayed {
+            self.edu_queue.push(destination, edu).await;
+        }
+
+        let no_pdus = body.get("pdus").and_then(|v| v.as_array()).is_none_or(|pdus| pdus.is_empty());
+        if no_pdus && kept.is_empty() {
+            return Ok(None);
+        }
+        body["edus"] = serde_json::to_value(kept)?;
+        Ok(Some(request))
+    }
+
+    /// Relay every queued EDU, one message per destination and topic. Returns
+    /// how many messages went out; EDUs that couldn't be sent are dropped.
+    pub async fn flush_edus(&self) -> usize {
+        let pending = self.edu_queue.drain(Duration::from_secs(self.config.edu_ttl)).await;
+
+        let mut batches: Vec<(String, &'static str, Vec<Edu>)> = Vec::new();
+        for (destination, edus) in pending {
+            for edu in edus {
+                let Some(topic) = edu_topic(&edu.edu_type) else { continue };
+                match batches.iter_mut().find(|(d, t, _)| *d == destination && *t == topic) {
+                    Some((_, _, batch)) => batch.push(edu),
+                    None => batches.push((destination.clone(), topic, vec![edu])),
+                }
+            }
+        }
+
+        let sends = batches.into_iter().map(|(destination, topic, edus)| async move {
+            let count = edus.len();
+            match self.send_edus(&destination, topic, edus).await {
+                Ok(()) => true,
+                Err(e) => {
+                    tracing::debug!("Dropping {} EDUs for {}: {}", count, destination, e);
+                    false
+                }
+            }
+        });
+        futures::future::join_all(sends).await.into_iter().filter(|sent| *sent).count()
+    }
+
+    /// Send EDUs to `destination` as a transaction on `topic`, without waiting
+    /// for a reply. Falls back to Matrix once if the destination's policy allows.
+    async fn send_edus(&self, destination: &str, topic: &str, edus: Vec<Edu>) -> Result<()> {
+        let now_ms = SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as u64;
+        let mut request = FederationRequest {
+            method: "PUT".to_string(),
+            path: format!("/_matrix/federation/v1/send/{}", uuid::Uuid::new_v4().simple()),
+            body: Some(serde_json::json!({
+                "origin": self.config.server_name,
+                "origin_server_ts": now_ms,
+                "pdus": [],
+                "edus": edus
+            })),
+            headers: std::collections::HashMap::from([("Destination".to_string(), destination.to_string())]),
+        };
+
+        if !self.route_via_mycelium(destination).await? {
+            self.handle_via_matrix(request).await?;
+            return Ok(());
+        }
+
+        self.sign_outgoing_request(&mut request, Some(destination))?;
+        let result = self.push_to_mycelium(destination, topic, &request).await;
+        match result {
+            Err(e) if self.transport_policies.policy_for(destination).await != TransportPolicy::MyceliumOnly => {
+                tracing::debug!("Relaying EDUs to {} over Mycelium failed, sending over Matrix: {}", destination, e);
+                self.handle_via_matrix(request).await?;
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    /// Push `request` to the first endpoint of `destination` that accepts it,
+    /// as an event on `topic` that isn't replied to.
+    async fn push_to_mycelium(&self, destination: &str, topic: &str, request: &FederationRequest) -> Result<()> {
+        let (Some(mycelium_url), Some(client)) = (&self.config.mycelium_api_url, &self.mycelium_client) else {
+            return Err(BridgeError::Config {
+                message: "Mycelium API URL not configured".to_string()
+            });
+        };
+        let route = self.get_mycelium_route(destination).await?;
+        let payload = serde_json::json!({
+            "method": request.method,
+            "path": request.path,
+            "body": request.body,
+            "headers": request.headers,
+            "timestamp": unix_now()
+        });
+
+        let mut last_error = None;
+        for mycelium_key in route.endpoint_order() {
+            let result = async {
+                let dest_pubkey = self.get_destination_pubkey(destination, &mycelium_key, client, mycelium_url).await?;
+                let envelope = Envelope::new(EnvelopeKind::Event, payload.clone())
+                    .compressed(self.compression_for(&dest_pubkey).await, self.config.mycelium_compression_threshold)?;
+                let (topic, message) = match fragment_envelope(&envelope, topic, self.config.mycelium_max_message_size)? {
+                    Some(mut fragments) => {
+                        let last = fragments.pop().expect("fragmented payloads have several fragments");
+                        self.send_fragments(client, mycelium_url, &dest_pubkey, &fragments).await?;
+                        (FRAGMENT_TOPIC.to_string(), last)
+                    }
+                    None => (topic.to_string(), envelope),
+                };
+
+                let response = client
+                    .post(format!("{}/api/v1/messages", mycelium_url))
+                    .json(&serde_json::json!({
+                        "dst": { "pk": dest_pubkey },
+                        "topic": encode_topic(&topic),
+                        "payload": message.to_base64()?
+                    }))
+                    .send()
+                    .await
+                    .map_err(|e| BridgeError::MyceliumApi {
+                        message: format!("Failed to send via Mycelium: {}", e)
+                    })?;
+                if !response.status().is_success() {
+                    return Err(BridgeError::Unreachable {
+                        message: format!("Mycelium node returned {} for {}", response.status(), dest_pubkey)
+                    });
+                }
+                Ok(())
+            }.await;
+
+            match result {
+                Ok(()) => return Ok(()),
+                Err(e) => last_error = Some(e),
+            }
+        }
+
+        Err(last_error.unwrap_or(BridgeError::Unreachable {
+            message: format!("No Mycelium endpoint for {}", destination)
+        }))
+    }
+
     pub async fn test_federation_connection(
         &self,
         server_name: &str,
@@ -67,7 +341,7 @@ impl MatrixMyceliumBridge {
         let start_time = std::time::Instant::now();
 
         // Check if we can use Mycelium for this request
-        let routing_method = if self.should_use_mycelium(server_name).await {
+        let routing_method = if self.route_via_mycelium(server_name).await? {
             "mycelium"
         } else {
             "matrix"
@@ -87,43 +361,405 @@ impl MatrixMyceliumBridge {
         }
     }
 
-    pub async fn translate_matrix_to_mycelium(&self, event: MatrixEvent) -> Result<MyceliumFederationMessage> {
-        let topic = self.determine_mycelium_topic(&event);
+    /// One message per remote server in the event's room; empty when nobody
+    /// else is in it.
+    pub async fn translate_matrix_to_mycelium(&self, event: MatrixEvent) -> Result<Vec<MyceliumFederationMessage>> {
+        let pdu = event.to_pdu();
+        self.room_messages(&event, pdu).await
+    }
+
+    /// One message per remote server in the room of `event`, each carrying
+    /// `pdu` untouched: any change to its keys would break its hashes and
+    /// signatures. Routing metadata lives on the message itself.
+    async fn room_messages(&self, event: &MatrixEvent, pdu: serde_json::Value) -> Result<Vec<MyceliumFederationMessage>> {
+        let topic = self.determine_mycelium_topic(event);
 
         // Find destination servers for this room
         let destinations = self.get_room_servers(&event.room_id).await?;
 
-        // Create comprehensive message payload with all Matrix event fields
-        let mut payload = serde_json::json!({
-            "event_id": event.event_id,
-            "event_type": event.event_type,
-            "room_id": event.room_id,
-            "sender": event.sender,
-            "origin_server_ts": event.origin_server_ts,
-            "content": event.content
+        Ok(destinations.into_iter()
+            .map(|destination| MyceliumFederationMessage {
+                topic: topic.clone(),
+                room_id: Some(event.room_id.clone()),
+                sender: event.sender.clone(),
+                origin_server_ts: event.origin_server_ts,
+                payload: pdu.clone(),
+                destination,
+                message_id: None,
+                source_pubkey: None,
+                envelope_id: None,
+                envelope_flags: 0,
+            })
+            .collect())
+    }
+
+    /// Send `event` to every other server in its room as a federation
+    /// transaction, reporting how delivery went for each of them.
+    pub async fn send_event_to_room(&self, event: MatrixEvent) -> Result<Vec<DestinationResult>> {
+        let messages = self.translate_matrix_to_mycelium(event.clone()).await?;
+        let deliveries = messages.into_iter().map(|message| self.deliver_to_destination(&event.event_id, message));
+        Ok(futures::future::join_all(deliveries).await)
+    }
+
+    async fn deliver_to_destination(&self, event_id: &str, message: MyceliumFederationMessage) -> DestinationResult {
+        let destination = message.destination.clone();
+
+        // One transaction per event, so a repeated delivery is deduplicated by the receiver
+        let txn_id = percent_encoding::utf8_percent_encode(event_id, percent_encoding::NON_ALPHANUMERIC).to_string();
+        let now_ms = SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as u64;
+
+        let request = FederationRequest {
+            method: "PUT".to_string(),
+            path: format!("/_matrix/federation/v1/send/{}", txn_id),
+            body: Some(serde_json::json!({
+                "origin": self.config.server_name,
+                "origin_server_ts": now_ms,
+                "pdus": [message.payload]
+            })),
+            headers: std::collections::HashMap::from([("Destination".to_string(), destination.clone())]),
+        };
+
+        match self.handle_federation_request(request).await {
+            Ok(response) => DestinationResult::from_response(destination, &response),
+            Err(e) => {
+                tracing::warn!("Failed to deliver event {} to {}: {}", event_id, destination, e);
+                DestinationResult::failed(destination, &e)
+            }
+        }
+    }
+
+    /// Process a transaction `origin` sent us with `PUT /send/{txn_id}`. A
+    /// transaction retried with the same txn id gets the first response again.
+    pub async fn process_transaction(self: &Arc<Self>, origin: &str, txn_id: &str, transaction: Transaction) -> Result<TransactionResponse> {
+        if transaction.origin != origin {
+            return Err(BridgeError::Auth {
+                message: format!("Transaction claims origin {} but was sent by {}", transaction.origin, origin)
+            });
+        }
+        check_transaction_limits(&transaction)?;
+
+        let response = self.transactions
+            .get_or_process(origin, txn_id, || self.process_pdus(origin, transaction.pdus))
+            .await;
+        Ok(response)
+    }
+
+    async fn process_pdus(self: &Arc<Self>, origin: &str, pdus: Vec<serde_json::Value>) -> TransactionResponse {
+        let mut response = TransactionResponse::default();
+        for pdu in pdus {
+            // Results are keyed by event ID, so a PDU whose ID can't be derived can't be answered for
+            let identified = self.pdu_room_version(&pdu).await
+                .and_then(|version| Ok((version, pdu_event_id(&pdu, version)?)));
+            let (version, event_id) = match identified {
+                Ok(identified) => identified,
+                Err(e) => {
+                    tracing::warn!("Dropping PDU from {} without a usable event ID: {}", origin, e);
+                    continue;
+                }
+            };
+
+            let result = match self.process_pdu(origin, version, &event_id, pdu).await {
+                Ok(()) => PduResult::default(),
+                Err(e) => {
+                    tracing::warn!("Rejected PDU {} from {}: {}", event_id, origin, e);
+                    PduResult { error: Some(e.to_string()) }
+                }
+            };
+            response.pdus.insert(event_id, result);
+        }
+        response
+    }
+
+    /// Accept one PDU of a room of `version`: check it, store it, and relay it
+    /// to the room's other servers the first time it's seen.
+    async fn process_pdu(self: &Arc<Self>, origin: &str, version: RoomVersion, event_id: &str, pdu: serde_json::Value) -> Result<()> {
+        let event = event_from_pdu(&pdu, event_id).map_err(|e| BridgeError::InvalidRequest {
+            message: format!("Invalid PDU: {}", e)
+        })?;
+        if !event.room_id.starts_with('!') {
+            return Err(BridgeError::InvalidRequest {
+                message: format!("Invalid room ID {}", event.room_id)
+            });
+        }
+        if !self.validate_matrix_event(&event.event_type, &event.content) {
+            return Err(BridgeError::InvalidRequest {
+                message: format!("Invalid content for {} event", event.event_type)
+            });
+        }
+        self.verify_pdu(&pdu, version).await?;
+
+        if !self.store_pdu(&event).await? {
+            return Ok(());
+        }
+
+        // Relaying and fetching missing history take round trips the sender
+        // shouldn't wait on, so they finish after the transaction is answered
+        let bridge = self.clone();
+        let origin = origin.to_string();
+        tokio::spawn(async move {
+            tokio::join!(bridge.fill_gaps(&origin, &event), bridge.relay_pdu(&origin, &event, pdu));
         });
+        Ok(())
+    }
 
-        // Add state_key if present (for state events)
-        if let Some(state_key) = event.state_key {
-            payload["state_key"] = serde_json::Value::String(state_key);
-        }
-
-        // Add additional metadata for federation
-        payload["federation_version"] = serde_json::json!("v1");
-        payload["origin_server"] = serde_json::json!(self.extract_server_from_user_id(&event.sender));
-
-        Ok(MyceliumFederationMessage {
-            topic,
-            room_id: Some(event.room_id),
-            sender: event.sender,
-            origin_server_ts: event.origin_server_ts,
-            payload,
-            destination: destinations.first()
-                .ok_or_else(|| BridgeError::Federation {
-                    message: "No destination servers found".to_string()
-                })?
-                .to_string(),
-        })
+    /// Check that `pdu`'s content matches its content hash and that the servers
+    /// that must sign it did: its sender's, and in room versions 1 and 2 also
+    /// the one that named it. Signatures cover the redacted event.
+    async fn verify_pdu(&self, pdu: &serde_json::Value, version: RoomVersion) -> Result<()> {
+        let unverified = |message: String| BridgeError::Auth { message };
+
+        let expected_hash = pdu.get("hashes")
+            .and_then(|hashes| hashes.get("sha256"))
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| unverified("PDU has no content hash".to_string()))?;
+        if content_hash(pdu)? != expected_hash.trim_end_matches('=') {
+            return Err(unverified("PDU content does not match its content hash".to_string()));
+        }
+
+        let mut signing_servers: Vec<&str> = pdu.get("sender")
+            .and_then(|v| v.as_str())
+            .and_then(server_name_from_id)
+            .into_iter()
+            .collect();
+        if signing_servers.is_empty() {
+            return Err(unverified("PDU has no valid sender".to_string()));
+        }
+        if !version.has_hashed_event_ids() {
+            let named_by = pdu.get("event_id")
+                .and_then(|v| v.as_str())
+                .and_then(server_name_from_id)
+                .ok_or_else(|| unverified("PDU has no valid event_id".to_string()))?;
+            if !signing_servers.contains(&named_by) {
+                signing_servers.push(named_by);
+            }
+        }
+
+        let redacted = redact_pdu(pdu, version);
+        for server_name in signing_servers {
+            let key_ids: Vec<&String> = pdu.get("signatures")
+                .and_then(|signatures| signatures.get(server_name))
+                .and_then(|v| v.as_object())
+                .into_iter()
+                .flat_map(|signatures| signatures.keys())
+                .filter(|key_id| key_id.starts_with("ed25519:"))
+                .collect();
+
+            let mut last_error = unverified(format!("PDU is not signed by {}", server_name));
+            let mut verified = false;
+            for key_id in key_ids {
+                let result = match self.key_store.get_verify_key(server_name, key_id).await {
+                    Ok(verify_key) => verify_json(&redacted, server_name, key_id, &verify_key),
+                    Err(e) => Err(e),
+                };
+                match result {
+                    Ok(()) => {
+                        verified = true;
+                        break;
+                    }
+                    Err(e) => last_error = e,
+                }
+            }
+            if !verified {
+                return Err(last_error);
+            }
+        }
+        Ok(())
+    }
+
+    /// Send `pdu`, as received, on to the room's servers other than `origin`.
+    async fn relay_pdu(&self, origin: &str, event: &MatrixEvent, pdu: serde_json::Value) {
+        let messages = match self.room_messages(event, pdu).await {
+            Ok(messages) => messages,
+            Err(e) => {
+                tracing::warn!("Failed to relay {}: {}", event.event_id, e);
+                return;
+            }
+        };
+        let deliveries = messages.into_iter()
+            .filter(|message| message.destination != origin)
+            .map(|message| self.deliver_to_destination(&event.event_id, message));
+        for result in futures::future::join_all(deliveries).await {
+            if result.status == "failed" {
+                tracing::warn!("Failed to relay {} to {}: {:?}", event.event_id, result.destination, result.error);
+            }
+        }
+    }
+
+    /// Room state just before `event_id` in `room_id` with its auth chain, for
+    /// `/state` and `/state_ids`. Only servers in the room may ask.
+    pub async fn room_state_at(&self, origin: &str, room_id: &str, event_id: &str) -> Result<RoomSnapshot> {
+        let database = self.database()?;
+        let event = database.get_event(event_id).await?
+            .filter(|event| event.room_id == room_id)
+            .ok_or(BridgeError::NotFound)?;
+        let state = database.get_state_before(&event).await?;
+        ensure_server_in_room(origin, &state, &event)?;
+
+        let auth_chain = if state.iter().any(|event| !event.auth_events.is_empty()) {
+            let state_ids: Vec<String> = state.iter().map(|event| event.event_id.clone()).collect();
+            database.get_auth_chain(&state_ids).await?
+        } else {
+            auth_chain_from_state(&state)
+        };
+        Ok(RoomSnapshot { state, auth_chain })
+    }
+
+    /// Up to `limit` events of `room_id` walking back breadth-first from the
+    /// events in `from`, those included, for `/backfill`.
+    pub async fn backfill(&self, origin: &str, room_id: &str, from: &[String], limit: usize) -> Result<Vec<MatrixEvent>> {
+        let database = self.database()?;
+        let start = database.get_room_events(room_id, from).await?;
+        self.check_server_in_room_at(origin, &start).await?;
+
+        let start_ids: Vec<String> = start.into_iter().map(|event| event.event_id).collect();
+        database.walk_events_backwards(room_id, &start_ids, &HashSet::new(), limit.clamp(1, MAX_BACKFILL_EVENTS), 0).await
+    }
+
+    /// The events between `earliest_events` and `latest_events` of `room_id`,
+    /// neither included, oldest first, for `/get_missing_events`.
+    pub async fn missing_events(&self, origin: &str, room_id: &str, request: MissingEventsRequest) -> Result<Vec<MatrixEvent>> {
+        let database = self.database()?;
+        let latest = database.get_room_events(room_id, &request.latest_events).await?;
+        self.check_server_in_room_at(origin, &latest).await?;
+
+        let start: Vec<String> = latest.iter().flat_map(|event| event.prev_events.iter().cloned()).collect();
+        let stop: HashSet<String> = request.earliest_events.into_iter().chain(request.latest_events).collect();
+        let limit = request.limit.clamp(1, MAX_BACKFILL_EVENTS);
+        let mut events = database.walk_events_backwards(room_id, &start, &stop, limit, request.min_depth).await?;
+        events.sort_by_key(|event| event.depth);
+        Ok(events)
+    }
+
+    /// Whether `origin` is in the room at the deepest of `events`.
+    async fn check_server_in_room_at(&self, origin: &str, events: &[MatrixEvent]) -> Result<()> {
+        let event = events.iter().max_by_key(|event| event.depth).ok_or(BridgeError::NotFound)?;
+        let state = self.database()?.get_state_before(event).await?;
+        ensure_server_in_room(origin, &state, event)
+    }
+
+    /// Store `event`; returns false if it was already stored.
+    async fn store_pdu(&self, event: &MatrixEvent) -> Result<bool> {
+        match &self.database {
+            Some(database) => database.store_event(event).await,
+            None => {
+                // With nowhere to look create events up later, remember the versions they name
+                if event.event_type == "m.room.create" && event.state_key.as_deref() == Some("") {
+                    if let Ok(version) = RoomVersion::of_create_content(&event.content) {
+                        self.room_versions.lock().await.entry(event.room_id.clone()).or_insert(version);
+                    }
+                }
+                Ok(true)
+            }
+        }
+    }
+
+    /// The version of the room `pdu` is in: the one it names if it's the
+    /// room's create event, the one its room was created with otherwise.
+    async fn pdu_room_version(&self, pdu: &serde_json::Value) -> Result<RoomVersion> {
+        if pdu.get("type").and_then(|v| v.as_str()) == Some("m.room.create")
+            && pdu.get("state_key").and_then(|v| v.as_str()) == Some("")
+        {
+            return RoomVersion::of_create_content(pdu.get("content").unwrap_or(&serde_json::Value::Null));
+        }
+        let room_id = pdu.get("room_id").and_then(|v| v.as_str()).ok_or_else(|| BridgeError::InvalidRequest {
+            message: "PDU has no room_id".to_string()
+        })?;
+        self.room_version(room_id).await
+    }
+
+    /// The version `room_id` was created with, from its stored create event.
+    /// Events of rooms whose creation we haven't seen can't be checked or
+    /// named, so they're refused.
+    async fn room_version(&self, room_id: &str) -> Result<RoomVersion> {
+        if let Some(version) = self.room_versions.lock().await.get(room_id) {
+            return Ok(*version);
+        }
+
+        let content = match &self.database {
+            Some(database) => database.get_room_create_content(room_id).await?,
+            None => None,
+        };
+        let version = content
+            .ok_or_else(|| BridgeError::InvalidRequest {
+                message: format!("Version of room {} is unknown", room_id)
+            })
+            .and_then(|content| RoomVersion::of_create_content(&content))?;
+        self.room_versions.lock().await.insert(room_id.to_string(), version);
+        Ok(version)
+    }
+
+    /// Fetch the history `event` skips over from `origin`, logging failures.
+    async fn fill_gaps(&self, origin: &str, event: &MatrixEvent) {
+        if self.database.is_none() {
+            return;
+        }
+        if let Err(e) = self.heal_gaps(origin, event).await {
+            tracing::warn!("Failed to fetch events missing before {} from {}: {}", event.event_id, origin, e);
+        }
+    }
+
+    /// Ask `origin` for the events between our forward extremities and `event`
+    /// when some of its prev_events are unknown, and store them. A single round
+    /// trip; whatever gap is left is filled by later backfill.
+    async fn heal_gaps(&self, origin: &str, event: &MatrixEvent) -> Result<()> {
+        let database = self.database()?;
+        let unknown = database.get_unknown_events(&event.prev_events).await?;
+        if unknown.is_empty() || origin == self.config.server_name {
+            return Ok(());
+        }
+
+        tracing::debug!("{} references {} unknown events, asking {}", event.event_id, unknown.len(), origin);
+        let earliest_events = database.get_forward_extremities(&event.room_id).await?
+            .into_iter()
+            .filter(|event_id| *event_id != event.event_id)
+            .collect();
+        let room_id = percent_encoding::utf8_percent_encode(&event.room_id, percent_encoding::NON_ALPHANUMERIC);
+        let body = MissingEventsRequest {
+            earliest_events,
+            latest_events: vec![event.event_id.clone()],
+            limit: MISSING_EVENTS_FETCH_LIMIT,
+            min_depth: 0,
+        };
+        let request = FederationRequest {
+            method: "POST".to_string(),
+            path: format!("/_matrix/federation/v1/get_missing_events/{}", room_id),
+            body: Some(serde_json::to_value(body)?),
+            headers: std::collections::HashMap::from([("Destination".to_string(), origin.to_string())]),
+        };
+
+        let response = self.handle_federation_request(request).await?;
+        if response.status_code >= 400 {
+            return Err(BridgeError::Federation {
+                message: format!("get_missing_events answered with {}", response.status_code)
+            });
+        }
+
+        let version = self.room_version(&event.room_id).await?;
+        let mut events = Vec::new();
+        for pdu in response.body.get("events").and_then(|v| v.as_array()).into_iter().flatten() {
+            let Some(missing) = pdu_event_id(pdu, version).ok().and_then(|event_id| event_from_pdu(pdu, &event_id).ok()) else {
+                continue;
+            };
+            if missing.room_id != event.room_id {
+                continue;
+            }
+            // Whoever answers only relays these events; their senders must have signed them
+            match self.verify_pdu(pdu, version).await {
+                Ok(()) => events.push(missing),
+                Err(e) => tracing::warn!("Dropping missing event {} from {}: {}", missing.event_id, origin, e),
+            }
+        }
+        // Parents first, so each event's edges land on stored events where possible
+        events.sort_by_key(|missing| missing.depth);
+        for missing in &events {
+            database.store_event(missing).await?;
+        }
+
+        let still_unknown = database.get_unknown_events(&event.prev_events).await?;
+        if !still_unknown.is_empty() {
+            tracing::debug!("{} still has {} unknown prev_events after fetching {}", event.event_id, still_unknown.len(), events.len());
+        }
+        Ok(())
     }
 
     pub async fn translate_mycelium_to_matrix(
@@ -138,7 +774,9 @@ impl MatrixMyceliumBridge {
             })?
             .to_string();
 
-        let event_type = mycelium_msg.payload.get("event_type")
+        // Older bridges named the type `event_type`
+        let event_type = mycelium_msg.payload.get("type")
+            .or_else(|| mycelium_msg.payload.get("event_type"))
             .and_then(|v| v.as_str())
             .ok_or_else(|| BridgeError::Serde {
                 message: "Missing event_type in Mycelium message".to_string()
@@ -183,6 +821,10 @@ impl MatrixMyceliumBridge {
             });
         }
 
+        // DAG fields travel along when the sending bridge had them
+        let dag: Option<MatrixEvent> = serde_json::from_value(mycelium_msg.payload.clone()).ok();
+        let dag = dag.unwrap_or_default();
+
         Ok(MatrixEvent {
             event_id,
             event_type,
@@ -191,6 +833,13 @@ impl MatrixMyceliumBridge {
             origin_server_ts,
             content,
             state_key,
+            prev_events: dag.prev_events,
+            auth_events: dag.auth_events,
+            depth: dag.depth,
+            hashes: dag.hashes,
+            signatures: dag.signatures,
+            unsigned: dag.unsigned,
+            redacts: dag.redacts,
         })
     }
 
@@ -214,7 +863,7 @@ impl MatrixMyceliumBridge {
     }
 
     pub async fn get_bridge_status(&self) -> Result<BridgeStatus> {
-        let server_discovery = self.server_discovery.lock().await;
+        let connected_servers = self.server_discovery.lock().await.len() as u32;
 
         let mycelium_connected = if let Some(client) = &self.mycelium_client {
             if let Some(api_url) = &self.config.mycelium_api_url {
@@ -231,19 +880,17 @@ impl MatrixMyceliumBridge {
             false
         };
 
-        let pending_count = {
-            let pending = self.pending_messages.lock().await;
-            pending.len() as u32
-        };
+        let pending_count = self.pending_messages.load(Ordering::Relaxed);
 
         Ok(BridgeStatus {
-            connected_servers: server_discovery.len() as u32,
+            connected_servers,
             pending_messages: pending_count,
             last_sync: SystemTime::now()
                 .duration_since(std::time::UNIX_EPOCH)
                 .unwrap()
                 .as_secs() as i64,
             mycelium_connected,
+            circuit_breakers: self.circuit_statuses().await,
         })
     }
 
@@ -252,18 +899,44 @@ impl MatrixMyceliumBridge {
         request: FederationRequest,
         destination: String
     ) -> Result<FederationResponse> {
+        self.send_via_mycelium(request, destination, MyceliumFailure::QueueOrFallback).await
+    }
+
+    /// Send a request over Mycelium and wait for the reply. When Mycelium can't
+    /// deliver it, `on_failure` decides whether retryable requests are handed to
+    /// the outbox, the request falls back to Matrix, or the error is returned.
+    async fn send_via_mycelium(
+        &self,
+        mut request: FederationRequest,
+        destination: String,
+        on_failure: MyceliumFailure,
+    ) -> Result<FederationResponse> {
+        // Destinations that must stay on Mycelium never fall back to Matrix
+        let policy = self.transport_policies.policy_for(&destination).await;
+        if policy == TransportPolicy::Blocked {
+            return Err(blocked_destination(&destination));
+        }
+        let may_fall_back = on_failure != MyceliumFailure::Report && policy != TransportPolicy::MyceliumOnly;
+
         // Get Mycelium route for destination
         let route = self.get_mycelium_route(&destination).await?;
 
-        // Create unique message ID for tracking
-        let message_id = format!("{}_{}", uuid::Uuid::new_v4(), std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_millis());
+        // While the destination's circuit is open, skip Mycelium instead of waiting
+        // out another timeout. Probes bypass the breaker, and their results close it.
+        if on_failure != MyceliumFailure::Report && !self.circuit_allows(&destination).await {
+            if !may_fall_back {
+                return Err(BridgeError::Unreachable {
+                    message: format!("Circuit to {} is open and it may only be reached over Mycelium", destination)
+                });
+            }
+            tracing::debug!("Circuit to {} is open, sending over Matrix", destination);
+            return self.handle_via_matrix(request).await;
+        }
+
+        self.sign_outgoing_request(&mut request, Some(&destination))?;
 
-        // Create Mycelium message payload with request tracking
+        // Create Mycelium message payload; the transport message id correlates the reply
         let message_payload = serde_json::json!({
-            "message_id": message_id,
             "method": request.method,
             "path": request.path,
             "body": request.body,
@@ -281,151 +954,502 @@ impl MatrixMyceliumBridge {
                     message: "Mycelium client not configured".to_string()
                 })?;
 
-            // Get the destination node's public key
-            let dest_pubkey = self.get_destination_pubkey(&destination, &route.mycelium_key, client, mycelium_url).await?;
-
-            // Create response channel for async response handling
-            let (response_tx, response_rx) = tokio::sync::oneshot::channel();
-
-            // Store the response channel
-            {
-                let mut pending = self.pending_messages.lock().await;
-                pending.insert(message_id.clone(), response_tx);
-            }
-
-            // Create the message to send via Mycelium
-            let mycelium_request = serde_json::json!({
-                "dst": { "pk": dest_pubkey },
-                "topic": format!("matrix.federation.{}", request.method.to_lowercase()).into_bytes(),
-                "payload": message_payload.to_string().into_bytes()
-            });
-
-            let response = client
-                .post(format!("{}/api/v1/messages", mycelium_url))
-                .json(&mycelium_request)
-                .send()
-                .await
-                .map_err(|e| BridgeError::MyceliumApi {
-                    message: format!("Failed to send via Mycelium: {}", e)
-                })?;
-
-            if response.status().is_success() {
-                tracing::info!(
-                    "Successfully sent federation request via Mycelium to {}: {} {} (ID: {})",
-                    destination, request.method, request.path, message_id
-                );
-
-                // Wait for response with timeout
-                match tokio::time::timeout(
-                    Duration::from_secs(self.config.federation_timeout),
-                    response_rx
-                ).await {
-                    Ok(Ok(federation_response)) => {
-                        tracing::info!("Received Mycelium response for message {}", message_id);
-                        return Ok(federation_response);
-                    },
-                    Ok(Err(_)) => {
-                        tracing::warn!("Response channel closed for message {}", message_id);
-                    },
-                    Err(_) => {
-                        tracing::warn!("Timeout waiting for Mycelium response for message {}", message_id);
+            // Endpoints are tried in priority order, spreading load by weight within a priority
+            let mut last_error = None;
+            for mycelium_key in route.endpoint_order() {
+                let dest_pubkey = match self.get_destination_pubkey(&destination, &mycelium_key, client, mycelium_url).await {
+                    Ok(pubkey) => pubkey,
+                    Err(e) => {
+                        tracing::warn!("Skipping Mycelium endpoint {} of {}: {}", mycelium_key, destination, e);
+                        last_error = Some(e);
+                        continue;
+                    }
+                };
+                match self.send_to_endpoint(client, mycelium_url, &dest_pubkey, &request.method, &message_payload).await {
+                    Ok(response) => {
+                        self.record_circuit_result(&destination, true).await;
+                        return Ok(response);
+                    }
+                    Err(e) => {
+                        tracing::warn!(
+                            "Mycelium endpoint {} of {} failed for {} {}: {}",
+                            dest_pubkey, destination, request.method, request.path, e
+                        );
+                        last_error = Some(e);
                     }
                 }
+            }
 
-                // Clean up pending message
-                {
-                    let mut pending = self.pending_messages.lock().await;
-                    pending.remove(&message_id);
-                }
+            if last_error.is_some() {
+                self.record_circuit_result(&destination, false).await;
+            }
 
-                // Return success status even if we didn't get a response
-                return Ok(FederationResponse {
-                    status_code: 200,
-                    body: serde_json::json!({
-                        "status": "sent_via_mycelium",
-                        "message_id": message_id
-                    })
-                });
-            } else {
-                // Clean up on failure
+            match last_error {
+                Some(BridgeError::Timeout)
+                    if on_failure == MyceliumFailure::QueueOrFallback && self.can_queue_for_retry(&request) =>
                 {
-                    let mut pending = self.pending_messages.lock().await;
-                    pending.remove(&message_id);
+                    return self.queue_for_retry(request, &destination, BridgeError::Timeout).await;
                 }
-
-                tracing::warn!(
-                    "Mycelium message send failed with status {}, falling back to Matrix",
-                    response.status()
-                );
+                Some(e) if !may_fall_back => return Err(e),
+                Some(e) => {
+                    tracing::warn!(
+                        "Mycelium couldn't deliver {} {} to {}: {}, falling back to Matrix",
+                        request.method, request.path, destination, e
+                    );
+                }
+                None => {}
             }
         }
 
+        if !may_fall_back {
+            return Err(BridgeError::Config {
+                message: "Mycelium API URL not configured".to_string()
+            });
+        }
+
         // Fall back to standard Matrix federation
         self.handle_via_matrix(request).await
     }
 
-    pub async fn handle_via_matrix(&self, request: FederationRequest) -> Result<FederationResponse> {
-        // Build Matrix federation URL
-        let url = format!("{}{}", self.config.matrix_homeserver_url, request.path);
-
-        // Build request
-        let mut req_builder = match request.method.as_str() {
-            "GET" => self.matrix_client.get(&url),
-            "POST" => self.matrix_client.post(&url),
-            "PUT" => self.matrix_client.put(&url),
-            "DELETE" => self.matrix_client.delete(&url),
-            _ => return Err(BridgeError::InvalidRequest {
-                message: format!("Unsupported method: {}", request.method)
-            }),
+    /// Send a request payload to one Mycelium node of the destination and wait
+    /// for its reply. A node that doesn't answer in time is a `Timeout`, one the
+    /// local node couldn't hand the message to is `Unreachable`.
+    async fn send_to_endpoint(
+        &self,
+        client: &reqwest::Client,
+        mycelium_url: &str,
+        dest_pubkey: &str,
+        method: &str,
+        message_payload: &serde_json::Value,
+    ) -> Result<FederationResponse> {
+        let envelope = Envelope::new(EnvelopeKind::Request, message_payload.clone())
+            .compressed(self.compression_for(dest_pubkey).await, self.config.mycelium_compression_threshold)?;
+        let topic = format!("matrix.federation.{}", method.to_lowercase());
+
+        // Oversized requests go out in fragments; the reply is waited on with the last one
+        let (topic, payload) = match fragment_envelope(&envelope, &topic, self.config.mycelium_max_message_size)? {
+            Some(mut fragments) => {
+                let last = fragments.pop().expect("fragmented payloads have several fragments");
+                self.send_fragments(client, mycelium_url, dest_pubkey, &fragments).await?;
+                (FRAGMENT_TOPIC.to_string(), last)
+            }
+            None => (topic, envelope.clone()),
         };
 
-        // Add headers
-        for (key, value) in &request.headers {
-            req_builder = req_builder.header(key, value);
-        }
+        let mycelium_request = serde_json::json!({
+            "dst": { "pk": dest_pubkey },
+            "topic": encode_topic(&topic),
+            "payload": payload.to_base64()?
+        });
 
-        // Add body if present
-        if let Some(body) = request.body {
-            req_builder = req_builder
-                .header("Content-Type", "application/json")
-                .json(&body);
+        // The node holds the HTTP request open until the peer replies or reply_timeout passes
+        let reply_timeout = self.config.federation_timeout;
+        let pending = PendingMessage::start(&self.pending_messages);
+        let response = client
+            .post(format!("{}/api/v1/messages", mycelium_url))
+            .query(&[("reply_timeout", reply_timeout)])
+            .timeout(Duration::from_secs(reply_timeout + 5))
+            .json(&mycelium_request)
+            .send()
+            .await;
+        drop(pending);
+
+        let response = response.map_err(|e| BridgeError::MyceliumApi {
+            message: format!("Failed to send via Mycelium: {}", e)
+        })?;
+
+        if response.status() == reqwest::StatusCode::REQUEST_TIMEOUT {
+            return Err(BridgeError::Timeout);
+        }
+        if !response.status().is_success() {
+            return Err(BridgeError::Unreachable {
+                message: format!("Mycelium node returned {} for {}", response.status(), dest_pubkey)
+            });
         }
 
-        // Send request
-        let response = req_builder.send().await?;
-        let status_code = response.status().as_u16();
-        let body: serde_json::Value = response.json().await
-            .map_err(|e| BridgeError::MatrixApi {
-                message: format!("Failed to parse response: {}", e)
-            })?;
+        let reply: MyceliumInboundMessage = response.json().await.map_err(|e| BridgeError::MyceliumApi {
+            message: format!("Invalid reply from Mycelium node: {}", e)
+        })?;
+        tracing::info!("Received Mycelium reply from {} (ID: {})", dest_pubkey, reply.id);
+
+        let reply = self.reply_envelope(&reply.src_pk, &reply.payload).await?;
+        self.record_peer_envelope_flags(dest_pubkey, reply.flags).await;
+        if reply.kind != EnvelopeKind::Response || reply.correlation_id != Some(envelope.message_id) {
+            return Err(BridgeError::InvalidResponse {
+                message: format!("Mycelium reply is a {:?} for {:?}, expected a response to {}", reply.kind, reply.correlation_id, envelope.message_id)
+            });
+        }
+        federation_response_from_reply(&reply.body)
+    }
 
-        Ok(FederationResponse { status_code, body })
+    /// Whether `request` can be handed to the outbox. Only transactions
+    /// (`PUT /send/{txnId}`) are idempotent enough to be replayed, and only a
+    /// bridge with a database has an outbox.
+    fn can_queue_for_retry(&self, request: &FederationRequest) -> bool {
+        self.database.is_some()
+            && request.method.eq_ignore_ascii_case("PUT")
+            && request.path.starts_with("/_matrix/federation/v1/send/")
     }
 
-    fn extract_server_name(&self, path: &str) -> Option<String> {
-        // Extract server name from federation path
-        // Matrix federation paths typically include destination server info in query params or headers
-        // For now, we'll look for common patterns
+    /// Persist a request that couldn't be delivered so the outbox worker retries
+    /// it. Requests the outbox can't take get `error`.
+    async fn queue_for_retry(
+        &self,
+        request: FederationRequest,
+        destination: &str,
+        error: BridgeError,
+    ) -> Result<FederationResponse> {
+        let Some(database) = self.database.as_ref().filter(|_| self.can_queue_for_retry(&request)) else {
+            return Err(error);
+        };
+
+        let id = database.enqueue_outbox_entry(destination, &request, unix_now()).await?;
+        tracing::info!("Queued {} {} to {} for retry as outbox entry {} ({})", request.method, request.path, destination, id, error);
+
+        Ok(FederationResponse {
+            status_code: 202,
+            body: serde_json::json!({
+                "status": "queued",
+                "outbox_id": id
+            }),
+            headers: std::collections::HashMap::new(),
+        })
+    }
 
-        // Check if this is a federation request with destination info
-        if path.starts_with("/_matrix/federation/") {
-            // In a real implementation, we'd extract from:
-            // - Query parameters (?destination=server.com)
-            // - Request headers (Destination: server.com)
-            // - Path segments for some endpoints
+    /// Deliver a queued outbox entry over whichever transport currently applies,
+    /// without re-queueing it on failure. Server errors count as failures.
+    pub async fn deliver_outbox_entry(&self, entry: &OutboxEntry) -> Result<FederationResponse> {
+        let request = entry.to_request();
 
-            // For now, return None to allow fallback logic to work
-            // This will be enhanced when we have proper request parsing
-            None
+        let response = if self.route_via_mycelium(&entry.destination).await? {
+            self.send_via_mycelium(request, entry.destination.clone(), MyceliumFailure::Fallback).await?
         } else {
-            None
+            self.handle_via_matrix(request).await?
+        };
+
+        if response.status_code >= 500 {
+            return Err(BridgeError::Federation {
+                message: format!("{} responded with {}", entry.destination, response.status_code)
+            });
         }
+
+        Ok(response)
     }
 
-    async fn should_use_mycelium(&self, destination: &str) -> bool {
+    pub async fn list_outbox_entries(
+        &self,
+        state: Option<&str>,
+        destination: Option<&str>,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<OutboxEntry>> {
+        self.database()?.list_outbox_entries(state, destination, limit, offset).await
+    }
+
+    pub async fn requeue_outbox_entry(&self, id: i64) -> Result<()> {
+        if self.database()?.requeue_outbox_entry(id, unix_now()).await? {
+            Ok(())
+        } else {
+            Err(BridgeError::NotFound)
+        }
+    }
+
+    pub async fn drop_outbox_entry(&self, id: i64) -> Result<()> {
+        if self.database()?.delete_outbox_entry(id).await? {
+            Ok(())
+        } else {
+            Err(BridgeError::NotFound)
+        }
+    }
+
+    pub fn database(&self) -> Result<&Database> {
+        self.database.as_deref().ok_or_else(|| BridgeError::Config {
+            message: "The bridge has no database configured".to_string()
+        })
+    }
+
+    pub async fn handle_via_matrix(&self, mut request: FederationRequest) -> Result<FederationResponse> {
+        let destination = self.extract_server_name(&request);
+        self.sign_outgoing_request(&mut request, destination.as_deref())?;
+
+        // Build Matrix federation URL
+        let url = format!("{}{}", self.config.matrix_homeserver_url, request.path);
+
+        // Build request
+        let mut req_builder = match request.method.as_str() {
+            "GET" => self.matrix_client.get(&url),
+            "POST" => self.matrix_client.post(&url),
+            "PUT" => self.matrix_client.put(&url),
+            "DELETE" => self.matrix_client.delete(&url),
+            _ => return Err(BridgeError::InvalidRequest {
+                message: format!("Unsupported method: {}", request.method)
+            }),
+        };
+
+        // Add headers
+        for (key, value) in &request.headers {
+            req_builder = req_builder.header(key, value);
+        }
+
+        // Add body if present
+        if let Some(body) = request.body {
+            req_builder = req_builder
+                .header("Content-Type", "application/json")
+                .json(&body);
+        }
+
+        // Send request; connection failures and timeouts map to their own error variants
+        let response = req_builder.send().await?;
+        let status = response.status();
+        let headers = forwarded_headers(response.headers());
+        let bytes = response.bytes().await?;
+
+        let body = if bytes.is_empty() {
+            serde_json::json!({})
+        } else {
+            match serde_json::from_slice(&bytes) {
+                Ok(body) => body,
+                // Proxies in front of homeservers answer errors with HTML; keep the status
+                Err(_) if !status.is_success() => serde_json::json!({
+                    "errcode": "M_UNKNOWN",
+                    "error": String::from_utf8_lossy(&bytes).chars().take(512).collect::<String>()
+                }),
+                Err(e) => return Err(BridgeError::InvalidResponse {
+                    message: format!("Failed to parse response: {}", e)
+                }),
+            }
+        };
+
+        Ok(FederationResponse { status_code: status.as_u16(), body, headers })
+    }
+
+    /// Our signed `/_matrix/key/v2/server` response.
+    pub fn server_keys(&self) -> Result<serde_json::Value> {
+        self.signing_key.server_keys_response(&self.config.server_name)
+    }
+
+    /// Verify the `X-Matrix` authorization of an inbound federation request and
+    /// return the authenticated origin server. Any one valid signature suffices.
+    pub async fn verify_federation_auth(
+        &self,
+        method: &str,
+        uri: &str,
+        authorizations: &[&str],
+        content: Option<&serde_json::Value>,
+    ) -> Result<String> {
+        let mut last_error = BridgeError::Auth {
+            message: "Missing X-Matrix authorization".to_string()
+        };
+
+        for header in authorizations {
+            let Some(auth) = XMatrixAuth::parse(header) else {
+                last_error = BridgeError::Auth {
+                    message: "Malformed X-Matrix authorization".to_string()
+                };
+                continue;
+            };
+
+            if auth.destination.as_ref().is_some_and(|d| d != &self.config.server_name) {
+                last_error = BridgeError::Auth {
+                    message: format!("Request is addressed to {}, not us", auth.destination.unwrap_or_default())
+                };
+                continue;
+            }
+
+            let verified = match self.key_store.get_verify_key(&auth.origin, &auth.key).await {
+                Ok(verify_key) => verify_request(&auth, method, uri, content, &verify_key),
+                Err(e) => Err(e),
+            };
+
+            match verified {
+                Ok(()) => return Ok(auth.origin),
+                Err(e) => last_error = e,
+            }
+        }
+
+        Err(last_error)
+    }
+
+    /// Attach an `Authorization: X-Matrix` header signed with our server key.
+    ///
+    /// Requests already signed by another origin are being relayed on that
+    /// server's behalf and are left untouched.
+    fn sign_outgoing_request(&self, request: &mut FederationRequest, destination: Option<&str>) -> Result<()> {
+        if !request.path.starts_with("/_matrix/federation/") {
+            return Ok(());
+        }
+
+        let existing = header_value(&request.headers, "Authorization").and_then(XMatrixAuth::parse);
+        if existing.is_some_and(|auth| auth.origin != self.config.server_name) {
+            return Ok(());
+        }
+
+        let authorization = self.signing_key.sign_request(
+            &request.method,
+            &request.path,
+            &self.config.server_name,
+            destination,
+            request.body.as_ref(),
+        )?;
+
+        request.headers.retain(|key, _| !key.eq_ignore_ascii_case("Authorization"));
+        request.headers.insert("Authorization".to_string(), authorization);
+        Ok(())
+    }
+
+    fn extract_server_name(&self, request: &FederationRequest) -> Option<String> {
+        if !request.path.starts_with("/_matrix/federation/") {
+            return None;
+        }
+
+        // An explicit Destination header always wins
+        if let Some(destination) = header_value(&request.headers, "Destination") {
+            if !destination.trim().is_empty() {
+                return Some(destination.trim().to_string());
+            }
+        }
+
+        // X-Matrix authorization carries destination="server" since Matrix v1.3
+        if let Some(destination) = header_value(&request.headers, "Authorization")
+            .and_then(XMatrixAuth::parse)
+            .and_then(|auth| auth.destination)
+        {
+            return Some(destination);
+        }
+
+        let url = reqwest::Url::parse(&format!("http://localhost{}", request.path)).ok()?;
+
+        // Query parameters (?destination=, query/profile?user_id=, query/directory?room_alias=)
+        for (key, value) in url.query_pairs() {
+            let server = match key.as_ref() {
+                "destination" => Some(value.to_string()),
+                "user_id" | "room_alias" => server_name_from_id(&value).map(str::to_string),
+                _ => None,
+            };
+            if let Some(server) = server.filter(|s| !s.is_empty()) {
+                return Some(server);
+            }
+        }
+
+        // Path segments: /_matrix/federation/{version}/{endpoint}/...
+        let segments: Vec<String> = url.path_segments()?
+            .map(|segment| percent_encoding::percent_decode_str(segment).decode_utf8_lossy().into_owned())
+            .collect();
+        let endpoint = segments.get(3).map(String::as_str)?;
+        let arg = |index: usize| segments.get(index).and_then(|id| server_name_from_id(id));
+
+        let server = match endpoint {
+            "make_join" | "make_leave" | "make_knock" => arg(4),
+            // Room v1/v2 event IDs still carry the origin server
+            "send_join" | "send_leave" | "send_knock" => arg(5).or_else(|| arg(4)),
+            // The invitee's server is the state_key of the invite event
+            "invite" => request.body.as_ref()
+                .and_then(|body| body.get("event").unwrap_or(body).get("state_key"))
+                .and_then(|v| v.as_str())
+                .and_then(server_name_from_id)
+                .or_else(|| arg(4)),
+            "state" | "state_ids" | "backfill" | "get_missing_events" | "event_auth"
+            | "timestamp_to_event" | "exchange_third_party_invite" | "event" => arg(4),
+            "user" if segments.get(4).map(String::as_str) == Some("devices") => arg(5),
+            _ => None,
+        };
+
+        server.map(str::to_string)
+    }
+
+    /// Whether a request to `destination` goes over Mycelium, as decided by the
+    /// destination's transport policy and whether it has a healthy route.
+    async fn route_via_mycelium(&self, destination: &str) -> Result<bool> {
+        match self.transport_policies.policy_for(destination).await {
+            TransportPolicy::Blocked => Err(blocked_destination(destination)),
+            TransportPolicy::MatrixOnly => Ok(false),
+            TransportPolicy::PreferMycelium => Ok(self.should_use_mycelium(destination).await),
+            TransportPolicy::MyceliumOnly if self.should_use_mycelium(destination).await => Ok(true),
+            TransportPolicy::MyceliumOnly => Err(BridgeError::Unreachable {
+                message: format!("{} may only be reached over Mycelium and has no usable route", destination)
+            }),
+        }
+    }
+
+    /// Whether the circuit breaker of `destination` lets a request over Mycelium.
+    async fn circuit_allows(&self, destination: &str) -> bool {
+        let mut breakers = self.circuit_breakers.lock().await;
+        match breakers.get_mut(destination) {
+            Some(breaker) => breaker.allow_request(),
+            None => true,
+        }
+    }
+
+    async fn record_circuit_result(&self, destination: &str, success: bool) {
+        let mut breakers = self.circuit_breakers.lock().await;
+        let breaker = breakers.entry(destination.to_string()).or_insert_with(|| CircuitBreaker::new(
+            self.config.circuit_failure_threshold,
+            Duration::from_secs(self.config.circuit_open_duration),
+        ));
+
+        if success {
+            if breaker.state() != CircuitState::Closed {
+                tracing::info!("Circuit to {} closed", destination);
+            }
+            breaker.record_success();
+        } else if breaker.record_failure() {
+            tracing::warn!(
+                "Circuit to {} opened after {} Mycelium failures",
+                destination, breaker.status().consecutive_failures
+            );
+        }
+    }
+
+    /// State of the circuit breaker of `destination`, `None` if Mycelium never failed for it.
+    pub async fn circuit_status(&self, destination: &str) -> Option<CircuitStatus> {
+        self.circuit_breakers.lock().await.get(destination).map(CircuitBreaker::status)
+    }
+
+    pub async fn circuit_statuses(&self) -> std::collections::HashMap<String, CircuitStatus> {
+        self.circuit_breakers.lock().await
+            .iter()
+            .map(|(destination, breaker)| (destination.clone(), breaker.status()))
+            .collect()
+    }
+
+    pub fn transport_policies(&self) -> &TransportPolicies {
+        &self.transport_policies
+    }
+
+    /// Set the policy for servers matching `pattern`, writing it through so it
+    /// survives restarts and reaches other instances.
+    pub async fn set_transport_policy(&self, pattern: &str, policy: TransportPolicy) -> Result<()> {
+        let rule = self.transport_policies.set(pattern, policy).await?;
+        if let Some(database) = &self.database {
+            database.store_transport_policy(&rule).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn remove_transport_policy(&self, pattern: &str) -> Result<()> {
+        let deleted = match &self.database {
+            Some(database) => database.delete_transport_policy(&pattern.trim().to_ascii_lowercase()).await?,
+            None => false,
+        };
+
+        match self.transport_policies.remove(pattern).await {
+            Err(BridgeError::NotFound) if deleted => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Replace the runtime policy rules with the ones stored in the database.
+    pub async fn load_transport_policies(&self) -> Result<usize> {
+        let rules = self.database()?.get_transport_policies().await?;
+        let count = rules.len();
+        self.transport_policies.replace_overrides(rules).await;
+        Ok(count)
+    }
+
+    async fn should_use_mycelium(&self, destination: &str) -> bool {
         self.config.mycelium_enabled
             && self.mycelium_client.is_some()
-            && self.get_mycelium_route(destination).await.is_ok()
+            && self.get_mycelium_route(destination).await.is_ok_and(|route| route.healthy)
     }
 
     fn determine_mycelium_topic(&self, event: &MatrixEvent) -> String {
@@ -440,208 +1464,1126 @@ impl MatrixMyceliumBridge {
         }
     }
 
-    fn extract_server_from_user_id(&self, user_id: &str) -> String {
-        // Matrix user IDs are in format @user:server.com
-        if let Some(server_part) = user_id.split(':').nth(1) {
-            server_part.to_string()
-        } else {
-            "unknown".to_string()
-        }
-    }
-
+    /// Remote servers with joined members in `room_id`. Without membership
+    /// data the server the room ID names is all we know of.
     async fn get_room_servers(&self, room_id: &str) -> Result<Vec<String>> {
-        // Extract server names from room ID
         // Matrix room IDs are in format !room:server.com
-        if let Some(server_part) = room_id.split(':').nth(1) {
-            // For now, return the server from the room ID
-            // In a full implementation, this would query the database
-            // for all servers that have users in this room
-            Ok(vec![server_part.to_string()])
-        } else {
-            Err(BridgeError::InvalidRequest {
+        let Some(room_server) = server_name_from_id(room_id) else {
+            return Err(BridgeError::InvalidRequest {
                 message: format!("Invalid room ID format: {}", room_id)
-            })
+            });
+        };
+
+        let mut servers = match &self.database {
+            Some(database) => database.get_room_servers(room_id).await?,
+            None => Vec::new(),
+        };
+        if servers.is_empty() {
+            servers.push(room_server.to_string());
         }
+
+        servers.retain(|server| !server.eq_ignore_ascii_case(&self.config.server_name));
+        servers.sort();
+        servers.dedup();
+        Ok(servers)
     }
 
     pub async fn add_federation_route(&self, server_name: String, mycelium_key: String) -> Result<()> {
-        let mut discovery = self.server_discovery.lock().await;
-        let route = FederationRoute {
-            destination_server: server_name.clone(),
-            mycelium_key,
-            last_successful: std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_secs() as i64,
-            latency_ms: 0, // Will be updated on successful communication
-        };
-        discovery.insert(server_name, route);
+        self.set_federation_route_endpoints(server_name, vec![MyceliumEndpoint::new(mycelium_key)]).await
+    }
+
+    /// Route `server_name` over the given set of Mycelium nodes, replacing any previous set.
+    pub async fn set_federation_route_endpoints(&self, server_name: String, mut endpoints: Vec<MyceliumEndpoint>) -> Result<()> {
+        validate_endpoints(&endpoints)?;
+        for endpoint in &mut endpoints {
+            endpoint.expires_at = None;
+        }
+
+        // A configured route overrides whatever discovery found, or didn't
+        self.route_discovery.forget(&server_name).await;
+        self.record_route(FederationRoute::with_endpoints(server_name, endpoints, unix_now())).await
+    }
+
+    async fn record_route(&self, route: FederationRoute) -> Result<()> {
+        // Latency and health are filled in by the route prober
+        self.route_health.lock().await.remove(&route.destination_server);
+        self.save_route(route).await
+    }
+
+    /// Store `route` as it is, health included.
+    async fn save_route(&self, route: FederationRoute) -> Result<()> {
+        // Write through, so the route survives restarts and reaches other instances
+        if let Some(database) = &self.database {
+            database.store_federation_route(&route).await?;
+        }
+
+        self.server_discovery.lock().await.insert(route.destination_server.clone(), route);
         Ok(())
     }
 
+    /// Drop the endpoints of `route` its server stopped announcing, and the
+    /// route itself once none are left. Returns what remains of it.
+    async fn expire_endpoints(&self, mut route: FederationRoute) -> Option<FederationRoute> {
+        let now = unix_now();
+        if !route.endpoints.iter().any(|endpoint| endpoint.has_expired(now)) {
+            return Some(route);
+        }
+
+        let live: Vec<MyceliumEndpoint> = route.endpoints.iter()
+            .filter(|endpoint| !endpoint.has_expired(now))
+            .cloned()
+            .collect();
+        if live.is_empty() {
+            tracing::info!("Route to {} expired, its server stopped announcing it", route.destination_server);
+            let _ = self.remove_federation_route(&route.destination_server).await;
+            return None;
+        }
+
+        route.set_endpoints(live);
+        if let Err(e) = self.save_route(route.clone()).await {
+            tracing::warn!("Failed to drop expired endpoints of route to {}: {}", route.destination_server, e);
+        }
+        Some(route)
+    }
+
+    /// Expire announced endpoints across all routes. Returns how many routes
+    /// were dropped for having none left.
+    pub async fn expire_announced_routes(&self) -> usize {
+        let routes: Vec<FederationRoute> = self.server_discovery.lock().await.values().cloned().collect();
+        let mut dropped = 0;
+        for route in routes {
+            if self.expire_endpoints(route).await.is_none() {
+                dropped += 1;
+            }
+        }
+        dropped
+    }
+
+    /// Apply `update` to the route of `server_name` and return the route as changed.
+    pub async fn update_federation_route(&self, server_name: &str, update: RouteUpdate) -> Result<FederationRoute> {
+        if !self.server_discovery.lock().await.contains_key(server_name) {
+            return Err(BridgeError::NotFound);
+        }
+
+        let endpoints = match (update.mycelium_key, update.endpoints) {
+            (Some(_), Some(_)) => return Err(BridgeError::InvalidRequest {
+                message: "Give either mycelium_key or endpoints, not both".to_string()
+            }),
+            (Some(mycelium_key), None) => Some(vec![MyceliumEndpoint::new(mycelium_key)]),
+            (None, endpoints) => endpoints,
+        };
+        if let Some(endpoints) = endpoints {
+            self.set_federation_route_endpoints(server_name.to_string(), endpoints).await?;
+        }
+
+        if let Some(healthy) = update.healthy {
+            let route = {
+                let mut discovery = self.server_discovery.lock().await;
+                let route = discovery.get_mut(server_name).ok_or(BridgeError::NotFound)?;
+                route.healthy = healthy;
+                if healthy {
+                    route.consecutive_failures = 0;
+                }
+                route.clone()
+            };
+            if healthy {
+                self.route_health.lock().await.remove(server_name);
+            }
+            if let Some(database) = &self.database {
+                database.update_federation_route_health(&route).await?;
+            }
+        }
+
+        self.server_discovery.lock().await.get(server_name).cloned().ok_or(BridgeError::NotFound)
+    }
+
+    /// Routes matching `query`, ordered by server name, with their circuit breakers.
+    pub async fn list_federation_routes(&self, query: &RouteListQuery) -> RouteList {
+        let mut routes = self.get_all_federation_routes().await;
+        routes.sort_by(|a, b| a.destination_server.cmp(&b.destination_server));
+
+        let circuits = self.circuit_statuses().await;
+        let pattern = query.server.as_ref().map(|server| server.to_ascii_lowercase());
+        let matching: Vec<RouteView> = routes.into_iter()
+            .filter(|route| pattern.as_ref().is_none_or(|pattern| {
+                glob_match(pattern.as_bytes(), route.destination_server.to_ascii_lowercase().as_bytes())
+            }))
+            .filter(|route| query.healthy.is_none_or(|healthy| route.healthy == healthy))
+            .map(|route| {
+                let circuit = circuits.get(&route.destination_server).cloned();
+                RouteView { route, circuit }
+            })
+            .filter(|view| query.circuit.is_none_or(|state| {
+                // Destinations without a breaker have never failed, so count as closed
+                view.circuit.as_ref().map_or(CircuitState::Closed, |circuit| circuit.state) == state
+            }))
+            .collect();
+
+        let total = matching.len();
+        let offset = query.offset.unwrap_or(0);
+        let limit = query.limit.unwrap_or(DEFAULT_ROUTE_PAGE_SIZE).clamp(1, MAX_ROUTE_PAGE_SIZE);
+        let routes: Vec<RouteView> = matching.into_iter().skip(offset).take(limit).collect();
+
+        RouteList { count: routes.len(), routes, total, offset, limit }
+    }
+
+    /// Every route in the form `import_route_table` takes back.
+    pub async fn export_route_table(&self) -> RouteTable {
+        let mut routes = self.get_all_federation_routes().await;
+        routes.sort_by(|a, b| a.destination_server.cmp(&b.destination_server));
+
+        RouteTable {
+            routes: routes.into_iter()
+                .map(|route| RouteSpec {
+                    server_name: route.destination_server,
+                    mycelium_key: None,
+                    endpoints: Some(route.endpoints),
+                })
+                .collect(),
+        }
+    }
+
+    /// Add or replace every route in `table`. The whole table is validated before
+    /// anything changes; with `replace`, routes the table doesn't list are removed.
+    pub async fn import_route_table(&self, table: RouteTable, replace: bool) -> Result<RouteImportResult> {
+        let mut imported = Vec::with_capacity(table.routes.len());
+        let mut seen = std::collections::HashSet::new();
+        for spec in table.routes {
+            let server_name = spec.server_name.trim().to_string();
+            if server_name.is_empty() {
+                return Err(BridgeError::InvalidRequest {
+                    message: "Route server_name must not be empty".to_string()
+                });
+            }
+            if !seen.insert(server_name.clone()) {
+                return Err(BridgeError::InvalidRequest {
+                    message: format!("Route for {} is listed twice", server_name)
+                });
+            }
+            let endpoints = spec.into_endpoints()?;
+            validate_endpoints(&endpoints).map_err(|e| BridgeError::InvalidRequest {
+                message: format!("Route for {}: {}", server_name, e)
+            })?;
+            imported.push((server_name, endpoints));
+        }
+
+        let mut removed = 0;
+        if replace {
+            let stale: Vec<String> = self.server_discovery.lock().await
+                .keys()
+                .filter(|server| !seen.contains(*server))
+                .cloned()
+                .collect();
+            for server_name in stale {
+                self.remove_federation_route(&server_name).await?;
+                removed += 1;
+            }
+        }
+
+        let count = imported.len();
+        for (server_name, endpoints) in imported {
+            self.set_federation_route_endpoints(server_name, endpoints).await?;
+        }
+
+        Ok(RouteImportResult { imported: count, removed })
+    }
+
     pub async fn get_all_federation_routes(&self) -> Vec<FederationRoute> {
         let discovery = self.server_discovery.lock().await;
         discovery.values().cloned().collect()
     }
 
     pub async fn remove_federation_route(&self, server_name: &str) -> Result<()> {
-        let mut discovery = self.server_discovery.lock().await;
-        if discovery.remove(server_name).is_some() {
+        let deleted = match &self.database {
+            Some(database) => database.delete_federation_route(server_name).await?,
+            None => false,
+        };
+
+        let removed = self.server_discovery.lock().await.remove(server_name).is_some();
+        if deleted || removed {
             Ok(())
         } else {
             Err(BridgeError::NotFound)
         }
     }
 
+    /// Replace the route cache with every route in the database.
+    pub async fn load_federation_routes(&self) -> Result<usize> {
+        let routes = self.database()?.get_all_federation_routes().await?;
+        let routes: Vec<FederationRoute> = routes.into_iter().filter(usable_route).collect();
+        let count = routes.len();
+
+        let mut discovery = self.server_discovery.lock().await;
+        *discovery = routes.into_iter()
+            .map(|route| (route.destination_server.clone(), route))
+            .collect();
+
+        Ok(count)
+    }
+
+    /// Re-read one route from the database after another instance changed it.
+    pub async fn refresh_federation_route(&self, server_name: &str) -> Result<()> {
+        let route = self.database()?.get_federation_route(server_name).await?.filter(usable_route);
+        // What we learned still holds for a route that is still discovered
+        if !route.as_ref().is_some_and(|route| route.discovered) {
+            self.route_discovery.forget(server_name).await;
+        }
+
+        let mut discovery = self.server_discovery.lock().await;
+        match route {
+            Some(route) => discovery.insert(server_name.to_string(), route),
+            None => discovery.remove(server_name),
+        };
+
+        Ok(())
+    }
+
+    /// Send our signed route announcement to every peer we have a route to.
+    /// Returns how many peers it reached.
+    pub async fn announce_route(&self) -> Result<usize> {
+        let (Some(mycelium_url), Some(client)) = (&self.config.mycelium_api_url, &self.mycelium_client) else {
+            return Ok(0);
+        };
+
+        let own_pubkey = self.local_mycelium_pubkey(client, mycelium_url).await?;
+        let peers: std::collections::HashSet<String> = self.server_discovery.lock().await
+            .values()
+            .filter(|route| route.destination_server != self.config.server_name)
+            .flat_map(|route| route.endpoint_order())
+            .filter_map(|key| parse_public_key(&key))
+            .filter(|key| *key != own_pubkey.to_lowercase())
+            .collect();
+        if peers.is_empty() {
+            return Ok(0);
+        }
+
+        let now_ms = SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as u64;
+        let announcement = RouteAnnouncement::new(&self.config.server_name, &own_pubkey, now_ms)
+            .signed(&self.signing_key)?;
+        let payload = Envelope::new(EnvelopeKind::Announcement, announcement).to_base64()?;
+
+        // Announcements are fire-and-forget; a peer that misses one hears the next
+        let mut reached = 0;
+        for peer in peers {
+            let message = serde_json::json!({
+                "dst": { "pk": peer },
+                "topic": encode_topic(ANNOUNCE_TOPIC),
+                "payload": payload
+            });
+
+            match client.post(format!("{}/api/v1/messages", mycelium_url)).json(&message).send().await {
+                Ok(response) if response.status().is_success() => reached += 1,
+                Ok(response) => tracing::debug!("Announcing our route to {} returned {}", peer, response.status()),
+                Err(e) => tracing::debug!("Failed to announce our route to {}: {}", peer, e),
+            }
+        }
+
+        Ok(reached)
+    }
+
+    /// Merge a peer's route announcement into our routes once its signature,
+    /// sender and timestamp check out. Announced nodes expire unless announced
+    /// again; configured routes are left as they are.
+    pub async fn handle_route_announcement(&self, message: MyceliumInboundMessage) -> Result<()> {
+        let envelope = Envelope::from_base64(&message.payload)?;
+        if envelope.kind != EnvelopeKind::Announcement {
+            return Err(BridgeError::Serde {
+                message: format!("Expected a route announcement, got a {:?}", envelope.kind)
+            });
+        }
+        let announcement: RouteAnnouncement = serde_json::from_value(envelope.body.clone())?;
+        if announcement.server_name == self.config.server_name {
+            return Ok(());
+        }
+
+        let now_ms = SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as u64;
+        announcement.check_freshness(&message.src_pk, now_ms, MAX_ANNOUNCEMENT_AGE)?;
+
+        let mut verified = false;
+        for key_id in announcement.signing_key_ids() {
+            match self.key_store.get_verify_key(&announcement.server_name, &key_id).await {
+                Ok(verify_key) => {
+                    if verify_json(&envelope.body, &announcement.server_name, &key_id, &verify_key).is_ok() {
+                        verified = true;
+                        break;
+                    }
+                }
+                Err(e) => tracing::debug!("No key {} for {}: {}", key_id, announcement.server_name, e),
+            }
+        }
+        if !verified {
+            return Err(BridgeError::Auth {
+                message: format!("Route announcement for {} is not signed by its server", announcement.server_name)
+            });
+        }
+
+        self.record_peer_envelope_flags(&message.src_pk, envelope.flags).await;
+
+        let existing = self.server_discovery.lock().await.get(&announcement.server_name).cloned();
+        if existing.as_ref().is_some_and(|route| !route.discovered) {
+            tracing::debug!("Keeping the configured route to {} over its announcement", announcement.server_name);
+            return Ok(());
+        }
+
+        let now = unix_now();
+        let lifetime = match self.config.gossip_interval {
+            0 => MAX_ANNOUNCEMENT_AGE.as_secs(),
+            interval => interval * ANNOUNCEMENT_MISSES,
+        };
+        let announced = MyceliumEndpoint {
+            expires_at: Some(now + lifetime as i64),
+            ..MyceliumEndpoint::new(announcement.mycelium_pubkey)
+        };
+        let learned = || tracing::info!(
+            "Learned route to {} from its announcement (capabilities: {})",
+            announcement.server_name, announcement.capabilities.join(", ")
+        );
+
+        let Some(mut route) = existing else {
+            learned();
+            self.route_discovery.forget(&announcement.server_name).await;
+            let route = FederationRoute {
+                discovered: true,
+                ..FederationRoute::with_endpoints(announcement.server_name.clone(), vec![announced], now)
+            };
+            return self.record_route(route).await;
+        };
+
+        // Servers with several nodes announce from each of them, so announced nodes add up
+        let mut endpoints: Vec<MyceliumEndpoint> = route.endpoints.iter()
+            .filter(|endpoint| !endpoint.has_expired(now))
+            .cloned()
+            .collect();
+        match endpoints.iter_mut().find(|endpoint| endpoint.mycelium_key.eq_ignore_ascii_case(&announced.mycelium_key)) {
+            // Route discovery found this node and keeps checking it itself
+            Some(endpoint) if endpoint.expires_at.is_none() => return Ok(()),
+            Some(endpoint) => endpoint.expires_at = announced.expires_at,
+            None => {
+                learned();
+                endpoints.push(announced);
+            }
+        }
+        route.set_endpoints(endpoints);
+        self.save_route(route).await
+    }
+
+    /// Our node's public key, asked from the node the first time it isn't configured.
+    async fn local_mycelium_pubkey(&self, client: &reqwest::Client, mycelium_url: &str) -> Result<String> {
+        let mut local = self.local_mycelium_pubkey.lock().await;
+        if let Some(pubkey) = local.as_ref() {
+            return Ok(pubkey.clone());
+        }
+
+        let info: serde_json::Value = client
+            .get(format!("{}/api/v1/admin", mycelium_url))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let pubkey = info.get("nodePubkey")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| BridgeError::MyceliumApi {
+                message: "Mycelium node info has no nodePubkey".to_string()
+            })?
+            .to_string();
+
+        *local = Some(pubkey.clone());
+        Ok(pubkey)
+    }
+
+    /// Probe every known route once, returning the routes as updated.
+    pub async fn probe_routes(&self) -> Vec<FederationRoute> {
+        let servers: Vec<String> = self.server_discovery.lock().await
+            .keys()
+            .filter(|server| **server != self.config.server_name)
+            .cloned()
+            .collect();
+
+        let probes = servers.iter().map(|server| self.probe_route(server));
+        futures::future::join_all(probes).await.into_iter().flatten().collect()
+    }
+
+    /// Ping `server_name` over Mycelium, and over classic federation for
+    /// comparison, and fold the results into its route. Returns `None` if the
+    /// route went away meanwhile.
+    pub async fn probe_route(&self, server_name: &str) -> Option<FederationRoute> {
+        let request = FederationRequest {
+            method: "GET".to_string(),
+            path: PROBE_PATH.to_string(),
+            headers: std::collections::HashMap::new(),
+            body: None,
+        };
+
+        let started = std::time::Instant::now();
+        let mycelium = self.send_via_mycelium(request, server_name.to_string(), MyceliumFailure::Report).await;
+        let mycelium_latency = started.elapsed().as_millis() as u64;
+        let mycelium_ok = match mycelium {
+            Ok(response) => response.status_code < 500,
+            Err(e) => {
+                tracing::debug!("Mycelium probe of {} failed: {}", server_name, e);
+                false
+            }
+        };
+
+        let started = std::time::Instant::now();
+        let base_url = resolve_federation_url(&self.matrix_client, server_name).await;
+        let matrix_ok = self.matrix_client
+            .get(format!("{}{}", base_url, PROBE_PATH))
+            .send()
+            .await
+            .is_ok_and(|response| response.status().is_success());
+        let matrix_latency = matrix_ok.then(|| started.elapsed().as_millis() as i64);
+
+        let (latency_ms, consecutive_failures) = {
+            let mut health = self.route_health.lock().await;
+            let health = health.entry(server_name.to_string()).or_default();
+            if mycelium_ok {
+                health.record_success(mycelium_latency);
+            } else {
+                health.record_failure();
+            }
+            (health.average_latency_ms(), health.consecutive_failures())
+        };
+
+        let now = unix_now();
+        let route = {
+            let mut discovery = self.server_discovery.lock().await;
+            let route = discovery.get_mut(server_name)?;
+            if let Some(latency_ms) = latency_ms {
+                route.latency_ms = latency_ms as i64;
+            }
+            if mycelium_ok {
+                route.last_successful = now;
+            }
+            route.consecutive_failures = consecutive_failures as i32;
+            route.matrix_latency_ms = matrix_latency.or(route.matrix_latency_ms);
+            route.last_probed = Some(now);
+
+            let healthy = consecutive_failures < self.config.route_unhealthy_threshold.max(1);
+            if route.healthy && !healthy {
+                tracing::warn!("Route to {} is unhealthy after {} failed probes", server_name, consecutive_failures);
+            } else if !route.healthy && healthy {
+                tracing::info!("Route to {} recovered", server_name);
+            }
+            route.healthy = healthy;
+            route.clone()
+        };
+
+        if let Some(database) = &self.database {
+            if let Err(e) = database.update_federation_route_health(&route).await {
+                tracing::warn!("Failed to store health of route to {}: {}", server_name, e);
+            }
+        }
+
+        Some(route)
+    }
+
     async fn get_mycelium_route(&self, server_name: &str) -> Result<FederationRoute> {
-        let discovery = self.server_discovery.lock().await;
+        let cached = self.server_discovery.lock().await.get(server_name).cloned();
+        let cached = match cached {
+            Some(route) => self.expire_endpoints(route).await,
+            None => None,
+        };
+
+        // Configured routes, fresh discoveries and routes their server keeps
+        // announcing are used as they are. Discovered routes loaded from the
+        // database are checked again on first use.
+        let due = match &cached {
+            Some(route) => {
+                let announced = route.endpoints.iter().any(|endpoint| endpoint.expires_at.is_some());
+                route.discovered && !announced && self.route_discovery.is_due(server_name).await
+            }
+            None => true,
+        };
+        if let Some(route) = cached.as_ref().filter(|_| !due) {
+            return Ok(route.clone());
+        }
+        if !self.route_discovery.is_enabled() {
+            return cached.ok_or(BridgeError::NotFound);
+        }
+
+        match self.route_discovery.discover(server_name).await {
+            Discovery::Found(key) if cached.as_ref().is_some_and(|route| route.has_endpoint(&key)) => {
+                cached.ok_or(BridgeError::NotFound)
+            }
+            Discovery::Found(key) => {
+                let route = FederationRoute::discovered(server_name.to_string(), key, unix_now());
+                if let Err(e) = self.record_route(route.clone()).await {
+                    tracing::warn!("Failed to persist discovered route for {}: {}", server_name, e);
+                    self.server_discovery.lock().await.insert(server_name.to_string(), route);
+                }
+                self.server_discovery.lock().await.get(server_name).cloned().ok_or(BridgeError::NotFound)
+            }
+            Discovery::Failed => {
+                // An outage of the server's well-known or DNS says nothing about
+                // its route; keep it until the resolvers answer again
+                if cached.is_some() {
+                    tracing::debug!("Route discovery for {} failed, keeping its route", server_name);
+                }
+                cached.ok_or(BridgeError::NotFound)
+            }
+            Discovery::Absent => {
+                // The server stopped advertising the route it was discovered through
+                if cached.is_some() {
+                    tracing::info!("Discovered route for {} withdrawn", server_name);
+                    let _ = self.remove_federation_route(server_name).await;
+                }
+                Err(BridgeError::NotFound)
+            }
+        }
+    }
+
+    /// The public key to address `destination`'s node by. Route keys are
+    /// either public keys or overlay addresses the local node can look up.
+    async fn get_destination_pubkey(
+        &self,
+        destination: &str,
+        mycelium_key: &str,
+        client: &reqwest::Client,
+        mycelium_url: &str
+    ) -> Result<String> {
+        self.pubkey_resolver.resolve(client, mycelium_url, mycelium_key).await.map_err(|e| match e {
+            BridgeError::UnresolvableKey { message } => BridgeError::UnresolvableKey {
+                message: format!("route to {}: {}", destination, message)
+            },
+            e => e,
+        })
+    }
+
+    pub async fn handle_incoming_mycelium_message(&self, mycelium_msg: MyceliumFederationMessage) -> Result<()> {
+        tracing::info!("Received incoming Mycelium message: {}", mycelium_msg.topic);
+
+        if let Some(source_pubkey) = &mycelium_msg.source_pubkey {
+            self.record_peer_envelope_flags(source_pubkey, mycelium_msg.envelope_flags).await;
+        }
+
+        // EDUs are pushed without expecting a reply; everything else is a request
+        if mycelium_msg.topic.starts_with(EDU_TOPIC_PREFIX) {
+            self.process_incoming_edus(mycelium_msg).await?;
+        } else if mycelium_msg.topic.starts_with("matrix.federation.") {
+            self.process_incoming_federation_request(mycelium_msg).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn process_incoming_federation_request(&self, mycelium_msg: MyceliumFederationMessage) -> Result<()> {
+        let request = federation_request_from_payload(&mycelium_msg.payload)?;
+        let relayed = relayed_transaction(&request).map(|transaction| (request.clone(), transaction));
+
+        // Process the federation request; failures are answered too, so the requester
+        // sees the error rather than waiting out its reply timeout
+        let response = match self.handle_federation_request(request).await {
+            Ok(response) => response,
+            Err(e) => {
+                tracing::warn!("Federation request from {} failed: {}", mycelium_msg.sender, e);
+                let (status, body) = e.to_matrix_error();
+                FederationResponse {
+                    status_code: status.as_u16(),
+                    body,
+                    headers: std::collections::HashMap::new(),
+                }
+            }
+        };
+        let accepted = accepted_pdus(&response);
+
+        // Answer through the node's reply endpoint, keyed by the transport message id
+        match (&mycelium_msg.message_id, &mycelium_msg.source_pubkey) {
+            (Some(message_id), Some(source_pubkey)) => {
+                self.send_mycelium_reply(message_id, source_pubkey, mycelium_msg.envelope_id, response).await?;
+            }
+            _ => tracing::warn!("Mycelium request from {} has no transport id, not replying", mycelium_msg.sender),
+        }
+
+        // Keep our copy of the room DAGs current, fetching whatever the relayed events skip over
+        if let Some((request, transaction)) = relayed {
+            self.record_relayed_pdus(&request, transaction, &accepted).await;
+        }
+
+        Ok(())
+    }
+
+    /// Record the PDUs of a relayed transaction that our homeserver accepted. The
+    /// origin in the body is only a claim, so the request's X-Matrix signature
+    /// has to check out and name the same server.
+    async fn record_relayed_pdus(&self, request: &FederationRequest, transaction: Transaction, accepted: &HashSet<String>) {
+        if self.database.is_none() || accepted.is_empty() {
+            return;
+        }
+
+        let authorizations: Vec<&str> = header_value(&request.headers, "Authorization").into_iter().collect();
+        let origin = match self.verify_federation_auth(&request.method, &request.path, &authorizations, request.body.as_ref()).await {
+            Ok(origin) if origin == transaction.origin => origin,
+            Ok(origin) => {
+                tracing::warn!("Transaction relayed by {} claims origin {}, not recording it", origin, transaction.origin);
+                return;
+            }
+            Err(e) => {
+                tracing::warn!("Not recording unauthenticated transaction from {}: {}", transaction.origin, e);
+                return;
+            }
+        };
+
+        for pdu in transaction.pdus {
+            let event_id = self.pdu_room_version(&pdu).await.and_then(|version| pdu_event_id(&pdu, version));
+            let Some(event_id) = event_id.ok().filter(|id| accepted.contains(id)) else {
+                continue;
+            };
+            let stored = match event_from_pdu(&pdu, &event_id) {
+                Ok(event) => self.store_pdu(&event).await.map(|new| new.then_some(event)),
+                Err(e) => Err(e.into()),
+            };
+            match stored {
+                Ok(Some(event)) => self.fill_gaps(&origin, &event).await,
+                Ok(None) => {}
+                Err(e) => tracing::debug!("Not recording PDU relayed by {}: {}", origin, e),
+            }
+        }
+    }
+
+    /// Hand EDUs a peer relayed to us on to the homeserver. They're ephemeral,
+    /// so nothing is replied and failures are only logged.
+    async fn process_incoming_edus(&self, mycelium_msg: MyceliumFederationMessage) -> Result<()> {
+        let request = federation_request_from_payload(&mycelium_msg.payload)?;
+        if !request.method.eq_ignore_ascii_case("PUT") || !request.path.starts_with("/_matrix/federation/v1/send/") {
+            return Err(BridgeError::InvalidRequest {
+                message: format!("{} {} sent on EDU topic {}", request.method, request.path, mycelium_msg.topic)
+            });
+        }
+
+        let response = self.handle_federation_request(request).await?;
+        if response.status_code >= 400 {
+            tracing::warn!("Homeserver rejected EDUs from {} with {}", mycelium_msg.sender, response.status_code);
+        }
+        Ok(())
+    }
+
+    async fn send_mycelium_reply(
+        &self,
+        message_id: &str,
+        source_pubkey: &str,
+        request_envelope_id: Option<uuid::Uuid>,
+        response: FederationResponse,
+    ) -> Result<()> {
+        if let Some(mycelium_url) = &self.config.mycelium_api_url {
+            let client = self.mycelium_client.as_ref()
+                .ok_or_else(|| BridgeError::Config {
+                    message: "Mycelium client not configured".to_string()
+                })?;
+
+            let response_payload = serde_json::json!({
+                "response_body": response.body,
+                "status_code": response.status_code,
+                "headers": response.headers,
+                "timestamp": std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs()
+            });
+
+            // Large responses (full state, backfill) are fragmented like requests;
+            // the last fragment travels as the reply itself
+            let envelope = Envelope::response(request_envelope_id, response_payload)
+                .compressed(self.compression_for(source_pubkey).await, self.config.mycelium_compression_threshold)?;
+            let topic = "matrix.federation.response".to_string();
+            let (topic, payload) = match fragment_envelope(&envelope, &topic, self.config.mycelium_max_message_size)? {
+                Some(mut fragments) => {
+                    let last = fragments.pop().expect("fragmented payloads have several fragments");
+                    self.send_fragments(client, mycelium_url, source_pubkey, &fragments).await?;
+                    (FRAGMENT_TOPIC.to_string(), last)
+                }
+                None => (topic, envelope),
+            };
+
+            let mycelium_reply = serde_json::json!({
+                "dst": { "pk": source_pubkey },
+                "topic": encode_topic(&topic),
+                "payload": payload.to_base64()?
+            });
+
+            let mycelium_response = client
+                .post(format!("{}/api/v1/messages/reply/{}", mycelium_url, message_id))
+                .json(&mycelium_reply)
+                .send()
+                .await
+                .map_err(|e| BridgeError::MyceliumApi {
+                    message: format!("Failed to send Mycelium reply: {}", e)
+                })?;
+
+            if mycelium_response.status().is_success() {
+                tracing::info!("Sent Mycelium reply for message {}", message_id);
+            } else {
+                tracing::warn!("Failed to send Mycelium reply: {}", mycelium_response.status());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Push all but the last fragment of a message to `dest_pubkey` without waiting
+    /// for replies. Stops at the first failure, since the message can't be completed.
+    async fn send_fragments(
+        &self,
+        client: &reqwest::Client,
+        mycelium_url: &str,
+        dest_pubkey: &str,
+        fragments: &[Envelope],
+    ) -> Result<()> {
+        let total = fragments.len() + 1;
+        for (sent, fragment) in fragments.iter().enumerate() {
+            let message = serde_json::json!({
+                "dst": { "pk": dest_pubkey },
+                "topic": encode_topic(FRAGMENT_TOPIC),
+                "payload": fragment.to_base64()?
+            });
+
+            let result = client
+                .post(format!("{}/api/v1/messages", mycelium_url))
+                .json(&message)
+                .send()
+                .await
+                .map_err(|e| e.to_string())
+                .and_then(|response| match response.status().is_success() {
+                    true => Ok(()),
+                    false => Err(format!("node returned {}", response.status())),
+                });
+
+            if let Err(e) = result {
+                return Err(BridgeError::PartialDelivery {
+                    message: format!("Sent {} of {} fragments to {}: {}", sent, total, dest_pubkey, e)
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Decode the envelope carried by a Mycelium reply, waiting for the rest of it
+    /// when the reply is the last fragment of a large response.
+    async fn reply_envelope(&self, source_pubkey: &str, payload: &str) -> Result<Envelope> {
+        let envelope = Envelope::from_base64(payload)?;
+        if envelope.kind != EnvelopeKind::Fragment {
+            return Ok(envelope);
+        }
+
+        let reassembled = self.reassembler.wait_for(source_pubkey, Fragment::from_envelope(&envelope)?).await?;
+        Envelope::decode(&reassembled.bytes)
+    }
+
+    /// Buffer an inbound fragment, handling its message once it is complete and
+    /// telling the sender when it can't be.
+    pub async fn handle_inbound_fragment(&self, message: MyceliumInboundMessage) -> Result<()> {
+        let envelope = Envelope::from_base64(&message.payload)?;
+        let fragment = Fragment::from_envelope(&envelope)?;
+        let reply_to = ReplyTarget {
+            transport_id: message.id.clone(),
+            source_pubkey: message.src_pk.clone(),
+        };
+
+        let reassembled = match self.reassembler.insert(&message.src_pk, fragment, Some(reply_to)).await {
+            Ok(Some(reassembled)) => reassembled,
+            Ok(None) => return Ok(()),
+            Err(abandoned) => return self.report_abandoned_message(abandoned).await,
+        };
+
+        // Response fragments only matter to a request still waiting for them
+        if reassembled.topic == "matrix.federation.response" {
+            tracing::warn!("Dropping reassembled response nobody is waiting for");
+            return Ok(());
+        }
+
+        // Replies go to the transport message the sender is waiting on
+        let origin = match reassembled.reply_to {
+            Some(target) => MyceliumInboundMessage {
+                id: target.transport_id,
+                src_pk: target.source_pubkey,
+                ..message
+            },
+            None => message,
+        };
+        let mycelium_msg = decode_inbound_payload(&origin, reassembled.topic, &reassembled.bytes)?;
+        self.handle_incoming_mycelium_message(mycelium_msg).await
+    }
+
+    /// Remember what a peer advertised; peers sending bare `v1` JSON advertise nothing.
+    async fn record_peer_envelope_flags(&self, pubkey: &str, flags: u16) {
+        self.peer_envelope_flags.lock().await.insert(pubkey.to_string(), flags);
+    }
+
+    /// The configured compression if `pubkey` has told us it can decompress it,
+    /// otherwise none: peers we haven't heard from yet may only speak `v1`.
+    async fn compression_for(&self, pubkey: &str) -> Compression {
+        let compression = self.config.mycelium_compression;
+        let peer_flags = self.peer_envelope_flags.lock().await.get(pubkey).copied();
+
+        match peer_flags {
+            Some(flags) if flags_accept(flags, compression) => compression,
+            _ => Compression::None,
+        }
+    }
+
+    /// Give up on messages whose fragments stopped arriving.
+    pub async fn expire_fragments(&self) {
+        for abandoned in self.reassembler.expire().await {
+            if let Err(e) = self.report_abandoned_message(abandoned).await {
+                tracing::warn!("Failed to report partial delivery: {}", e);
+            }
+        }
+    }
+
+    async fn report_abandoned_message(&self, abandoned: crate::fragment::Abandoned) -> Result<()> {
+        let Some(reply_to) = abandoned.reply_to else {
+            return Ok(());
+        };
 
-        if let Some(route) = discovery.get(server_name) {
-            return Ok(route.clone());
-        }
+        let errcode = if abandoned.status_code == 413 { "M_TOO_LARGE" } else { "M_UNKNOWN" };
+        let response = FederationResponse {
+            status_code: abandoned.status_code,
+            body: serde_json::json!({
+                "errcode": errcode,
+                "error": abandoned.reason.to_string()
+            }),
+            headers: std::collections::HashMap::new(),
+        };
 
-        Err(BridgeError::NotFound)
+        self.send_mycelium_reply(&reply_to.transport_id, &reply_to.source_pubkey, Some(abandoned.message_id), response).await
     }
+}
 
-    async fn get_destination_pubkey(
-        &self,
-        destination: &str,
-        mycelium_key: &str,
-        client: &reqwest::Client,
-        mycelium_url: &str
-    ) -> Result<String> {
-        // If we already have a public key, use it
-        if mycelium_key.starts_with("0x") || mycelium_key.len() == 64 {
-            return Ok(mycelium_key.to_string());
-        }
+/// Largest message reassembled from fragments, matching what we accept over HTTP.
+const MAX_REASSEMBLED_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
 
-        // Try to get the public key from Mycelium API
-        // For now, we'll use a placeholder - in a real implementation,
-        // we'd need a way to resolve server names to public keys
-        // This could be done via DNS, a distributed registry, or configuration
+/// Routes listed per page when the caller doesn't ask for a page size, and the most it may ask for.
+const DEFAULT_ROUTE_PAGE_SIZE: usize = 100;
+const MAX_ROUTE_PAGE_SIZE: usize = 1000;
 
-        // Placeholder: return the mycelium_key as-is for now
-        // In production, this would need proper key resolution
-        tracing::warn!("Using placeholder public key resolution for {}", destination);
-        Ok(mycelium_key.to_string())
-    }
+/// Most events `/backfill` and `/get_missing_events` return at once.
+const MAX_BACKFILL_EVENTS: usize = 100;
 
-    pub async fn handle_incoming_mycelium_message(&self, mycelium_msg: MyceliumFederationMessage) -> Result<()> {
-        tracing::info!("Received incoming Mycelium message: {}", mycelium_msg.topic);
+/// Missing events asked for when an incoming event leaves a gap.
+const MISSING_EVENTS_FETCH_LIMIT: usize = 10;
 
-        // Check if this is a response to a pending request
-        if let Some(message_id) = mycelium_msg.payload.get("message_id").and_then(|v| v.as_str()) {
-            let mut pending = self.pending_messages.lock().await;
-            if let Some(response_tx) = pending.remove(message_id) {
-                // This is a response to a pending request
-                let federation_response = FederationResponse {
-                    status_code: 200, // Assume success for now
-                    body: mycelium_msg.payload.get("response_body")
-                        .unwrap_or(&serde_json::json!({ "status": "received_via_mycelium" }))
-                        .clone(),
-                };
+/// Response headers relayed back to the caller alongside status and body.
+const FORWARDED_RESPONSE_HEADERS: &[&str] = &["content-type", "retry-after", "cache-control"];
 
-                // Send response back to waiting request handler
-                let _ = response_tx.send(federation_response);
-                tracing::info!("Delivered Mycelium response for message {}", message_id);
-                return Ok(());
-            }
-        }
+fn forwarded_headers(headers: &reqwest::header::HeaderMap) -> std::collections::HashMap<String, String> {
+    FORWARDED_RESPONSE_HEADERS.iter()
+        .filter_map(|name| {
+            let value = headers.get(*name)?.to_str().ok()?;
+            Some((name.to_string(), value.to_string()))
+        })
+        .collect()
+}
 
-        // Handle incoming federation requests
-        if mycelium_msg.topic.starts_with("matrix.federation.") {
-            self.process_incoming_federation_request(mycelium_msg).await?;
-        }
+/// Counts a message in flight for as long as it lives, so a send whose future
+/// is dropped midway doesn't leave the count raised.
+struct PendingMessage<'a>(&'a AtomicU32);
 
-        Ok(())
+impl<'a> PendingMessage<'a> {
+    fn start(count: &'a AtomicU32) -> Self {
+        count.fetch_add(1, Ordering::Relaxed);
+        Self(count)
     }
+}
 
-    async fn process_incoming_federation_request(&self, mycelium_msg: MyceliumFederationMessage) -> Result<()> {
-        // Extract the federation request from the Mycelium message
-        let method = mycelium_msg.payload.get("method")
-            .and_then(|v| v.as_str())
-            .ok_or_else(|| BridgeError::Serde {
-                message: "Missing method in federation request".to_string()
-            })?;
-
-        let path = mycelium_msg.payload.get("path")
-            .and_then(|v| v.as_str())
-            .ok_or_else(|| BridgeError::Serde {
-                message: "Missing path in federation request".to_string()
-            })?;
+impl Drop for PendingMessage<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
 
-        let body = mycelium_msg.payload.get("body").cloned();
-        let headers = mycelium_msg.payload.get("headers")
-            .and_then(|v| v.as_object())
-            .map(|obj| obj.iter()
-                .filter_map(|(k, v)| v.as_str().map(|s| (k.clone(), s.to_string())))
-                .collect()
-            )
-            .unwrap_or_default();
+/// Only servers in a room may read its history. A server asking about the
+/// event it joined with isn't in the state before it yet.
+fn ensure_server_in_room(origin: &str, state: &[MatrixEvent], event: &MatrixEvent) -> Result<()> {
+    if server_in_room(state, origin) || server_in_room(std::slice::from_ref(event), origin) {
+        return Ok(());
+    }
+    Err(BridgeError::Forbidden {
+        message: format!("{} is not in room {}", origin, event.room_id)
+    })
+}
 
-        let request = FederationRequest {
-            method: method.to_string(),
-            path: path.to_string(),
-            body,
-            headers,
-        };
+/// The event ID of `pdu` in a room of `version`: the one it carries in room
+/// versions 1 and 2, its reference hash from room version 3 on.
+fn pdu_event_id(pdu: &serde_json::Value, version: RoomVersion) -> Result<String> {
+    if !pdu.is_object() {
+        return Err(BridgeError::InvalidRequest {
+            message: "PDU is not a JSON object".to_string()
+        });
+    }
+    if version.has_hashed_event_ids() {
+        return reference_event_id(pdu, version);
+    }
+    pdu.get("event_id")
+        .and_then(|v| v.as_str())
+        .map(str::to_string)
+        .ok_or_else(|| BridgeError::InvalidRequest {
+            message: format!("PDU of room version {} has no event_id", version)
+        })
+}
 
-        // Process the federation request
-        let response = self.handle_federation_request(request).await?;
+/// Parse `pdu` as the event `event_id`, leaving the PDU itself as received.
+fn event_from_pdu(pdu: &serde_json::Value, event_id: &str) -> serde_json::Result<MatrixEvent> {
+    let mut fields = pdu.clone();
+    if let Some(fields) = fields.as_object_mut() {
+        fields.insert("event_id".to_string(), serde_json::json!(event_id));
+    }
+    MatrixEvent::from_pdu(fields)
+}
 
-        // Send response back via Mycelium
-        if let Some(message_id) = mycelium_msg.payload.get("message_id").and_then(|v| v.as_str()) {
-            self.send_mycelium_response(&mycelium_msg.sender, message_id, response).await?;
-        }
+/// Event IDs of the PDUs a homeserver's answer to `/send` accepted: the
+/// request succeeded and their result carries no error.
+fn accepted_pdus(response: &FederationResponse) -> HashSet<String> {
+    if !(200..300).contains(&response.status_code) {
+        return HashSet::new();
+    }
+    response.body.get("pdus")
+        .and_then(|v| v.as_object())
+        .into_iter()
+        .flatten()
+        .filter(|(_, result)| result.get("error").is_none())
+        .map(|(event_id, _)| event_id.clone())
+        .collect()
+}
 
-        Ok(())
+/// The transaction carried by a relayed `PUT /send/{txnId}`, if that's what `request` is.
+fn relayed_transaction(request: &FederationRequest) -> Option<Transaction> {
+    if !request.method.eq_ignore_ascii_case("PUT") || !request.path.starts_with("/_matrix/federation/v1/send/") {
+        return None;
     }
+    serde_json::from_value(request.body.clone()?).ok()
+}
 
-    async fn send_mycelium_response(&self, destination: &str, message_id: &str, response: FederationResponse) -> Result<()> {
-        if let Some(mycelium_url) = &self.config.mycelium_api_url {
-            let client = self.mycelium_client.as_ref()
-                .ok_or_else(|| BridgeError::Config {
-                    message: "Mycelium client not configured".to_string()
-                })?;
+/// The federation request a peer sent over Mycelium.
+fn federation_request_from_payload(payload: &serde_json::Value) -> Result<FederationRequest> {
+    let method = payload.get("method")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| BridgeError::Serde {
+            message: "Missing method in federation request".to_string()
+        })?;
+
+    let path = payload.get("path")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| BridgeError::Serde {
+            message: "Missing path in federation request".to_string()
+        })?;
+
+    let body = payload.get("body").cloned();
+    let headers = payload.get("headers")
+        .and_then(|v| v.as_object())
+        .map(|obj| obj.iter()
+            .filter_map(|(k, v)| v.as_str().map(|s| (k.clone(), s.to_string())))
+            .collect()
+        )
+        .unwrap_or_default();
+
+    Ok(FederationRequest {
+        method: method.to_string(),
+        path: path.to_string(),
+        body,
+        headers,
+    })
+}
 
-            // Get route for the destination
-            let route = self.get_mycelium_route(destination).await?;
-            let dest_pubkey = self.get_destination_pubkey(destination, &route.mycelium_key, client, mycelium_url).await?;
+/// Rebuild the remote server's response from a Mycelium reply payload.
+fn federation_response_from_reply(payload: &serde_json::Value) -> Result<FederationResponse> {
+    let status_code = payload.get("status_code")
+        .and_then(|v| v.as_u64())
+        .and_then(|code| u16::try_from(code).ok())
+        .filter(|code| (100..600).contains(code))
+        .ok_or_else(|| BridgeError::InvalidResponse {
+            message: format!("Mycelium reply has no valid status_code: {}", payload.get("status_code").unwrap_or(&serde_json::Value::Null))
+        })?;
+
+    let headers = payload.get("headers")
+        .and_then(|v| v.as_object())
+        .map(|headers| headers.iter()
+            .filter(|(k, _)| FORWARDED_RESPONSE_HEADERS.contains(&k.to_ascii_lowercase().as_str()))
+            .filter_map(|(k, v)| v.as_str().map(|v| (k.to_ascii_lowercase(), v.to_string())))
+            .collect())
+        .unwrap_or_default();
+
+    Ok(FederationResponse {
+        status_code,
+        body: payload.get("response_body").cloned().unwrap_or_else(|| serde_json::json!({})),
+        headers,
+    })
+}
 
-            let response_payload = serde_json::json!({
-                "message_id": message_id,
-                "response_body": response.body,
-                "status_code": response.status_code,
-                "timestamp": std::time::SystemTime::now()
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs()
-            });
+fn blocked_destination(destination: &str) -> BridgeError {
+    BridgeError::Blocked {
+        message: format!("Traffic to {} is blocked", destination)
+    }
+}
 
-            let mycelium_request = serde_json::json!({
-                "dst": { "pk": dest_pubkey },
-                "topic": "matrix.federation.response".to_string().into_bytes(),
-                "payload": response_payload.to_string().into_bytes()
-            });
+/// Whether `key` is a Mycelium public key or overlay address.
+fn is_mycelium_key(key: &str) -> bool {
+    parse_public_key(key).is_some() || parse_overlay_address(key).is_some()
+}
 
-            let mycelium_response = client
-                .post(format!("{}/api/v1/messages", mycelium_url))
-                .json(&mycelium_request)
-                .send()
-                .await
-                .map_err(|e| BridgeError::MyceliumApi {
-                    message: format!("Failed to send Mycelium response: {}", e)
-                })?;
+/// Whether every key of a stored `route` is a Mycelium public key or overlay
+/// address. Routes that aren't, like placeholders, are skipped with a warning.
+fn usable_route(route: &FederationRoute) -> bool {
+    let invalid = std::iter::once(&route.mycelium_key)
+        .chain(route.endpoints.iter().map(|endpoint| &endpoint.mycelium_key))
+        .find(|key| !is_mycelium_key(key));
+    match invalid {
+        Some(key) => {
+            tracing::warn!("Ignoring route to {}: {} is not a Mycelium key or address", route.destination_server, key);
+            false
+        }
+        None => true,
+    }
+}
 
-            if mycelium_response.status().is_success() {
-                tracing::info!("Sent Mycelium response for message {}", message_id);
-            } else {
-                tracing::warn!("Failed to send Mycelium response: {}", mycelium_response.status());
-            }
+/// Reject endpoint sets the routing code can't use.
+fn validate_endpoints(endpoints: &[MyceliumEndpoint]) -> Result<()> {
+    let invalid = |message: String| Err(BridgeError::InvalidRequest { message });
+
+    if endpoints.is_empty() {
+        return invalid("A route needs at least one endpoint".to_string());
+    }
+    let mut seen = std::collections::HashSet::new();
+    for endpoint in endpoints {
+        if endpoint.mycelium_key.trim().is_empty() {
+            return invalid("Endpoint mycelium_key must not be empty".to_string());
+        }
+        if !is_mycelium_key(&endpoint.mycelium_key) {
+            return invalid(format!("Endpoint {} is not a Mycelium public key or overlay address", endpoint.mycelium_key));
+        }
+        if endpoint.weight < 1 {
+            return invalid(format!("Endpoint {} needs a positive weight", endpoint.mycelium_key));
         }
+        if !seen.insert(endpoint.mycelium_key.to_lowercase()) {
+            return invalid(format!("Endpoint {} is listed twice", endpoint.mycelium_key));
+        }
+    }
+    Ok(())
+}
 
-        Ok(())
+pub(crate) fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+}
+
+/// Case-insensitive lookup of an HTTP header in a federation request.
+fn header_value<'a>(headers: &'a std::collections::HashMap<String, String>, name: &str) -> Option<&'a str> {
+    headers.iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+/// Server name part of a Matrix identifier (`@user:server`, `!room:server`, `$event:server`, `#alias:server`).
+fn server_name_from_id(id: &str) -> Option<&str> {
+    if !id.starts_with(['@', '!', '$', '#']) {
+        return None;
     }
+    id.split_once(':')
+        .map(|(_, server)| server)
+        .filter(|server| !server.is_empty())
 }
 
 #[cfg(test)]
@@ -662,24 +2604,28 @@ mod tests {
         let bridge = MatrixMyceliumBridge::new(config).await.unwrap();
 
         let message_event = MatrixEvent {
+            event_id: "$message:example.com".to_string(),
             event_type: "m.room.message".to_string(),
             room_id: "!room:example.com".to_string(),
             sender: "@user:example.com".to_string(),
             origin_server_ts: 1234567890,
             content: serde_json::json!({"body": "test"}),
             state_key: None,
+            ..MatrixEvent::default()
         };
 
         let topic = bridge.determine_mycelium_topic(&message_event);
         assert_eq!(topic, "matrix.federation.message");
 
         let membership_event = MatrixEvent {
+            event_id: "$member:example.com".to_string(),
             event_type: "m.room.member".to_string(),
             room_id: "!room:example.com".to_string(),
             sender: "@user:example.com".to_string(),
             origin_server_ts: 1234567890,
             content: serde_json::json!({"membership": "join"}),
             state_key: Some("@user:example.com".to_string()),
+            ..MatrixEvent::default()
         };
 
         let topic = bridge.determine_mycelium_topic(&membership_event);
@@ -700,14 +2646,23 @@ mod tests {
             origin_server_ts: 1234567890,
             content: serde_json::json!({"body": "Hello World", "msgtype": "m.text"}),
             state_key: None,
+            ..MatrixEvent::default()
         };
 
-        let mycelium_msg = bridge.translate_matrix_to_mycelium(test_event.clone()).await.unwrap();
+        let mut messages = bridge.translate_matrix_to_mycelium(test_event.clone()).await.unwrap();
+        assert_eq!(messages.len(), 1);
+        let mycelium_msg = messages.remove(0);
 
+        assert_eq!(mycelium_msg.destination, "example.com");
         assert_eq!(mycelium_msg.topic, "matrix.federation.message");
         assert_eq!(mycelium_msg.sender, "@user:example.com");
-        assert!(mycelium_msg.payload.get("event_id").is_some());
-        assert!(mycelium_msg.payload.get("federation_version").is_some());
+        assert_eq!(mycelium_msg.payload, test_event.to_pdu());
+
+        // PDUs are relayed exactly as received, keys the event type doesn't model included
+        let mut pdu = test_event.to_pdu();
+        pdu["origin"] = serde_json::json!("example.com");
+        let relayed = bridge.room_messages(&test_event, pdu.clone()).await.unwrap();
+        assert_eq!(relayed[0].payload, pdu);
 
         // Test Mycelium to Matrix transformation
         let matrix_event = bridge.translate_mycelium_to_matrix(mycelium_msg).await.unwrap();
@@ -723,13 +2678,15 @@ mod tests {
         let bridge = MatrixMyceliumBridge::new(config).await.unwrap();
 
         // Test adding a federation route
-        bridge.add_federation_route("test.example.com".to_string(), "test_key_123".to_string()).await.unwrap();
+        let key = "ab".repeat(32);
+        bridge.add_federation_route("test.example.com".to_string(), key.clone()).await.unwrap();
+        assert!(bridge.add_federation_route("other.example.com".to_string(), "test_key_123".to_string()).await.is_err());
 
         // Test getting all routes
         let routes = bridge.get_all_federation_routes().await;
         assert_eq!(routes.len(), 1);
         assert_eq!(routes[0].destination_server, "test.example.com");
-        assert_eq!(routes[0].mycelium_key, "test_key_123");
+        assert_eq!(routes[0].mycelium_key, key);
 
         // Test getting specific route
         let route = bridge.get_mycelium_route("test.example.com").await.unwrap();
@@ -739,6 +2696,15 @@ mod tests {
         bridge.remove_federation_route("test.example.com").await.unwrap();
         let routes_after = bridge.get_all_federation_routes().await;
         assert_eq!(routes_after.len(), 0);
+
+        // Stored routes are only loaded when all their keys are usable
+        let route = |key: &str| FederationRoute::new("matrix.org".to_string(), key.to_string(), 0);
+        assert!(usable_route(&route(&"ab".repeat(32))));
+        assert!(usable_route(&route("400::1")));
+        assert!(!usable_route(&route("default-key-1")));
+        let mut mixed = route(&"ab".repeat(32));
+        mixed.endpoints.push(MyceliumEndpoint::new("default-key-1".to_string()));
+        assert!(!usable_route(&mixed));
     }
 
     #[tokio::test]
@@ -755,4 +2721,772 @@ mod tests {
         let result = bridge.get_room_servers("invalid_room_id").await;
         assert!(result.is_err());
     }
+
+    #[tokio::test]
+    async fn test_extract_server_name() {
+        let config = BridgeConfig::default();
+        let bridge = MatrixMyceliumBridge::new(config).await.unwrap();
+
+        let request = |path: &str, headers: &[(&str, &str)], body: Option<serde_json::Value>| FederationRequest {
+            method: "GET".to_string(),
+            path: path.to_string(),
+            body,
+            headers: headers.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+        };
+
+        // Headers take precedence over the path
+        let req = request("/_matrix/federation/v1/send/txn1", &[("destination", "header.example.com")], None);
+        assert_eq!(bridge.extract_server_name(&req).as_deref(), Some("header.example.com"));
+
+        let req = request(
+            "/_matrix/federation/v1/send/txn1",
+            &[("Authorization", r#"X-Matrix origin="origin.example.com",destination="auth.example.com",key="ed25519:1",sig="abc""#)],
+            None,
+        );
+        assert_eq!(bridge.extract_server_name(&req).as_deref(), Some("auth.example.com"));
+
+        // Query parameters
+        let req = request("/_matrix/federation/v1/query/profile?user_id=%40alice%3Aquery.example.com", &[], None);
+        assert_eq!(bridge.extract_server_name(&req).as_deref(), Some("query.example.com"));
+
+        // Path segments, including percent-encoded IDs and ports
+        let req = request("/_matrix/federation/v1/make_join/%21room%3Aroom.example.com%3A8448/%40bob%3Alocal.example.com", &[], None);
+        assert_eq!(bridge.extract_server_name(&req).as_deref(), Some("room.example.com:8448"));
+
+        let req = request("/_matrix/federation/v2/send_join/!room:room.example.com/$event:event.example.com", &[], None);
+        assert_eq!(bridge.extract_server_name(&req).as_deref(), Some("event.example.com"));
+
+        let req = request("/_matrix/federation/v2/send_join/!room:room.example.com/$opaqueeventid", &[], None);
+        assert_eq!(bridge.extract_server_name(&req).as_deref(), Some("room.example.com"));
+
+        let body = serde_json::json!({"event": {"state_key": "@carol:invitee.example.com"}});
+        let req = request("/_matrix/federation/v2/invite/!room:room.example.com/$event", &[], Some(body));
+        assert_eq!(bridge.extract_server_name(&req).as_deref(), Some("invitee.example.com"));
+
+        // Nothing to go on, or not a federation request at all
+        let req = request("/_matrix/federation/v1/version", &[], None);
+        assert_eq!(bridge.extract_server_name(&req), None);
+        let req = request("/_matrix/client/v3/sync", &[("Destination", "header.example.com")], None);
+        assert_eq!(bridge.extract_server_name(&req), None);
+    }
+
+    #[tokio::test]
+    async fn test_outgoing_request_signing() {
+        let config = BridgeConfig {
+            server_name: "bridge.example.com".to_string(),
+            ..BridgeConfig::default()
+        };
+        let bridge = MatrixMyceliumBridge::new(config).await.unwrap();
+
+        let mut request = FederationRequest {
+            method: "PUT".to_string(),
+            path: "/_matrix/federation/v1/send/txn1".to_string(),
+            body: Some(serde_json::json!({"pdus": []})),
+            headers: std::collections::HashMap::from([("authorization".to_string(), "Bearer x".to_string())]),
+        };
+        bridge.sign_outgoing_request(&mut request, Some("remote.example.com")).unwrap();
+
+        assert_eq!(request.headers.len(), 1);
+        let auth = XMatrixAuth::parse(&request.headers["Authorization"]).unwrap();
+        assert_eq!(auth.origin, "bridge.example.com");
+        assert_eq!(auth.destination.as_deref(), Some("remote.example.com"));
+        assert_eq!(auth.key, bridge.signing_key.key_id());
+
+        // Requests signed by another server are relayed as-is
+        let relayed = r#"X-Matrix origin="other.example.com",key="ed25519:1",sig="abc""#;
+        request.headers.insert("Authorization".to_string(), relayed.to_string());
+        bridge.sign_outgoing_request(&mut request, Some("remote.example.com")).unwrap();
+        assert_eq!(request.headers["Authorization"], relayed);
+    }
+
+    #[tokio::test]
+    async fn test_inbound_request_verification() {
+        let config = BridgeConfig {
+            server_name: "bridge.example.com".to_string(),
+            ..BridgeConfig::default()
+        };
+        let bridge = MatrixMyceliumBridge::new(config).await.unwrap();
+
+        let remote = ServerSigningKey::generate();
+        bridge.key_store
+            .insert_verify_key("remote.example.com", &remote.key_id(), remote.verifying_key(), u64::MAX)
+            .await;
+
+        let uri = "/_matrix/federation/v1/send/txn1";
+        let body = serde_json::json!({"pdus": []});
+        let header = remote
+            .sign_request("PUT", uri, "remote.example.com", Some("bridge.example.com"), Some(&body))
+            .unwrap();
+
+        let origin = bridge.verify_federation_auth("PUT", uri, &[&header], Some(&body)).await.unwrap();
+        assert_eq!(origin, "remote.example.com");
+
+        // Tampered body, missing header, or a request meant for another server
+        let tampered = serde_json::json!({"pdus": [{}]});
+        assert!(bridge.verify_federation_auth("PUT", uri, &[&header], Some(&tampered)).await.is_err());
+        assert!(bridge.verify_federation_auth("PUT", uri, &[], Some(&body)).await.is_err());
+        let misdirected = remote
+            .sign_request("PUT", uri, "remote.example.com", Some("other.example.com"), Some(&body))
+            .unwrap();
+        assert!(bridge.verify_federation_auth("PUT", uri, &[&misdirected], Some(&body)).await.is_err());
+    }
+
+    #[test]
+    fn test_federation_response_from_reply() {
+        let payload = serde_json::json!({
+            "status_code": 403,
+            "response_body": {"errcode": "M_FORBIDDEN", "error": "You are not invited to this room"},
+            "headers": {"Content-Type": "application/json", "Set-Cookie": "dropped"}
+        });
+        let response = federation_response_from_reply(&payload).unwrap();
+        assert_eq!(response.status_code, 403);
+        assert_eq!(response.body["errcode"], "M_FORBIDDEN");
+        assert_eq!(response.headers.get("content-type").map(String::as_str), Some("application/json"));
+        assert_eq!(response.headers.len(), 1);
+
+        // A reply without a usable status is an error, not a success
+        let missing = serde_json::json!({"response_body": {}});
+        assert!(matches!(federation_response_from_reply(&missing), Err(BridgeError::InvalidResponse { .. })));
+        let bogus = serde_json::json!({"status_code": 70000});
+        assert!(federation_response_from_reply(&bogus).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_compression_negotiation() {
+        let bridge = MatrixMyceliumBridge::new(BridgeConfig::default()).await.unwrap();
+        let peer = "aa".repeat(32);
+
+        // Unknown peers and bare `v1` peers get uncompressed payloads
+        assert_eq!(bridge.compression_for(&peer).await, Compression::None);
+        bridge.record_peer_envelope_flags(&peer, crate::envelope::FLAG_ACCEPTS_ZSTD).await;
+        assert_eq!(bridge.compression_for(&peer).await, Compression::Zstd);
+        bridge.record_peer_envelope_flags(&peer, 0).await;
+        assert_eq!(bridge.compression_for(&peer).await, Compression::None);
+    }
+
+    /// Answers discovery for every server with whatever `answer` holds:
+    /// 0 a key, 1 an outage, 2 no key.
+    struct SwitchedResolver {
+        answer: Arc<std::sync::atomic::AtomicU8>,
+        lookups: Arc<AtomicU32>,
+    }
+
+    impl RouteResolver for SwitchedResolver {
+        fn name(&self) -> &'static str {
+            "switched"
+        }
+
+        fn resolve<'a>(&'a self, _server_name: &'a str) -> futures::future::BoxFuture<'a, Result<Option<crate::discovery::DiscoveredKey>>> {
+            self.lookups.fetch_add(1, Ordering::Relaxed);
+            let answer = self.answer.load(Ordering::Relaxed);
+            Box::pin(async move {
+                match answer {
+                    0 => Ok(Some(crate::discovery::DiscoveredKey { mycelium_key: "ab".repeat(32), ttl: None })),
+                    1 => Err(BridgeError::Unreachable { message: "well-known answered 503".to_string() }),
+                    _ => Ok(None),
+                }
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_discovered_route_survives_resolver_outage() {
+        let answer = Arc::new(std::sync::atomic::AtomicU8::new(0));
+        let lookups = Arc::new(AtomicU32::new(0));
+        let config = BridgeConfig {
+            // Every answer expires at once, so each lookup asks the resolver again
+            route_discovery_ttl: 0,
+            route_discovery_negative_ttl: 0,
+            ..BridgeConfig::default()
+        };
+        let resolver = SwitchedResolver { answer: answer.clone(), lookups: lookups.clone() };
+        let bridge = MatrixMyceliumBridge::new(config).await.unwrap().with_route_resolvers(vec![Box::new(resolver)]);
+
+        let route = bridge.get_mycelium_route("remote.example.com").await.unwrap();
+        assert!(route.discovered);
+
+        // An outage keeps the route, an authoritative miss withdraws it
+        answer.store(1, Ordering::Relaxed);
+        assert_eq!(bridge.get_mycelium_route("remote.example.com").await.unwrap().mycelium_key, "ab".repeat(32));
+        answer.store(2, Ordering::Relaxed);
+        assert!(matches!(bridge.get_mycelium_route("remote.example.com").await, Err(BridgeError::NotFound)));
+        assert!(bridge.get_all_federation_routes().await.is_empty());
+
+        // Configured routes aren't checked against discovery, but discovered
+        // ones loaded from the database are
+        bridge.add_federation_route("configured.example.com".to_string(), "cd".repeat(32)).await.unwrap();
+        bridge.server_discovery.lock().await.insert(
+            "loaded.example.com".to_string(),
+            FederationRoute::discovered("loaded.example.com".to_string(), "ef".repeat(32), 0),
+        );
+        let before = lookups.load(Ordering::Relaxed);
+        assert!(bridge.get_mycelium_route("configured.example.com").await.is_ok());
+        assert_eq!(lookups.load(Ordering::Relaxed), before);
+        assert!(matches!(bridge.get_mycelium_route("loaded.example.com").await, Err(BridgeError::NotFound)));
+        assert_eq!(lookups.load(Ordering::Relaxed), before + 1);
+    }
+
+    #[tokio::test]
+    async fn test_route_announcement_handling() {
+        let bridge = MatrixMyceliumBridge::new(BridgeConfig::default()).await.unwrap();
+        let remote = ServerSigningKey::generate();
+        bridge.key_store
+            .insert_verify_key("remote.example.com", &remote.key_id(), remote.verifying_key(), u64::MAX)
+            .await;
+
+        let pubkey = "ab".repeat(32);
+        let now_ms = SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as u64;
+        let inbound = |src_pk: &str, announcement: serde_json::Value| MyceliumInboundMessage {
+            id: "1".to_string(),
+            src_ip: "400::1".to_string(),
+            src_pk: src_pk.to_string(),
+            dst_ip: "400::2".to_string(),
+            dst_pk: "cd".repeat(32),
+            topic: None,
+            payload: Envelope::new(EnvelopeKind::Announcement, announcement).to_base64().unwrap(),
+        };
+
+        // Relayed by another node, or signed by a key the server doesn't own
+        let signed = RouteAnnouncement::new("remote.example.com", &pubkey, now_ms).signed(&remote).unwrap();
+        assert!(bridge.handle_route_announcement(inbound(&"ef".repeat(32), signed.clone())).await.is_err());
+        let forged = RouteAnnouncement::new("remote.example.com", &pubkey, now_ms)
+            .signed(&ServerSigningKey::generate())
+            .unwrap();
+        assert!(bridge.handle_route_announcement(inbound(&pubkey, forged)).await.is_err());
+        assert!(bridge.get_all_federation_routes().await.is_empty());
+
+        bridge.handle_route_announcement(inbound(&pubkey, signed)).await.unwrap();
+        let route = bridge.get_mycelium_route("remote.example.com").await.unwrap();
+        assert_eq!(route.mycelium_key, pubkey);
+        assert!(route.discovered);
+        let expires_at = route.endpoints[0].expires_at.unwrap();
+        assert!(expires_at > unix_now());
+        assert_eq!(bridge.compression_for(&pubkey).await, Compression::Zstd);
+
+        // Announced nodes add up, and announcing a node again keeps it longer
+        let second = "cd".repeat(32);
+        let announce = |key: &str| RouteAnnouncement::new("remote.example.com", key, now_ms).signed(&remote).unwrap();
+        bridge.handle_route_announcement(inbound(&second, announce(&second))).await.unwrap();
+        bridge.server_discovery.lock().await.get_mut("remote.example.com").unwrap().endpoints
+            .iter_mut()
+            .for_each(|endpoint| endpoint.expires_at = Some(endpoint.expires_at.unwrap() - 600));
+        bridge.handle_route_announcement(inbound(&pubkey, announce(&pubkey))).await.unwrap();
+        let route = bridge.get_mycelium_route("remote.example.com").await.unwrap();
+        assert_eq!(route.endpoints.len(), 2);
+        assert!(route.endpoints.iter().all(|endpoint| endpoint.expires_at.is_some()));
+        assert!(route.endpoints.iter().any(|endpoint| endpoint.mycelium_key == pubkey && endpoint.expires_at >= Some(expires_at)));
+
+        // Nodes that stop being announced are dropped, then the route with the last of them
+        async fn expire(bridge: &MatrixMyceliumBridge, key: &str) {
+            bridge.server_discovery.lock().await.get_mut("remote.example.com").unwrap().endpoints
+                .iter_mut()
+                .filter(|endpoint| endpoint.mycelium_key == key)
+                .for_each(|endpoint| endpoint.expires_at = Some(0));
+        }
+        expire(&bridge, &second).await;
+        let route = bridge.get_mycelium_route("remote.example.com").await.unwrap();
+        assert_eq!(route.endpoint_order(), vec![pubkey.clone()]);
+        assert_eq!(bridge.expire_announced_routes().await, 0);
+        expire(&bridge, &pubkey).await;
+        assert_eq!(bridge.expire_announced_routes().await, 1);
+        assert!(bridge.get_all_federation_routes().await.is_empty());
+
+        // Announcements never touch routes operators configured
+        bridge.key_store
+            .insert_verify_key("configured.example.com", &remote.key_id(), remote.verifying_key(), u64::MAX)
+            .await;
+        bridge.add_federation_route("configured.example.com".to_string(), "ef".repeat(32)).await.unwrap();
+        let announced = RouteAnnouncement::new("configured.example.com", &pubkey, now_ms).signed(&remote).unwrap();
+        bridge.handle_route_announcement(inbound(&pubkey, announced)).await.unwrap();
+        let configured = bridge.get_mycelium_route("configured.example.com").await.unwrap();
+        assert!(!configured.discovered);
+        assert_eq!(configured.endpoints, vec![MyceliumEndpoint::new("ef".repeat(32))]);
+    }
+
+    #[tokio::test]
+    async fn test_route_marked_unhealthy_after_failed_probes() {
+        let config = BridgeConfig {
+            mycelium_api_url: Some("http://127.0.0.1:1".to_string()),
+            route_unhealthy_threshold: 2,
+            route_discovery_methods: Vec::new(),
+            ..BridgeConfig::default()
+        };
+        let bridge = MatrixMyceliumBridge::new(config).await.unwrap();
+        let server = "127.0.0.1:1";
+        bridge.add_federation_route(server.to_string(), "ab".repeat(32)).await.unwrap();
+        assert!(bridge.should_use_mycelium(server).await);
+
+        let route = bridge.probe_route(server).await.unwrap();
+        assert!(route.healthy);
+        assert_eq!(route.consecutive_failures, 1);
+        assert!(route.last_probed.is_some());
+
+        let route = bridge.probe_route(server).await.unwrap();
+        assert!(!route.healthy);
+        assert!(!bridge.should_use_mycelium(server).await);
+
+        // Re-adding the route starts its health over
+        bridge.add_federation_route(server.to_string(), "ab".repeat(32)).await.unwrap();
+        assert!(bridge.should_use_mycelium(server).await);
+        assert!(bridge.probe_route("unknown.example.com:1").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_failover_between_route_endpoints() {
+        // A stand-in Mycelium node that can only reach the second endpoint
+        let reachable = "bb".repeat(32);
+        let reachable_key = reachable.clone();
+        let app = axum::Router::new().route("/api/v1/messages", axum::routing::post(move |axum::Json(message): axum::Json<serde_json::Value>| {
+            let reachable = reachable_key.clone();
+            async move {
+                if message["dst"]["pk"] != reachable.as_str() {
+                    return Err(axum::http::StatusCode::BAD_GATEWAY);
+                }
+                let request = Envelope::from_base64(message["payload"].as_str().unwrap()).unwrap();
+                let reply = Envelope::response(Some(request.message_id), serde_json::json!({
+                    "status_code": 200,
+                    "response_body": {"server": {"name": "stand-in"}}
+                }));
+                Ok(axum::Json(serde_json::json!({
+                    "id": "1", "srcIp": "400::1", "srcPk": reachable, "dstIp": "400::2", "dstPk": "cc",
+                    "payload": reply.to_base64().unwrap()
+                })))
+            }
+        }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let config = BridgeConfig {
+            mycelium_api_url: Some(format!("http://{}", addr)),
+            route_discovery_methods: Vec::new(),
+            ..BridgeConfig::default()
+        };
+        let bridge = MatrixMyceliumBridge::new(config).await.unwrap();
+        let endpoints = vec![
+            MyceliumEndpoint { mycelium_key: "aa".repeat(32), priority: 0, weight: 5, expires_at: None },
+            MyceliumEndpoint { mycelium_key: reachable.clone(), priority: 1, weight: 1, expires_at: None },
+        ];
+        bridge.set_federation_route_endpoints("remote.example.com".to_string(), endpoints).await.unwrap();
+
+        let route = bridge.get_mycelium_route("remote.example.com").await.unwrap();
+        assert_eq!(route.mycelium_key, "aa".repeat(32));
+        assert_eq!(route.endpoint_order(), vec!["aa".repeat(32), reachable]);
+
+        let request = FederationRequest {
+            method: "GET".to_string(),
+            path: "/_matrix/federation/v1/version".to_string(),
+            body: None,
+            headers: std::collections::HashMap::new(),
+        };
+        let response = bridge.send_via_mycelium(request, "remote.example.com".to_string(), MyceliumFailure::Report).await.unwrap();
+        assert_eq!(response.status_code, 200);
+        assert_eq!(response.body["server"]["name"], "stand-in");
+
+        // Empty sets, zero weights, duplicates and keys that aren't Mycelium keys are rejected
+        assert!(bridge.set_federation_route_endpoints("remote.example.com".to_string(), Vec::new()).await.is_err());
+        let placeholder = vec![MyceliumEndpoint::new("aa".repeat(32)), MyceliumEndpoint::new("default-key-1".to_string())];
+        assert!(bridge.set_federation_route_endpoints("remote.example.com".to_string(), placeholder).await.is_err());
+        let duplicate = vec![MyceliumEndpoint::new("aa".repeat(32)), MyceliumEndpoint::new("AA".repeat(32))];
+        assert!(bridge.set_federation_route_endpoints("remote.example.com".to_string(), duplicate).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_room_fan_out() {
+        let config = BridgeConfig {
+            server_name: "example.com".to_string(),
+            ..BridgeConfig::default()
+        };
+        let bridge = MatrixMyceliumBridge::new(config).await.unwrap();
+
+        // Nobody else known in our own room, so nothing goes out
+        let event = MatrixEvent {
+            event_id: "$event:example.com".to_string(),
+            event_type: "m.room.message".to_string(),
+            room_id: "!room:example.com".to_string(),
+            sender: "@user:example.com".to_string(),
+            origin_server_ts: 1234567890,
+            content: serde_json::json!({"body": "Hello", "msgtype": "m.text"}),
+            state_key: None,
+            ..MatrixEvent::default()
+        };
+        assert!(bridge.send_event_to_room(event).await.unwrap().is_empty());
+
+        let response = |status_code, body| FederationResponse { status_code, body, headers: Default::default() };
+        let queued = DestinationResult::from_response("a.example".to_string(), &response(202, serde_json::json!({"status": "queued"})));
+        assert_eq!(queued.status, "queued");
+        let delivered = DestinationResult::from_response("b.example".to_string(), &response(200, serde_json::json!({})));
+        assert_eq!(delivered.status, "delivered");
+        let failed = DestinationResult::from_response("c.example".to_string(), &response(403, serde_json::json!({"error": "Forbidden"})));
+        assert_eq!((failed.status.as_str(), failed.error.as_deref()), ("failed", Some("Forbidden")));
+    }
+
+    #[tokio::test]
+    async fn test_transport_policy_enforcement() {
+        let config = BridgeConfig {
+            mycelium_api_url: None,
+            route_discovery_methods: Vec::new(),
+            transport_policies: vec![
+                "blocked.example.com=blocked".parse().unwrap(),
+                "*.mycelium.example.com=mycelium_only".parse().unwrap(),
+                "direct.example.com=matrix_only".parse().unwrap(),
+            ],
+            ..BridgeConfig::default()
+        };
+        let bridge = MatrixMyceliumBridge::new(config).await.unwrap();
+        bridge.add_federation_route("direct.example.com".to_string(), "ab".repeat(32)).await.unwrap();
+        bridge.add_federation_route("a.mycelium.example.com".to_string(), "cd".repeat(32)).await.unwrap();
+
+        assert!(matches!(bridge.route_via_mycelium("blocked.example.com").await, Err(BridgeError::Blocked { .. })));
+        assert!(!bridge.route_via_mycelium("direct.example.com").await.unwrap());
+        assert!(bridge.route_via_mycelium("a.mycelium.example.com").await.unwrap());
+        assert!(matches!(bridge.route_via_mycelium("b.mycelium.example.com").await, Err(BridgeError::Unreachable { .. })));
+        assert!(!bridge.route_via_mycelium("other.example.com").await.unwrap());
+
+        // Mycelium-only destinations don't fall back to Matrix when Mycelium can't deliver
+        let request = FederationRequest {
+            method: "GET".to_string(),
+            path: "/_matrix/federation/v1/version".to_string(),
+            body: None,
+            headers: std::collections::HashMap::from([("Destination".to_string(), "a.mycelium.example.com".to_string())]),
+        };
+        assert!(matches!(bridge.handle_federation_request(request.clone()).await, Err(BridgeError::Config { .. })));
+        let blocked = FederationRequest {
+            headers: std::collections::HashMap::from([("Destination".to_string(), "blocked.example.com".to_string())]),
+            ..request
+        };
+        assert!(matches!(bridge.handle_federation_request(blocked).await, Err(BridgeError::Blocked { .. })));
+    }
+
+    /// A stand-in homeserver answering every federation request with 200.
+    async fn stand_in_homeserver() -> String {
+        let app = axum::Router::new().fallback(|| async { axum::Json(serde_json::json!({"server": {"name": "homeserver"}})) });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn test_circuit_opens_after_mycelium_failures() {
+        // Nothing listens on the Mycelium API port, so every send fails
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        let config = BridgeConfig {
+            mycelium_api_url: Some(format!("http://{}", addr)),
+            matrix_homeserver_url: stand_in_homeserver().await,
+            route_discovery_methods: Vec::new(),
+            circuit_failure_threshold: 2,
+            ..BridgeConfig::default()
+        };
+        let bridge = MatrixMyceliumBridge::new(config).await.unwrap();
+        bridge.add_federation_route("remote.example.com".to_string(), "ab".repeat(32)).await.unwrap();
+
+        let request = FederationRequest {
+            method: "GET".to_string(),
+            path: "/_matrix/federation/v1/version".to_string(),
+            body: None,
+            headers: std::collections::HashMap::new(),
+        };
+        for _ in 0..2 {
+            let response = bridge.handle_via_mycelium(request.clone(), "remote.example.com".to_string()).await.unwrap();
+            assert_eq!(response.body["server"]["name"], "homeserver");
+        }
+
+        let status = bridge.get_bridge_status().await.unwrap();
+        let circuit = &status.circuit_breakers["remote.example.com"];
+        assert_eq!(circuit.state, crate::breaker::CircuitState::Open);
+        assert_eq!(circuit.consecutive_failures, 2);
+
+        // With the circuit open Mycelium isn't tried at all
+        let response = bridge.handle_via_mycelium(request, "remote.example.com".to_string()).await.unwrap();
+        assert_eq!(response.status_code, 200);
+        assert_eq!(bridge.circuit_status("remote.example.com").await.unwrap().consecutive_failures, 2);
+    }
+
+    #[tokio::test]
+    async fn test_prefer_mycelium_falls_back_on_any_failure() {
+        // A stand-in Mycelium node whose peers never reply in time
+        let app = axum::Router::new().route("/api/v1/messages", axum::routing::post(|| async {
+            axum::http::StatusCode::REQUEST_TIMEOUT
+        }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let timing_out = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        // And a port nothing listens on, for a local node that is down
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let down = listener.local_addr().unwrap();
+        drop(listener);
+
+        let request = FederationRequest {
+            method: "GET".to_string(),
+            path: "/_matrix/federation/v1/version".to_string(),
+            body: None,
+            headers: std::collections::HashMap::new(),
+        };
+        let homeserver = stand_in_homeserver().await;
+        for mycelium in [timing_out, down] {
+            let config = BridgeConfig {
+                mycelium_api_url: Some(format!("http://{}", mycelium)),
+                matrix_homeserver_url: homeserver.clone(),
+                route_discovery_methods: Vec::new(),
+                transport_policies: vec!["*.mycelium.example.com=mycelium_only".parse().unwrap()],
+                ..BridgeConfig::default()
+            };
+            let bridge = MatrixMyceliumBridge::new(config).await.unwrap();
+            bridge.add_federation_route("remote.example.com".to_string(), "ab".repeat(32)).await.unwrap();
+            bridge.add_federation_route("a.mycelium.example.com".to_string(), "cd".repeat(32)).await.unwrap();
+
+            for on_failure in [MyceliumFailure::QueueOrFallback, MyceliumFailure::Fallback] {
+                let response = bridge.send_via_mycelium(request.clone(), "remote.example.com".to_string(), on_failure).await.unwrap();
+                assert_eq!(response.body["server"]["name"], "homeserver");
+            }
+
+            // Mycelium-only destinations still see the failure
+            let result = bridge.send_via_mycelium(request.clone(), "a.mycelium.example.com".to_string(), MyceliumFailure::Fallback).await;
+            assert!(matches!(result, Err(BridgeError::Timeout | BridgeError::MyceliumApi { .. })));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_route_management() {
+        let config = BridgeConfig {
+            mycelium_api_url: None,
+            route_discovery_methods: Vec::new(),
+            ..BridgeConfig::default()
+        };
+        let bridge = MatrixMyceliumBridge::new(config).await.unwrap();
+        let table = RouteTable {
+            routes: ["a.example.com", "b.example.com", "c.example.org"].iter()
+                .map(|server| RouteSpec {
+                    server_name: server.to_string(),
+                    mycelium_key: Some("ab".repeat(32)),
+                    endpoints: None,
+                })
+                .collect(),
+        };
+        let result = bridge.import_route_table(table, false).await.unwrap();
+        assert_eq!((result.imported, result.removed), (3, 0));
+
+        let update = RouteUpdate { healthy: Some(false), ..RouteUpdate::default() };
+        assert!(!bridge.update_federation_route("b.example.com", update).await.unwrap().healthy);
+        assert!(matches!(bridge.update_federation_route("z.example.com", RouteUpdate::default()).await, Err(BridgeError::NotFound)));
+
+        let query = RouteListQuery { server: Some("*.example.com".to_string()), healthy: Some(true), ..RouteListQuery::default() };
+        let list = bridge.list_federation_routes(&query).await;
+        assert_eq!(list.total, 1);
+        assert_eq!(list.routes[0].route.destination_server, "a.example.com");
+
+        let query = RouteListQuery { offset: Some(1), limit: Some(1), ..RouteListQuery::default() };
+        let list = bridge.list_federation_routes(&query).await;
+        assert_eq!((list.count, list.total), (1, 3));
+        assert_eq!(list.routes[0].route.destination_server, "b.example.com");
+
+        // A replacing import drops what it doesn't list, and a bad entry changes nothing
+        let mut exported = bridge.export_route_table().await;
+        exported.routes.retain(|spec| spec.server_name != "c.example.org");
+        let result = bridge.import_route_table(exported.clone(), true).await.unwrap();
+        assert_eq!((result.imported, result.removed), (2, 1));
+
+        exported.routes[0].endpoints = Some(Vec::new());
+        exported.routes[1].mycelium_key = Some("cd".repeat(32));
+        assert!(bridge.import_route_table(exported, true).await.is_err());
+        assert_eq!(bridge.get_all_federation_routes().await.len(), 2);
+    }
+
+    /// `pdu` with its content hash and the signature `key`'s server gives it.
+    fn signed_pdu(key: &ServerSigningKey, mut pdu: serde_json::Value, version: RoomVersion) -> serde_json::Value {
+        pdu["hashes"] = serde_json::json!({"sha256": content_hash(&pdu).unwrap()});
+        let signature = key.sign_json(&redact_pdu(&pdu, version)).unwrap();
+        pdu["signatures"] = serde_json::json!({"remote.example.com": {key.key_id(): signature}});
+        pdu
+    }
+
+    #[tokio::test]
+    async fn test_transaction_processing() {
+        let config = BridgeConfig {
+            route_discovery_methods: Vec::new(),
+            ..BridgeConfig::default()
+        };
+        let bridge = Arc::new(MatrixMyceliumBridge::new(config).await.unwrap());
+        let remote_key = ServerSigningKey::generate();
+        bridge.key_store.insert_verify_key("remote.example.com", &remote_key.key_id(), remote_key.verifying_key(), u64::MAX).await;
+        let v1 = RoomVersion::parse("1").unwrap();
+        let v10 = RoomVersion::parse("10").unwrap();
+
+        let create = |room_id: &str, content: serde_json::Value| serde_json::json!({
+            "type": "m.room.create", "room_id": room_id, "sender": "@alice:remote.example.com",
+            "state_key": "", "origin_server_ts": 1700000000000u64, "content": content
+        });
+        let message = |room_id: &str, body: &str| serde_json::json!({
+            "type": "m.room.message", "room_id": room_id,
+            "sender": "@alice:remote.example.com", "origin_server_ts": 1700000000001u64,
+            "content": {"msgtype": "m.text", "body": body}
+        });
+        let member = serde_json::json!({
+            "type": "m.room.member", "room_id": "!room:remote.example.com",
+            "sender": "@alice:remote.example.com", "origin_server_ts": 1700000000000u64,
+            "content": {}, "state_key": "@alice:remote.example.com"
+        });
+        let mut legacy_create = create("!old:remote.example.com", serde_json::json!({"creator": "@alice:remote.example.com"}));
+        legacy_create["event_id"] = serde_json::json!("$legacy-create:remote.example.com");
+        let mut legacy_message = message("!old:remote.example.com", "room version 1 events carry their ID");
+        legacy_message["event_id"] = serde_json::json!("$legacy:remote.example.com");
+        let mut disguised = message("!room:remote.example.com", "IDs are hashes from room version 3 on");
+        disguised["event_id"] = serde_json::json!("$claimed");
+        let mut tampered = signed_pdu(&remote_key, message("!room:remote.example.com", "as sent"), v10);
+        tampered["content"]["body"] = serde_json::json!("as received");
+        let mut forged = signed_pdu(&ServerSigningKey::generate(), message("!room:remote.example.com", "forged"), v10);
+        forged["signatures"] = serde_json::json!({
+            "remote.example.com": {remote_key.key_id(): forged["signatures"]["remote.example.com"].as_object().unwrap().values().next().unwrap()}
+        });
+
+        let transaction: Transaction = serde_json::from_value(serde_json::json!({
+            "origin": "remote.example.com",
+            "origin_server_ts": 1700000000000u64,
+            "pdus": [
+                signed_pdu(&remote_key, create("!room:remote.example.com", serde_json::json!({"creator": "@alice:remote.example.com", "room_version": "10"})), v10),
+                signed_pdu(&remote_key, message("!room:remote.example.com", "hi"), v10),
+                signed_pdu(&remote_key, member, v10),
+                signed_pdu(&remote_key, disguised, v10),
+                signed_pdu(&remote_key, legacy_create, v1),
+                signed_pdu(&remote_key, legacy_message, v1),
+                message("!unknown:remote.example.com", "nobody told us this room's version"),
+                create("!new:remote.example.com", serde_json::json!({"creator": "@alice:remote.example.com", "room_version": "org.example.future"})),
+                message("!room:remote.example.com", "unsigned"),
+                tampered,
+                forged
+            ],
+            "edus": [{"edu_type": "m.typing", "content": {}}]
+        })).unwrap();
+
+        let response = bridge.process_transaction("remote.example.com", "txn1", transaction.clone()).await.unwrap();
+        let event_id = |index: usize| reference_event_id(&transaction.pdus[index], v10).unwrap();
+        assert_eq!(response.pdus[&event_id(0)], PduResult::default());
+        assert_eq!(response.pdus[&event_id(1)], PduResult::default());
+        assert_eq!(serde_json::to_value(&response).unwrap()["pdus"][event_id(1)], serde_json::json!({}));
+        assert!(response.pdus[&event_id(2)].error.is_some());
+        assert_eq!(response.pdus[&event_id(3)], PduResult::default());
+        assert!(!response.pdus.contains_key("$claimed"));
+        assert_eq!(response.pdus["$legacy-create:remote.example.com"], PduResult::default());
+        assert_eq!(response.pdus["$legacy:remote.example.com"], PduResult::default());
+        // Unsigned, altered and forged PDUs are refused
+        for index in 8..11 {
+            assert!(response.pdus[&event_id(index)].error.is_some(), "PDU {} was accepted", index);
+        }
+        // Events of rooms with unknown or unsupported versions can't even be named
+        assert_eq!(response.pdus.len(), 9);
+
+        // Nobody but the claimed origin may send its transactions
+        let spoofed = bridge.process_transaction("other.example.com", "txn1", transaction).await;
+        assert!(matches!(spoofed, Err(BridgeError::Auth { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_edu_relay_over_mycelium() {
+        // A stand-in Mycelium node that records what it's asked to send
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let recorded = sent.clone();
+        let app = axum::Router::new().route("/api/v1/messages", axum::routing::post(move |axum::Json(message): axum::Json<serde_json::Value>| {
+            let recorded = recorded.clone();
+            async move {
+                recorded.lock().await.push(message);
+                axum::Json(serde_json::json!({"id": "1"}))
+            }
+        }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let config = BridgeConfig {
+            mycelium_api_url: Some(format!("http://{}", addr)),
+            route_discovery_methods: Vec::new(),
+            ..BridgeConfig::default()
+        };
+        let bridge = MatrixMyceliumBridge::new(config).await.unwrap();
+        bridge.add_federation_route("remote.example.com".to_string(), "ab".repeat(32)).await.unwrap();
+
+        for typing in [true, false] {
+            let request = FederationRequest {
+                method: "PUT".to_string(),
+                path: format!("/_matrix/federation/v1/send/{}", typing),
+                body: Some(serde_json::json!({
+                    "origin": "localhost",
+                    "origin_server_ts": 0,
+                    "pdus": [],
+                    "edus": [{"edu_type": "m.typing", "content": {"room_id": "!r:localhost", "user_id": "@u:localhost", "typing": typing}}]
+                })),
+                headers: std::collections::HashMap::from([("Destination".to_string(), "remote.example.com".to_string())]),
+            };
+            let response = bridge.handle_federation_request(request).await.unwrap();
+            assert_eq!(response.body, serde_json::json!({"pdus": {}}));
+        }
+        assert!(sent.lock().await.is_empty());
+
+        // Both updates went out as one message on the typing topic
+        assert_eq!(bridge.flush_edus().await, 1);
+        let sent = sent.lock().await;
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0]["topic"], encode_topic("matrix.federation.edu.typing"));
+        let envelope = Envelope::from_base64(sent[0]["payload"].as_str().unwrap()).unwrap();
+        assert_eq!(envelope.kind, EnvelopeKind::Event);
+        let edus = &envelope.body["body"]["edus"];
+        assert_eq!(edus.as_array().unwrap().len(), 1);
+        assert_eq!(edus[0]["content"]["typing"], false);
+        assert_eq!(bridge.flush_edus().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_relayed_transaction_pdus() {
+        let bridge = MatrixMyceliumBridge::new(BridgeConfig::default()).await.unwrap();
+        let event = MatrixEvent {
+            event_id: "$b".to_string(),
+            event_type: "m.room.message".to_string(),
+            room_id: "!r:remote.example.com".to_string(),
+            sender: "@u:remote.example.com".to_string(),
+            origin_server_ts: 1,
+            content: serde_json::json!({"msgtype": "m.text", "body": "hi"}),
+            prev_events: vec!["$a".to_string()],
+            depth: 2,
+            ..MatrixEvent::default()
+        };
+
+        // PDUs relayed over Mycelium carry the type twice
+        let mut payload = event.to_pdu();
+        payload["event_type"] = serde_json::json!(event.event_type);
+        let request = FederationRequest {
+            method: "PUT".to_string(),
+            path: "/_matrix/federation/v1/send/1".to_string(),
+            body: Some(serde_json::json!({"origin": "remote.example.com", "origin_server_ts": 0, "pdus": [payload]})),
+            headers: std::collections::HashMap::new(),
+        };
+        let transaction = relayed_transaction(&request).unwrap();
+        let parsed = MatrixEvent::from_pdu(transaction.pdus[0].clone()).unwrap();
+        assert_eq!(parsed.event_type, "m.room.message");
+        assert_eq!(parsed.prev_events, vec!["$a".to_string()]);
+        assert!(relayed_transaction(&FederationRequest { method: "GET".to_string(), ..request }).is_none());
+
+        // Only PDUs the homeserver accepted are recorded
+        let response = |status_code| FederationResponse {
+            status_code,
+            body: serde_json::json!({"pdus": {"$a": {}, "$b": {"error": "rejected"}}}),
+            headers: std::collections::HashMap::new(),
+        };
+        assert_eq!(accepted_pdus(&response(200)), HashSet::from(["$a".to_string()]));
+        assert!(accepted_pdus(&response(401)).is_empty());
+
+        // History is only served from a database, and only to servers in the room
+        assert!(matches!(bridge.backfill("remote.example.com", &event.room_id, &["$b".to_string()], 10).await, Err(BridgeError::Config { .. })));
+        let join = MatrixEvent {
+            event_type: "m.room.member".to_string(),
+            state_key: Some("@u:remote.example.com".to_string()),
+            content: serde_json::json!({"membership": "join"}),
+            ..event.clone()
+        };
+        assert!(matches!(ensure_server_in_room("remote.example.com", &[], &event), Err(BridgeError::Forbidden { .. })));
+        assert!(ensure_server_in_room("remote.example.com", &[join], &event).is_ok());
+    }
 }
@@ -2,6 +2,9 @@ use serde::{Deserialize, Serialize};
 use config::Config;
 use std::convert::TryFrom;
 
+use crate::envelope::Compression;
+use crate::policy::{PolicyRule, TransportPolicy};
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BridgeConfig {
     pub server_host: String,
@@ -14,6 +17,55 @@ pub struct BridgeConfig {
     pub max_connections: u32,
     pub log_level: String,
     pub mycelium_enabled: bool,
+    /// Our homeserver's name, used as the `origin` of signed federation requests.
+    pub server_name: String,
+    /// Where the ed25519 server signing key is stored; a new key is generated
+    /// there on first start. Without a path an ephemeral key is used.
+    pub signing_key_path: Option<String>,
+    /// Notary servers asked for a remote server's keys when it can't be reached directly.
+    pub trusted_key_servers: Vec<String>,
+    /// How many inbound Mycelium messages are processed concurrently.
+    pub mycelium_consumer_concurrency: u32,
+    /// Long-poll timeout in seconds when popping messages from the Mycelium node.
+    pub mycelium_poll_timeout: u64,
+    /// Largest encoded payload sent as one Mycelium message; bigger ones are fragmented.
+    pub mycelium_max_message_size: usize,
+    /// Seconds to wait for the missing fragments of a partially received message.
+    pub mycelium_reassembly_timeout: u64,
+    /// Upper bound in bytes on fragments buffered for reassembly across all messages.
+    pub mycelium_reassembly_memory_limit: usize,
+    /// Compression used for Mycelium payloads to peers that advertise support for it.
+    pub mycelium_compression: Compression,
+    /// Payloads up to this many bytes are sent uncompressed.
+    pub mycelium_compression_threshold: usize,
+    /// How routes are discovered for servers without one: `well_known`, `dns_txt`.
+    pub route_discovery_methods: Vec<String>,
+    /// Longest a discovered route is trusted before it is looked up again, in seconds.
+    pub route_discovery_ttl: u64,
+    /// How long a server without a discoverable route isn't asked again, in seconds.
+    pub route_discovery_negative_ttl: u64,
+    /// Our Mycelium node's public key as announced to peers. Asked from the node when unset.
+    pub mycelium_public_key: Option<String>,
+    /// Seconds between route announcements to known peers; 0 disables gossip.
+    pub gossip_interval: u64,
+    /// Seconds between health probes of every route; 0 disables probing.
+    pub route_probe_interval: u64,
+    /// Probes that must fail in a row before a route is marked unhealthy.
+    pub route_unhealthy_threshold: u32,
+    /// Mycelium failures in a row that open a destination's circuit breaker.
+    pub circuit_failure_threshold: u32,
+    /// Seconds an open circuit sends traffic over classic federation before trying Mycelium again.
+    pub circuit_open_duration: u64,
+    /// Milliseconds EDUs are collected and coalesced for before being relayed
+    /// over Mycelium; 0 sends them inside their transactions instead.
+    pub edu_flush_interval: u64,
+    /// Seconds a queued EDU may wait before it's dropped as stale.
+    pub edu_ttl: u64,
+    /// Transport policy for servers no rule in `transport_policies` matches.
+    pub default_transport_policy: TransportPolicy,
+    /// Per-server transport policies, configured as `pattern=policy` where the
+    /// pattern may use `*` and `?` globs.
+    pub transport_policies: Vec<PolicyRule>,
 }
 
 impl Default for BridgeConfig {
@@ -29,6 +81,29 @@ impl Default for BridgeConfig {
             max_connections: 100,
             log_level: "info".to_string(),
             mycelium_enabled: true,
+            server_name: "localhost".to_string(),
+            signing_key_path: None,
+            trusted_key_servers: Vec::new(),
+            mycelium_consumer_concurrency: 8,
+            mycelium_poll_timeout: 60,
+            mycelium_max_message_size: 64 * 1024,
+            mycelium_reassembly_timeout: 60,
+            mycelium_reassembly_memory_limit: 64 * 1024 * 1024,
+            mycelium_compression: Compression::Zstd,
+            mycelium_compression_threshold: 1024,
+            route_discovery_methods: vec!["well_known".to_string(), "dns_txt".to_string()],
+            route_discovery_ttl: 3600,
+            route_discovery_negative_ttl: 300,
+            mycelium_public_key: None,
+            gossip_interval: 300,
+            route_probe_interval: 60,
+            route_unhealthy_threshold: 3,
+            circuit_failure_threshold: 5,
+            circuit_open_duration: 30,
+            edu_flush_interval: 250,
+            edu_ttl: 30,
+            default_transport_policy: TransportPolicy::PreferMycelium,
+            transport_policies: Vec::new(),
         }
     }
 }
@@ -65,6 +140,47 @@ impl TryFrom<Config> for BridgeConfig {
             max_connections: config.get_int("max_connections")? as u32,
             log_level: config.get_string("log_level")?,
             mycelium_enabled: config.get_bool("mycelium_enabled")?,
+            server_name: config.get_string("server_name")?,
+            signing_key_path: config.get_string("signing_key_path").ok(),
+            trusted_key_servers: config.get_array("trusted_key_servers")
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|v| v.into_string().ok())
+                .collect(),
+            mycelium_consumer_concurrency: config.get_int("mycelium_consumer_concurrency")? as u32,
+            mycelium_poll_timeout: config.get_int("mycelium_poll_timeout")? as u64,
+            mycelium_max_message_size: config.get_int("mycelium_max_message_size")? as usize,
+            mycelium_reassembly_timeout: config.get_int("mycelium_reassembly_timeout")? as u64,
+            mycelium_reassembly_memory_limit: config.get_int("mycelium_reassembly_memory_limit")? as usize,
+            mycelium_compression: config.get_string("mycelium_compression")?
+                .parse()
+                .map_err(config::ConfigError::Message)?,
+            mycelium_compression_threshold: config.get_int("mycelium_compression_threshold")? as usize,
+            route_discovery_methods: config.get_array("route_discovery_methods")
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|v| v.into_string().ok())
+                .collect(),
+            route_discovery_ttl: config.get_int("route_discovery_ttl")? as u64,
+            route_discovery_negative_ttl: config.get_int("route_discovery_negative_ttl")? as u64,
+            mycelium_public_key: config.get_string("mycelium_public_key").ok(),
+            gossip_interval: config.get_int("gossip_interval")? as u64,
+            route_probe_interval: config.get_int("route_probe_interval")? as u64,
+            route_unhealthy_threshold: config.get_int("route_unhealthy_threshold")? as u32,
+            circuit_failure_threshold: config.get_int("circuit_failure_threshold")? as u32,
+            circuit_open_duration: config.get_int("circuit_open_duration")? as u64,
+            edu_flush_interval: config.get_int("edu_flush_interval")? as u64,
+            edu_ttl: config.get_int("edu_ttl")? as u64,
+            default_transport_policy: config.get_string("default_transport_policy")?
+                .parse()
+                .map_err(config::ConfigError::Message)?,
+            transport_policies: config.get_array("transport_policies")
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|v| v.into_string().ok())
+                .map(|rule| rule.parse())
+                .collect::<Result<_, String>>()
+                .map_err(config::ConfigError::Message)?,
         })
     }
 }
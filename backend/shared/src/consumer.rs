@@ -0,0 +1,291 @@
+use std::sync::Arc;
+use tokio::sync::{watch, Semaphore};
+use tokio::time::Duration;
+
+use crate::bridge::MatrixMyceliumBridge;
+use crate::envelope::{decode_base64, encode_topic, is_envelope, Envelope};
+use crate::fragment::FRAGMENT_TOPIC;
+use crate::gossip::ANNOUNCE_TOPIC;
+use crate::error::{BridgeError, Result};
+use crate::signing::XMatrixAuth;
+use crate::types::{MyceliumFederationMessage, MyceliumInboundMessage};
+
+/// Topics the bridge consumes. Mycelium only filters on exact topics, so each
+/// one gets its own long-poll loop.
+pub const INBOUND_TOPICS: &[&str] = &[
+    "matrix.federation.get",
+    "matrix.federation.put",
+    "matrix.federation.post",
+    "matrix.federation.delete",
+    "matrix.federation.membership",
+    "matrix.federation.message",
+    "matrix.federation.state",
+    "matrix.federation.redaction",
+    "matrix.federation.encrypted",
+    "matrix.federation.event",
+    "matrix.federation.edu.typing",
+    "matrix.federation.edu.receipt",
+    "matrix.federation.edu.presence",
+    "matrix.federation.edu.device_list",
+    FRAGMENT_TOPIC,
+    ANNOUNCE_TOPIC,
+];
+
+/// How often partially reassembled messages are checked for expiry.
+const FRAGMENT_SWEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+const MIN_ERROR_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_ERROR_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Pop inbound messages from the local Mycelium node and dispatch them into the
+/// bridge until `shutdown` flips to true, then wait for in-flight messages.
+pub async fn run_mycelium_consumer(bridge: Arc<MatrixMyceliumBridge>, shutdown: watch::Receiver<bool>) {
+    let api_url = match &bridge.config.mycelium_api_url {
+        Some(url) if bridge.config.mycelium_enabled => url.clone(),
+        _ => {
+            tracing::info!("Mycelium disabled, inbound consumer not started");
+            return;
+        }
+    };
+
+    // Long polls outlive the bridge's federation timeout, so they get their own client
+    let client = reqwest::Client::new();
+    let concurrency = bridge.config.mycelium_consumer_concurrency.max(1);
+    let permits = Arc::new(Semaphore::new(concurrency as usize));
+
+    tracing::info!("Mycelium consumer started on {} topics with concurrency {}", INBOUND_TOPICS.len(), concurrency);
+
+    let pollers = INBOUND_TOPICS.iter().map(|topic| poll_topic(
+        bridge.clone(),
+        client.clone(),
+        api_url.clone(),
+        topic,
+        permits.clone(),
+        shutdown.clone(),
+    ));
+    tokio::join!(
+        futures::future::join_all(pollers),
+        sweep_fragments(bridge.clone(), shutdown.clone()),
+    );
+
+    // Every permit back means every dispatched message has been handled
+    let _ = permits.acquire_many(concurrency).await;
+    tracing::info!("Mycelium consumer stopped");
+}
+
+async fn poll_topic(
+    bridge: Arc<MatrixMyceliumBridge>,
+    client: reqwest::Client,
+    api_url: String,
+    topic: &'static str,
+    permits: Arc<Semaphore>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let poll_timeout = bridge.config.mycelium_poll_timeout;
+    let mut backoff = MIN_ERROR_BACKOFF;
+
+    while !*shutdown.borrow() {
+        let popped = tokio::select! {
+            popped = pop_message(&client, &api_url, topic, poll_timeout) => popped,
+            _ = shutdown.changed() => break,
+        };
+
+        match popped {
+            Ok(Some(message)) => {
+                backoff = MIN_ERROR_BACKOFF;
+                // Permits bound dispatches rather than polls, so idle long-polls can't
+                // starve other topics; a popped message waits for one, never dropped
+                let Ok(permit) = permits.clone().acquire_owned().await else {
+                    break;
+                };
+                let bridge = bridge.clone();
+                tokio::spawn(async move {
+                    let _permit = permit;
+                    dispatch(&bridge, topic, message).await;
+                });
+            }
+            Ok(None) => {}
+            Err(e) => {
+                tracing::warn!("Failed to pop Mycelium messages for {}: {}", topic, e);
+                tokio::select! {
+                    _ = tokio::time::sleep(backoff) => {},
+                    _ = shutdown.changed() => break,
+                }
+                backoff = (backoff * 2).min(MAX_ERROR_BACKOFF);
+            }
+        }
+    }
+}
+
+/// Long-poll the node for the next message on `topic`. `None` means the poll timed out.
+async fn pop_message(
+    client: &reqwest::Client,
+    api_url: &str,
+    topic: &str,
+    poll_timeout: u64,
+) -> Result<Option<MyceliumInboundMessage>> {
+    let encoded_topic = encode_topic(topic);
+    let timeout = poll_timeout.to_string();
+
+    let response = client
+        .get(format!("{}/api/v1/messages", api_url))
+        .query(&[("peek", "false"), ("timeout", timeout.as_str()), ("topic", encoded_topic.as_str())])
+        .timeout(Duration::from_secs(poll_timeout + 10))
+        .send()
+        .await
+        .map_err(|e| BridgeError::MyceliumApi {
+            message: format!("Failed to poll messages: {}", e)
+        })?;
+
+    if response.status() == reqwest::StatusCode::NO_CONTENT {
+        return Ok(None);
+    }
+    if !response.status().is_success() {
+        return Err(BridgeError::MyceliumApi {
+            message: format!("Message poll returned {}", response.status())
+        });
+    }
+
+    let message = response.json().await.map_err(|e| BridgeError::MyceliumApi {
+        message: format!("Invalid message from Mycelium node: {}", e)
+    })?;
+
+    Ok(Some(message))
+}
+
+async fn sweep_fragments(bridge: Arc<MatrixMyceliumBridge>, mut shutdown: watch::Receiver<bool>) {
+    while !*shutdown.borrow() {
+        tokio::select! {
+            _ = tokio::time::sleep(FRAGMENT_SWEEP_INTERVAL) => bridge.expire_fragments().await,
+            _ = shutdown.changed() => {},
+        }
+    }
+}
+
+async fn dispatch(bridge: &MatrixMyceliumBridge, topic: &str, message: MyceliumInboundMessage) {
+    let message_id = message.id.clone();
+
+    let result = if topic == FRAGMENT_TOPIC {
+        bridge.handle_inbound_fragment(message).await
+    } else if topic == ANNOUNCE_TOPIC {
+        bridge.handle_route_announcement(message).await
+    } else {
+        match decode_inbound_message(message) {
+            Ok(decoded) => bridge.handle_incoming_mycelium_message(decoded).await,
+            Err(e) => Err(e),
+        }
+    };
+
+    if let Err(e) = result {
+        tracing::warn!("Failed to handle Mycelium message {}: {}", message_id, e);
+    }
+}
+
+/// Decode a raw node message into the bridge's federation message.
+pub fn decode_inbound_message(message: MyceliumInboundMessage) -> Result<MyceliumFederationMessage> {
+    let with_id = |e: BridgeError| BridgeError::Serde {
+        message: format!("Mycelium message {}: {}", message.id, e)
+    };
+
+    let topic = match &message.topic {
+        Some(topic) => String::from_utf8(decode_base64("topic", topic).map_err(with_id)?).map_err(|e| BridgeError::Serde {
+            message: format!("Topic of Mycelium message {} is not UTF-8: {}", message.id, e)
+        })?,
+        None => String::new(),
+    };
+
+    let bytes = decode_base64("payload", &message.payload).map_err(with_id)?;
+    decode_inbound_payload(&message, topic, &bytes)
+}
+
+/// Decode the raw payload `bytes` of `message`, received on `topic`. Reassembled
+/// fragments come through here directly.
+pub fn decode_inbound_payload(message: &MyceliumInboundMessage, topic: String, bytes: &[u8]) -> Result<MyceliumFederationMessage> {
+    let with_id = |e: BridgeError| BridgeError::Serde {
+        message: format!("Mycelium message {}: {}", message.id, e)
+    };
+
+    // Peers that predate the envelope send bare JSON
+    let (payload, envelope_id, envelope_flags): (serde_json::Value, _, _) = if is_envelope(bytes) {
+        let envelope = Envelope::decode(bytes).map_err(with_id)?;
+        (envelope.body, Some(envelope.message_id), envelope.flags)
+    } else {
+        (serde_json::from_slice(bytes)?, None, 0)
+    };
+
+    // Events name their origin server; relayed requests carry it in their X-Matrix header
+    let sender = payload.get("sender")
+        .or_else(|| payload.get("origin_server"))
+        .and_then(|v| v.as_str())
+        .map(str::to_string)
+        .or_else(|| payload.get("headers")
+            .and_then(|headers| headers.as_object())
+            .and_then(|headers| headers.iter().find(|(k, _)| k.eq_ignore_ascii_case("Authorization")))
+            .and_then(|(_, v)| v.as_str())
+            .and_then(XMatrixAuth::parse)
+            .map(|auth| auth.origin))
+        .unwrap_or_else(|| message.src_pk.clone());
+
+    Ok(MyceliumFederationMessage {
+        topic,
+        room_id: payload.get("room_id").and_then(|v| v.as_str()).map(str::to_string),
+        sender,
+        origin_server_ts: payload.get("origin_server_ts")
+            .or_else(|| payload.get("timestamp"))
+            .and_then(|v| v.as_u64())
+            .unwrap_or_default(),
+        payload,
+        destination: message.dst_pk.clone(),
+        message_id: Some(message.id.clone()),
+        source_pubkey: Some(message.src_pk.clone()),
+        envelope_id,
+        envelope_flags,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envelope::{EnvelopeKind, FLAG_ACCEPTS_ZSTD};
+    use base64::Engine;
+
+    #[test]
+    fn test_decode_inbound_message() {
+        let payload = serde_json::json!({
+            "method": "PUT",
+            "path": "/_matrix/federation/v1/send/txn1",
+            "headers": {"Authorization": r#"X-Matrix origin="remote.example.com",key="ed25519:1",sig="abc""#},
+            "timestamp": 1234
+        });
+        let message = MyceliumInboundMessage {
+            id: "0123456789abcdef".to_string(),
+            src_ip: "5c4:c176:bf44:b2ab::1".to_string(),
+            src_pk: "aa".repeat(32),
+            dst_ip: "4ab:a476:c94d:e36a::1".to_string(),
+            dst_pk: "bb".repeat(32),
+            topic: Some(encode_topic("matrix.federation.put")),
+            payload: Envelope::new(EnvelopeKind::Request, payload.clone()).to_base64().unwrap(),
+        };
+
+        let decoded = decode_inbound_message(message.clone()).unwrap();
+        assert_eq!(decoded.topic, "matrix.federation.put");
+        assert_eq!(decoded.sender, "remote.example.com");
+        assert_eq!(decoded.origin_server_ts, 1234);
+        assert_eq!(decoded.destination, "bb".repeat(32));
+        assert_eq!(decoded.payload, payload);
+        assert_eq!(decoded.message_id.as_deref(), Some("0123456789abcdef"));
+        assert_eq!(decoded.source_pubkey, Some("aa".repeat(32)));
+        assert!(decoded.envelope_id.is_some());
+        assert!(decoded.envelope_flags & FLAG_ACCEPTS_ZSTD != 0);
+
+        // Bare JSON from peers without envelope support still decodes
+        let legacy = MyceliumInboundMessage {
+            payload: base64::engine::general_purpose::STANDARD.encode(payload.to_string()),
+            ..message.clone()
+        };
+        assert_eq!(decode_inbound_message(legacy).unwrap().payload, payload);
+
+        let garbled = MyceliumInboundMessage { payload: "not base64!".to_string(), ..message };
+        assert!(decode_inbound_message(garbled).is_err());
+    }
+}
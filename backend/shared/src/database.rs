@@ -1,6 +1,9 @@
+use sqlx::postgres::PgListener;
 use sqlx::PgPool;
+use std::collections::{HashMap, HashSet};
 use crate::error::{Result, BridgeError};
-use crate::types::{FederationRoute, RoomState, MatrixEvent};
+use crate::policy::PolicyRule;
+use crate::types::{FederationRequest, FederationRoute, MatrixEvent, MyceliumEndpoint, OutboxEntry, RoomState};
 
 pub async fn create_pool(database_url: &str) -> Result<PgPool> {
     PgPool::connect(database_url).await
@@ -18,6 +21,82 @@ pub async fn run_migrations(pool: &PgPool) -> Result<()> {
         })
 }
 
+/// Notification channel fed by the `federation_routes` change trigger.
+pub const FEDERATION_ROUTES_CHANNEL: &str = "federation_routes";
+
+/// Notification channel fed by the `transport_policy_rules` change trigger.
+pub const TRANSPORT_POLICIES_CHANNEL: &str = "transport_policies";
+
+/// A `federation_routes` row; its endpoints live in `federation_route_endpoints`.
+struct FederationRouteRow {
+    destination_server: String,
+    mycelium_key: String,
+    last_successful: i64,
+    latency_ms: i64,
+    consecutive_failures: i32,
+    healthy: bool,
+    matrix_latency_ms: Option<i64>,
+    last_probed: Option<i64>,
+    discovered: bool,
+}
+
+impl FederationRouteRow {
+    fn into_route(self, endpoints: Vec<MyceliumEndpoint>) -> FederationRoute {
+        FederationRoute {
+            destination_server: self.destination_server,
+            mycelium_key: self.mycelium_key,
+            last_successful: self.last_successful,
+            latency_ms: self.latency_ms,
+            consecutive_failures: self.consecutive_failures,
+            healthy: self.healthy,
+            matrix_latency_ms: self.matrix_latency_ms,
+            last_probed: self.last_probed,
+            endpoints,
+            discovered: self.discovered,
+        }
+    }
+}
+
+/// A `matrix_events` row; prev and auth events are kept as received, in order.
+struct EventRow {
+    event_id: String,
+    event_type: String,
+    room_id: String,
+    sender: String,
+    origin_server_ts: i64,
+    content: Option<serde_json::Value>,
+    state_key: Option<String>,
+    depth: i64,
+    prev_events: serde_json::Value,
+    auth_events: serde_json::Value,
+    hashes: Option<serde_json::Value>,
+    signatures: Option<serde_json::Value>,
+    unsigned: Option<serde_json::Value>,
+    redacts: Option<String>,
+}
+
+impl EventRow {
+    fn into_event(self) -> MatrixEvent {
+        let event_ids = |ids: serde_json::Value| serde_json::from_value(ids).unwrap_or_default();
+        MatrixEvent {
+            event_id: self.event_id,
+            event_type: self.event_type,
+            room_id: self.room_id,
+            sender: self.sender,
+            origin_server_ts: self.origin_server_ts as u64,
+            content: self.content.unwrap_or_else(|| serde_json::json!({})),
+            state_key: self.state_key,
+            prev_events: event_ids(self.prev_events),
+            auth_events: event_ids(self.auth_events),
+            depth: self.depth as u64,
+            hashes: self.hashes,
+            signatures: self.signatures,
+            unsigned: self.unsigned,
+            redacts: self.redacts,
+        }
+    }
+}
+
 pub struct Database {
     pool: PgPool,
 }
@@ -27,36 +106,84 @@ impl Database {
         Self { pool }
     }
 
+    /// Store a route together with its full set of endpoints.
     pub async fn store_federation_route(&self, route: &FederationRoute) -> Result<()> {
+        let db_error = |e: sqlx::Error| BridgeError::Database {
+            message: format!("Failed to store federation route: {}", e)
+        };
+        let mut tx = self.pool.begin().await.map_err(db_error)?;
+
         sqlx::query!(
             r#"
             INSERT INTO federation_routes
-            (destination_server, mycelium_key, last_successful, latency_ms)
-            VALUES ($1, $2, $3, $4)
+            (destination_server, mycelium_key, last_successful, latency_ms,
+             consecutive_failures, healthy, matrix_latency_ms, last_probed, discovered)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
             ON CONFLICT (destination_server) DO UPDATE SET
                 mycelium_key = EXCLUDED.mycelium_key,
                 last_successful = EXCLUDED.last_successful,
-                latency_ms = EXCLUDED.latency_ms
+                latency_ms = EXCLUDED.latency_ms,
+                consecutive_failures = EXCLUDED.consecutive_failures,
+                healthy = EXCLUDED.healthy,
+                matrix_latency_ms = EXCLUDED.matrix_latency_ms,
+                last_probed = EXCLUDED.last_probed,
+                discovered = EXCLUDED.discovered
             "#,
             route.destination_server,
             route.mycelium_key,
             route.last_successful as i64,
-            route.latency_ms as i64
+            route.latency_ms as i64,
+            route.consecutive_failures,
+            route.healthy,
+            route.matrix_latency_ms,
+            route.last_probed,
+            route.discovered
         )
-        .execute(&self.pool)
+        .execute(&mut *tx)
         .await
-        .map_err(|e| BridgeError::Database {
-            message: format!("Failed to store federation route: {}", e)
-        })?;
+        .map_err(db_error)?;
 
-        Ok(())
+        sqlx::query!(
+            r#"DELETE FROM federation_route_endpoints WHERE destination_server = $1"#,
+            route.destination_server
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(db_error)?;
+
+        // Routes built before endpoints existed only know their primary key
+        let primary = [MyceliumEndpoint::new(route.mycelium_key.clone())];
+        let endpoints = if route.endpoints.is_empty() { &primary[..] } else { &route.endpoints[..] };
+        for endpoint in endpoints {
+            sqlx::query!(
+                r#"
+                INSERT INTO federation_route_endpoints (destination_server, mycelium_key, priority, weight, expires_at)
+                VALUES ($1, $2, $3, $4, $5)
+                "#,
+                route.destination_server,
+                endpoint.mycelium_key,
+                endpoint.priority,
+                endpoint.weight,
+                endpoint.expires_at
+            )
+            .execute(&mut *tx)
+            .await
+            .map_err(db_error)?;
+        }
+
+        tx.commit().await.map_err(db_error)
     }
 
     pub async fn get_federation_route(&self, server: &str) -> Result<Option<FederationRoute>> {
-        let route = sqlx::query_as!(
-            FederationRoute,
+        let db_error = |e: sqlx::Error| BridgeError::Database {
+            message: format!("Failed to get federation route: {}", e)
+        };
+
+        let row = sqlx::query_as!(
+            FederationRouteRow,
             r#"
-            SELECT destination_server, mycelium_key, last_successful, latency_ms
+            SELECT destination_server, mycelium_key, last_successful, latency_ms,
+                   consecutive_failures, healthy, matrix_latency_ms, last_probed, discovered
             FROM federation_routes
             WHERE destination_server = $1
             "#,
@@ -64,29 +191,195 @@ impl Database {
         )
         .fetch_optional(&self.pool)
         .await
-        .map_err(|e| BridgeError::Database {
-            message: format!("Failed to get federation route: {}", e)
-        })?;
+        .map_err(db_error)?;
+
+        let Some(row) = row else {
+            return Ok(None);
+        };
+
+        let endpoints = sqlx::query_as!(
+            MyceliumEndpoint,
+            r#"
+            SELECT mycelium_key, priority, weight, expires_at
+            FROM federation_route_endpoints
+            WHERE destination_server = $1
+            ORDER BY priority, weight DESC, mycelium_key
+            "#,
+            server
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(db_error)?;
 
-        Ok(route)
+        Ok(Some(row.into_route(endpoints)))
     }
 
     pub async fn get_all_federation_routes(&self) -> Result<Vec<FederationRoute>> {
-        let routes = sqlx::query_as!(
-            FederationRoute,
+        let db_error = |e: sqlx::Error| BridgeError::Database {
+            message: format!("Failed to get all federation routes: {}", e)
+        };
+
+        let rows = sqlx::query_as!(
+            FederationRouteRow,
             r#"
-            SELECT destination_server, mycelium_key, last_successful, latency_ms
+            SELECT destination_server, mycelium_key, last_successful, latency_ms,
+                   consecutive_failures, healthy, matrix_latency_ms, last_probed, discovered
             FROM federation_routes
             ORDER BY destination_server
             "#,
         )
         .fetch_all(&self.pool)
         .await
+        .map_err(db_error)?;
+
+        let endpoint_rows = sqlx::query!(
+            r#"
+            SELECT destination_server, mycelium_key, priority, weight, expires_at
+            FROM federation_route_endpoints
+            ORDER BY destination_server, priority, weight DESC, mycelium_key
+            "#,
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(db_error)?;
+
+        let mut endpoints: HashMap<String, Vec<MyceliumEndpoint>> = HashMap::new();
+        for row in endpoint_rows {
+            endpoints.entry(row.destination_server).or_default().push(MyceliumEndpoint {
+                mycelium_key: row.mycelium_key,
+                priority: row.priority,
+                weight: row.weight,
+                expires_at: row.expires_at,
+            });
+        }
+
+        Ok(rows.into_iter()
+            .map(|row| {
+                let route_endpoints = endpoints.remove(&row.destination_server).unwrap_or_default();
+                row.into_route(route_endpoints)
+            })
+            .collect())
+    }
+
+    /// Record a probe's outcome without touching the route's key, which another
+    /// instance may have changed meanwhile. Returns whether the route still exists.
+    pub async fn update_federation_route_health(&self, route: &FederationRoute) -> Result<bool> {
+        let result = sqlx::query!(
+            r#"
+            UPDATE federation_routes SET
+                last_successful = $2,
+                latency_ms = $3,
+                consecutive_failures = $4,
+                healthy = $5,
+                matrix_latency_ms = $6,
+                last_probed = $7
+            WHERE destination_server = $1 AND mycelium_key = $8
+            "#,
+            route.destination_server,
+            route.last_successful,
+            route.latency_ms,
+            route.consecutive_failures,
+            route.healthy,
+            route.matrix_latency_ms,
+            route.last_probed,
+            route.mycelium_key
+        )
+        .execute(&self.pool)
+        .await
         .map_err(|e| BridgeError::Database {
-            message: format!("Failed to get all federation routes: {}", e)
+            message: format!("Failed to update federation route health: {}", e)
         })?;
 
-        Ok(routes)
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Returns whether a route was deleted.
+    pub async fn delete_federation_route(&self, server: &str) -> Result<bool> {
+        let result = sqlx::query!(
+            r#"DELETE FROM federation_routes WHERE destination_server = $1"#,
+            server
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| BridgeError::Database {
+            message: format!("Failed to delete federation route: {}", e)
+        })?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Transport policy rules set through the API. Rows with a policy this
+    /// version doesn't know are skipped.
+    pub async fn get_transport_policies(&self) -> Result<Vec<PolicyRule>> {
+        let rows = sqlx::query!(
+            r#"SELECT pattern, policy FROM transport_policy_rules ORDER BY pattern"#
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| BridgeError::Database {
+            message: format!("Failed to get transport policies: {}", e)
+        })?;
+
+        Ok(rows.into_iter()
+            .filter_map(|row| match row.policy.parse() {
+                Ok(policy) => Some(PolicyRule { pattern: row.pattern, policy }),
+                Err(e) => {
+                    tracing::warn!("Ignoring stored transport policy for {}: {}", row.pattern, e);
+                    None
+                }
+            })
+            .collect())
+    }
+
+    pub async fn store_transport_policy(&self, rule: &PolicyRule) -> Result<()> {
+        sqlx::query!(
+            r#"
+            INSERT INTO transport_policy_rules (pattern, policy)
+            VALUES ($1, $2)
+            ON CONFLICT (pattern) DO UPDATE SET policy = EXCLUDED.policy
+            "#,
+            rule.pattern,
+            rule.policy.as_str()
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| BridgeError::Database {
+            message: format!("Failed to store transport policy: {}", e)
+        })?;
+
+        Ok(())
+    }
+
+    /// Returns whether a rule was deleted.
+    pub async fn delete_transport_policy(&self, pattern: &str) -> Result<bool> {
+        let result = sqlx::query!(
+            r#"DELETE FROM transport_policy_rules WHERE pattern = $1"#,
+            pattern
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| BridgeError::Database {
+            message: format!("Failed to delete transport policy: {}", e)
+        })?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Listener notified with the destination server whenever a route changes,
+    /// and with the pattern whenever a transport policy changes, on this or any
+    /// other bridge instance.
+    pub async fn listen_for_route_changes(&self) -> Result<PgListener> {
+        let mut listener = PgListener::connect_with(&self.pool).await
+            .map_err(|e| BridgeError::Database {
+                message: format!("Failed to connect route change listener: {}", e)
+            })?;
+
+        listener.listen_all([FEDERATION_ROUTES_CHANNEL, TRANSPORT_POLICIES_CHANNEL]).await
+            .map_err(|e| BridgeError::Database {
+                message: format!("Failed to listen for route changes: {}", e)
+            })?;
+
+        Ok(listener)
     }
 
     pub async fn store_room_state(&self, room_state: &RoomState) -> Result<()> {
@@ -218,6 +511,7 @@ impl Database {
                 origin_server_ts: row.origin_server_ts as u64,
                 content: row.content.unwrap_or_else(|| serde_json::json!({})),
                 state_key: row.state_key,
+                ..MatrixEvent::default()
             })
             .collect();
 
@@ -230,6 +524,559 @@ impl Database {
 
         Ok(Some(room_state))
     }
+
+    /// Store one event with its DAG edges and move the room's forward
+    /// extremities past it. Returns false if it was already stored.
+    pub async fn store_event(&self, event: &MatrixEvent) -> Result<bool> {
+        let db_error = |e: sqlx::Error| BridgeError::Database {
+            message: format!("Failed to store event: {}", e)
+        };
+        let mut tx = self.pool.begin().await.map_err(db_error)?;
+
+        let inserted = sqlx::query!(
+            r#"
+            INSERT INTO matrix_events
+            (event_id, event_type, room_id, sender, origin_server_ts, content, state_key,
+             depth, prev_events, auth_events, hashes, signatures, unsigned, redacts)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14)
+            ON CONFLICT (event_id) DO NOTHING
+            "#,
+            event.event_id,
+            event.event_type,
+            event.room_id,
+            event.sender,
+            event.origin_server_ts as i64,
+            event.content.clone(),
+            event.state_key,
+            event.depth as i64,
+            serde_json::json!(event.prev_events),
+            serde_json::json!(event.auth_events),
+            event.hashes.clone(),
+            event.signatures.clone(),
+            event.unsigned.clone(),
+            event.redacts
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(db_error)?
+        .rows_affected() > 0;
+
+        if !inserted {
+            return Ok(false);
+        }
+
+        for prev_event_id in &event.prev_events {
+            sqlx::query!(
+                r#"
+                INSERT INTO event_edges (event_id, prev_event_id, room_id)
+                VALUES ($1, $2, $3)
+                ON CONFLICT DO NOTHING
+                "#,
+                event.event_id,
+                prev_event_id,
+                event.room_id
+            )
+            .execute(&mut *tx)
+            .await
+            .map_err(db_error)?;
+        }
+
+        for auth_event_id in &event.auth_events {
+            sqlx::query!(
+                r#"
+                INSERT INTO event_auth_edges (event_id, auth_event_id)
+                VALUES ($1, $2)
+                ON CONFLICT DO NOTHING
+                "#,
+                event.event_id,
+                auth_event_id
+            )
+            .execute(&mut *tx)
+            .await
+            .map_err(db_error)?;
+        }
+
+        // The event's parents stop being extremities; the event becomes one
+        // unless it arrived late and a stored event already builds on it
+        sqlx::query!(
+            r#"
+            DELETE FROM room_forward_extremities
+            WHERE room_id = $1 AND event_id = ANY($2)
+            "#,
+            event.room_id,
+            &event.prev_events
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(db_error)?;
+
+        sqlx::query!(
+            r#"
+            INSERT INTO room_forward_extremities (room_id, event_id)
+            SELECT $1, $2::VARCHAR
+            WHERE NOT EXISTS (SELECT 1 FROM event_edges WHERE prev_event_id = $2::VARCHAR)
+            ON CONFLICT DO NOTHING
+            "#,
+            event.room_id,
+            event.event_id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(db_error)?;
+
+        tx.commit().await.map_err(db_error)?;
+        Ok(true)
+    }
+
+    pub async fn get_event(&self, event_id: &str) -> Result<Option<MatrixEvent>> {
+        let row = sqlx::query_as!(
+            EventRow,
+            r#"
+            SELECT event_id, event_type, room_id, sender, origin_server_ts, content, state_key,
+                   depth, prev_events, auth_events, hashes, signatures, unsigned, redacts
+            FROM matrix_events
+            WHERE event_id = $1
+            "#,
+            event_id
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| BridgeError::Database {
+            message: format!("Failed to get event: {}", e)
+        })?;
+
+        Ok(row.map(EventRow::into_event))
+    }
+
+    /// The content of `room_id`'s create event, which names the room version.
+    /// Should more than one have been stored, the first one stored counts.
+    pub async fn get_room_create_content(&self, room_id: &str) -> Result<Option<serde_json::Value>> {
+        let row = sqlx::query!(
+            r#"
+            SELECT content
+            FROM matrix_events
+            WHERE room_id = $1 AND event_type = 'm.room.create' AND state_key = ''
+            ORDER BY created_at ASC
+            LIMIT 1
+            "#,
+            room_id
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| BridgeError::Database {
+            message: format!("Failed to get create event of {}: {}", room_id, e)
+        })?;
+
+        Ok(row.map(|row| row.content.unwrap_or_else(|| serde_json::json!({}))))
+    }
+
+    /// The room state just before `event`: for each type and state key, the
+    /// deepest state event among its stored ancestors through prev_events.
+    /// Branches that merged before `event` are picked between by depth rather
+    /// than by full state resolution. Events stored before edges were kept have
+    /// no known ancestors, so the state before them is empty.
+    pub async fn get_state_before(&self, event: &MatrixEvent) -> Result<Vec<MatrixEvent>> {
+        let rows = sqlx::query_as!(
+            EventRow,
+            r#"
+            WITH RECURSIVE ancestors(event_id) AS (
+                SELECT prev_event_id FROM event_edges WHERE event_id = $2
+                UNION
+                SELECT edges.prev_event_id
+                FROM event_edges edges
+                JOIN ancestors ON edges.event_id = ancestors.event_id
+            )
+            SELECT DISTINCT ON (event_type, state_key)
+                event_id, event_type, room_id, sender, origin_server_ts, content, state_key,
+                depth, prev_events, auth_events, hashes, signatures, unsigned, redacts
+            FROM matrix_events
+            WHERE room_id = $1 AND state_key IS NOT NULL
+              AND event_id IN (SELECT event_id FROM ancestors)
+            ORDER BY event_type, state_key, depth DESC, event_id DESC
+            "#,
+            event.room_id,
+            event.event_id
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| BridgeError::Database {
+            message: format!("Failed to get room state: {}", e)
+        })?;
+
+        Ok(rows.into_iter().map(EventRow::into_event).collect())
+    }
+
+    /// The stored events of `room_id` among `event_ids`.
+    pub async fn get_room_events(&self, room_id: &str, event_ids: &[String]) -> Result<Vec<MatrixEvent>> {
+        let rows = sqlx::query_as!(
+            EventRow,
+            r#"
+            SELECT event_id, event_type, room_id, sender, origin_server_ts, content, state_key,
+                   depth, prev_events, auth_events, hashes, signatures, unsigned, redacts
+            FROM matrix_events
+            WHERE room_id = $1 AND event_id = ANY($2)
+            "#,
+            room_id,
+            event_ids
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| BridgeError::Database {
+            message: format!("Failed to get events: {}", e)
+        })?;
+
+        Ok(rows.into_iter().map(EventRow::into_event).collect())
+    }
+
+    /// Which of `event_ids` aren't stored.
+    pub async fn get_unknown_events(&self, event_ids: &[String]) -> Result<Vec<String>> {
+        let rows = sqlx::query!(
+            r#"
+            SELECT id AS "id!"
+            FROM UNNEST($1::VARCHAR[]) AS id
+            WHERE NOT EXISTS (SELECT 1 FROM matrix_events WHERE event_id = id)
+            "#,
+            event_ids
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| BridgeError::Database {
+            message: format!("Failed to look up events: {}", e)
+        })?;
+
+        Ok(rows.into_iter().map(|row| row.id).collect())
+    }
+
+    /// Walk the DAG of `room_id` backwards from `start` breadth-first, following
+    /// prev_events, until `limit` events are collected. Events in `stop` and
+    /// below `min_depth` aren't returned or walked past.
+    pub async fn walk_events_backwards(
+        &self,
+        room_id: &str,
+        start: &[String],
+        stop: &HashSet<String>,
+        limit: usize,
+        min_depth: u64,
+    ) -> Result<Vec<MatrixEvent>> {
+        let mut seen: HashSet<String> = stop.clone();
+        let mut frontier: Vec<String> = start.iter().filter(|id| seen.insert(id.to_string())).cloned().collect();
+        let mut events = Vec::new();
+
+        while !frontier.is_empty() && events.len() < limit {
+            let mut layer = self.get_room_events(room_id, &frontier).await?;
+            // Stay breadth-first within a layer too: deepest events first
+            layer.sort_by(|a, b| b.depth.cmp(&a.depth).then_with(|| a.event_id.cmp(&b.event_id)));
+
+            frontier = Vec::new();
+            for event in layer {
+                if events.len() == limit {
+                    break;
+                }
+                if event.depth < min_depth {
+                    continue;
+                }
+                frontier.extend(event.prev_events.iter().filter(|id| seen.insert(id.to_string())).cloned());
+                events.push(event);
+            }
+        }
+
+        Ok(events)
+    }
+
+    /// Every stored event reachable from `event_ids` through their auth events,
+    /// oldest first.
+    pub async fn get_auth_chain(&self, event_ids: &[String]) -> Result<Vec<MatrixEvent>> {
+        let rows = sqlx::query_as!(
+            EventRow,
+            r#"
+            WITH RECURSIVE chain(event_id) AS (
+                SELECT auth_event_id FROM event_auth_edges WHERE event_id = ANY($1)
+                UNION
+                SELECT edges.auth_event_id
+                FROM event_auth_edges edges
+                JOIN chain ON edges.event_id = chain.event_id
+            )
+            SELECT event_id, event_type, room_id, sender, origin_server_ts, content, state_key,
+                   depth, prev_events, auth_events, hashes, signatures, unsigned, redacts
+            FROM matrix_events
+            WHERE event_id IN (SELECT event_id FROM chain)
+            ORDER BY depth, origin_server_ts, event_id
+            "#,
+            event_ids
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| BridgeError::Database {
+            message: format!("Failed to get auth chain: {}", e)
+        })?;
+
+        Ok(rows.into_iter().map(EventRow::into_event).collect())
+    }
+
+    /// Events of `room_id` no stored event builds on yet.
+    pub async fn get_forward_extremities(&self, room_id: &str) -> Result<Vec<String>> {
+        let rows = sqlx::query!(
+            r#"SELECT event_id FROM room_forward_extremities WHERE room_id = $1 ORDER BY event_id"#,
+            room_id
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| BridgeError::Database {
+            message: format!("Failed to get forward extremities: {}", e)
+        })?;
+
+        Ok(rows.into_iter().map(|row| row.event_id).collect())
+    }
+
+    /// Servers with at least one joined member in `room_id`. A member's latest
+    /// `m.room.member` event by DAG depth overrides what `room_members` says
+    /// about them; timestamps are set by the sender and can't be trusted.
+    pub async fn get_room_servers(&self, room_id: &str) -> Result<Vec<String>> {
+        let rows = sqlx::query!(
+            r#"
+            WITH latest AS (
+                SELECT DISTINCT ON (state_key) state_key AS user_id, content->>'membership' AS membership
+                FROM matrix_events
+                WHERE room_id = $1 AND event_type = 'm.room.member' AND state_key IS NOT NULL
+                ORDER BY state_key, depth DESC, event_id DESC
+            ),
+            joined AS (
+                SELECT user_id FROM latest WHERE membership = 'join'
+                UNION
+                SELECT m.user_id FROM room_members m
+                WHERE m.room_id = $1 AND m.membership = 'join'
+                  AND NOT EXISTS (SELECT 1 FROM latest l WHERE l.user_id = m.user_id)
+            )
+            SELECT DISTINCT substr(user_id, strpos(user_id, ':') + 1) AS "server_name!"
+            FROM joined
+            WHERE strpos(user_id, ':') > 0
+            ORDER BY 1
+            "#,
+            room_id
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| BridgeError::Database {
+            message: format!("Failed to get room servers: {}", e)
+        })?;
+
+        Ok(rows.into_iter().map(|row| row.server_name).collect())
+    }
+
+    pub async fn enqueue_outbox_entry(
+        &self,
+        destination: &str,
+        request: &FederationRequest,
+        now: i64,
+    ) -> Result<i64> {
+        let row = sqlx::query!(
+            r#"
+            INSERT INTO federation_outbox
+            (destination, method, path, body, headers, next_attempt_at, updated_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $6)
+            RETURNING id
+            "#,
+            destination,
+            request.method,
+            request.path,
+            request.body.clone(),
+            serde_json::to_value(&request.headers)?,
+            now
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| BridgeError::Database {
+            message: format!("Failed to enqueue outbox entry: {}", e)
+        })?;
+
+        Ok(row.id)
+    }
+
+    /// Claim the oldest pending entry of up to `limit` destinations, if it's
+    /// due. Entries queue behind it even when their own retry time has come,
+    /// and only one entry per destination is in flight at a time, so
+    /// transactions stay ordered.
+    /// Rows are locked with `SKIP LOCKED`, so bridge instances sharing the
+    /// database never claim the same entry.
+    pub async fn claim_due_outbox_entries(&self, now: i64, limit: i64) -> Result<Vec<OutboxEntry>> {
+        let entries = sqlx::query_as!(
+            OutboxEntry,
+            r#"
+            UPDATE federation_outbox
+            SET state = 'sending', attempts = attempts + 1, updated_at = $1
+            WHERE state = 'pending' AND id IN (
+                SELECT id
+                FROM federation_outbox outbox
+                WHERE state = 'pending' AND next_attempt_at <= $1
+                  AND id = (
+                      SELECT MIN(id) FROM federation_outbox head
+                      WHERE head.destination = outbox.destination AND head.state = 'pending'
+                  )
+                  AND NOT EXISTS (
+                      SELECT 1 FROM federation_outbox sending
+                      WHERE sending.destination = outbox.destination AND sending.state = 'sending'
+                  )
+                ORDER BY id
+                LIMIT $2
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING id, destination, method, path, body, headers, state, attempts,
+                      last_error, next_attempt_at, updated_at
+            "#,
+            now,
+            limit
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| BridgeError::Database {
+            message: format!("Failed to claim outbox entries: {}", e)
+        })?;
+
+        Ok(entries)
+    }
+
+    /// Return entries stuck in `sending` since before `stale_before` (e.g. after a crash) to the queue.
+    pub async fn release_stale_outbox_entries(&self, stale_before: i64) -> Result<u64> {
+        let result = sqlx::query!(
+            r#"
+            UPDATE federation_outbox SET state = 'pending'
+            WHERE state = 'sending' AND updated_at < $1
+            "#,
+            stale_before
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| BridgeError::Database {
+            message: format!("Failed to release stale outbox entries: {}", e)
+        })?;
+
+        Ok(result.rows_affected())
+    }
+
+    pub async fn complete_outbox_entry(&self, id: i64) -> Result<()> {
+        sqlx::query!(r#"DELETE FROM federation_outbox WHERE id = $1"#, id)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| BridgeError::Database {
+                message: format!("Failed to complete outbox entry: {}", e)
+            })?;
+
+        Ok(())
+    }
+
+    /// Record a failed attempt. A `next_attempt_at` of `None` gives up on the entry.
+    pub async fn fail_outbox_entry(
+        &self,
+        id: i64,
+        error: &str,
+        next_attempt_at: Option<i64>,
+        now: i64,
+    ) -> Result<()> {
+        sqlx::query!(
+            r#"
+            UPDATE federation_outbox
+            SET state = CASE WHEN $3::BIGINT IS NULL THEN 'failed' ELSE 'pending' END,
+                last_error = $2,
+                next_attempt_at = COALESCE($3, next_attempt_at),
+                updated_at = $4
+            WHERE id = $1
+            "#,
+            id,
+            error,
+            next_attempt_at,
+            now
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| BridgeError::Database {
+            message: format!("Failed to update outbox entry: {}", e)
+        })?;
+
+        Ok(())
+    }
+
+    /// Push back every pending entry for a destination that's backing off.
+    pub async fn defer_outbox_destination(&self, destination: &str, until: i64) -> Result<()> {
+        sqlx::query!(
+            r#"
+            UPDATE federation_outbox SET next_attempt_at = GREATEST(next_attempt_at, $2)
+            WHERE destination = $1 AND state = 'pending'
+            "#,
+            destination,
+            until
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| BridgeError::Database {
+            message: format!("Failed to defer outbox destination: {}", e)
+        })?;
+
+        Ok(())
+    }
+
+    pub async fn list_outbox_entries(
+        &self,
+        state: Option<&str>,
+        destination: Option<&str>,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<OutboxEntry>> {
+        let entries = sqlx::query_as!(
+            OutboxEntry,
+            r#"
+            SELECT id, destination, method, path, body, headers, state, attempts,
+                   last_error, next_attempt_at, updated_at
+            FROM federation_outbox
+            WHERE ($1::TEXT IS NULL OR state = $1)
+              AND ($2::TEXT IS NULL OR destination = $2)
+            ORDER BY id
+            LIMIT $3 OFFSET $4
+            "#,
+            state,
+            destination,
+            limit,
+            offset
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| BridgeError::Database {
+            message: format!("Failed to list outbox entries: {}", e)
+        })?;
+
+        Ok(entries)
+    }
+
+    /// Reset an entry to be retried immediately. Returns false if it doesn't exist.
+    pub async fn requeue_outbox_entry(&self, id: i64, now: i64) -> Result<bool> {
+        let result = sqlx::query!(
+            r#"
+            UPDATE federation_outbox
+            SET state = 'pending', attempts = 0, next_attempt_at = $2, updated_at = $2
+            WHERE id = $1 AND state <> 'sending'
+            "#,
+            id,
+            now
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| BridgeError::Database {
+            message: format!("Failed to requeue outbox entry: {}", e)
+        })?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Drop an entry for good. Returns false if it doesn't exist.
+    pub async fn delete_outbox_entry(&self, id: i64) -> Result<bool> {
+        let result = sqlx::query!(r#"DELETE FROM federation_outbox WHERE id = $1"#, id)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| BridgeError::Database {
+                message: format!("Failed to delete outbox entry: {}", e)
+            })?;
+
+        Ok(result.rows_affected() > 0)
+    }
 }
 
 #[cfg(test)]
@@ -252,10 +1099,8 @@ mod tests {
             .as_secs();
 
         let route = FederationRoute {
-            destination_server: "server1.com".to_string(),
-            mycelium_key: "key123".to_string(),
-            last_successful: now as i64,
             latency_ms: 50,
+            ..FederationRoute::new("server1.com".to_string(), "key123".to_string(), now as i64)
         };
 
         assert_eq!(route.destination_server, "server1.com");
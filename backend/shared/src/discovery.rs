@@ -0,0 +1,353 @@
+use futures::future::BoxFuture;
+use hickory_resolver::error::ResolveErrorKind;
+use hickory_resolver::TokioAsyncResolver;
+use std::collections::HashMap;
+use tokio::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+use crate::config::BridgeConfig;
+use crate::error::{BridgeError, Result};
+
+/// Key in `/.well-known/matrix/server` advertising the server's Mycelium node.
+pub const WELL_KNOWN_MYCELIUM_KEY: &str = "m.mycelium";
+
+/// Prefix of the DNS name holding a server's Mycelium TXT record.
+const DNS_TXT_PREFIX: &str = "_mycelium";
+
+/// A Mycelium public key learned for a server, and how long it may be trusted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiscoveredKey {
+    pub mycelium_key: String,
+    pub ttl: Option<Duration>,
+}
+
+/// What route discovery learned about a server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Discovery {
+    /// A resolver advertised this Mycelium public key.
+    Found(String),
+    /// Every resolver answered that the server advertises no key.
+    Absent,
+    /// No resolver found a key and some couldn't answer, so nothing is known.
+    Failed,
+}
+
+/// One way of learning a server's Mycelium public key. `Ok(None)` means the
+/// server doesn't publish one through this resolver, `Err` that it couldn't
+/// be asked.
+pub trait RouteResolver: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn resolve<'a>(&'a self, server_name: &'a str) -> BoxFuture<'a, Result<Option<DiscoveredKey>>>;
+}
+
+/// Reads the `m.mycelium` key of `/.well-known/matrix/server`:
+/// `{"m.server": "...", "m.mycelium": {"public_key": "<hex>"}}`.
+pub struct WellKnownResolver {
+    client: reqwest::Client,
+    base_url: Option<String>,
+}
+
+impl WellKnownResolver {
+    pub fn new(client: reqwest::Client) -> Self {
+        Self { client, base_url: None }
+    }
+
+    /// Query `base_url` instead of `https://{server_name}`, e.g. a local stand-in.
+    pub fn with_base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.base_url = Some(base_url.into());
+        self
+    }
+}
+
+impl RouteResolver for WellKnownResolver {
+    fn name(&self) -> &'static str {
+        "well_known"
+    }
+
+    fn resolve<'a>(&'a self, server_name: &'a str) -> BoxFuture<'a, Result<Option<DiscoveredKey>>> {
+        Box::pin(async move {
+            let base_url = self.base_url.clone().unwrap_or_else(|| format!("https://{}", server_name));
+            let response = self.client
+                .get(format!("{}/.well-known/matrix/server", base_url))
+                .send()
+                .await?;
+
+            if response.status() == reqwest::StatusCode::NOT_FOUND {
+                return Ok(None);
+            }
+            let response = response.error_for_status()?;
+
+            let ttl = response.headers()
+                .get(reqwest::header::CACHE_CONTROL)
+                .and_then(|v| v.to_str().ok())
+                .and_then(max_age);
+            let well_known: serde_json::Value = response.json().await?;
+
+            let mycelium = well_known.get(WELL_KNOWN_MYCELIUM_KEY);
+            let key = mycelium
+                .and_then(|v| v.get("public_key"))
+                .or(mycelium.filter(|v| v.is_string()))
+                .and_then(|v| v.as_str())
+                .map(str::trim)
+                .filter(|key| !key.is_empty());
+
+            Ok(key.map(|key| DiscoveredKey { mycelium_key: key.to_string(), ttl }))
+        })
+    }
+}
+
+/// Reads a `_mycelium.{server_name}` TXT record of the form `v=mycelium1 pk=<hex>`.
+pub struct DnsTxtResolver {
+    resolver: TokioAsyncResolver,
+}
+
+impl DnsTxtResolver {
+    pub fn new(resolver: TokioAsyncResolver) -> Self {
+        Self { resolver }
+    }
+
+    pub fn from_system_conf() -> Result<Self> {
+        let resolver = TokioAsyncResolver::tokio_from_system_conf().map_err(|e| BridgeError::Config {
+            message: format!("Failed to read system DNS configuration: {}", e)
+        })?;
+        Ok(Self::new(resolver))
+    }
+}
+
+impl RouteResolver for DnsTxtResolver {
+    fn name(&self) -> &'static str {
+        "dns_txt"
+    }
+
+    fn resolve<'a>(&'a self, server_name: &'a str) -> BoxFuture<'a, Result<Option<DiscoveredKey>>> {
+        Box::pin(async move {
+            // Ports aren't part of the DNS name
+            let host = server_name.rsplit_once(':')
+                .filter(|(_, port)| port.chars().all(|c| c.is_ascii_digit()))
+                .map_or(server_name, |(host, _)| host);
+
+            let lookup = match self.resolver.txt_lookup(format!("{}.{}.", DNS_TXT_PREFIX, host)).await {
+                Ok(lookup) => lookup,
+                Err(e) if matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }) => return Ok(None),
+                Err(e) => return Err(BridgeError::Unreachable {
+                    message: format!("TXT lookup for {} failed: {}", host, e)
+                }),
+            };
+
+            let ttl = lookup.valid_until().checked_duration_since(std::time::Instant::now());
+            let key = lookup.iter()
+                .map(|txt| txt.txt_data().iter().map(|part| String::from_utf8_lossy(part)).collect::<String>())
+                .find_map(|record| parse_txt_record(&record));
+
+            Ok(key.map(|mycelium_key| DiscoveredKey { mycelium_key, ttl }))
+        })
+    }
+}
+
+fn parse_txt_record(record: &str) -> Option<String> {
+    let mut fields = record.split_whitespace();
+    if fields.next() != Some("v=mycelium1") {
+        return None;
+    }
+    fields.find_map(|field| field.strip_prefix("pk=")).map(str::to_string)
+}
+
+fn max_age(cache_control: &str) -> Option<Duration> {
+    cache_control.split(',')
+        .find_map(|directive| directive.trim().strip_prefix("max-age="))
+        .and_then(|secs| secs.parse().ok())
+        .map(Duration::from_secs)
+}
+
+#[derive(Debug, Clone)]
+struct CachedDiscovery {
+    discovery: Discovery,
+    expires_at: Instant,
+}
+
+/// Learns Mycelium routes for servers nobody configured, asking each resolver
+/// in turn. Answers are cached for their TTL, misses and failures for `negative_ttl`.
+pub struct RouteDiscovery {
+    resolvers: Vec<Box<dyn RouteResolver>>,
+    default_ttl: Duration,
+    negative_ttl: Duration,
+    cache: Mutex<HashMap<String, CachedDiscovery>>,
+}
+
+impl RouteDiscovery {
+    pub fn new(resolvers: Vec<Box<dyn RouteResolver>>, default_ttl: Duration, negative_ttl: Duration) -> Self {
+        Self {
+            resolvers,
+            default_ttl,
+            negative_ttl,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The resolvers named in `route_discovery_methods`.
+    pub fn from_config(config: &BridgeConfig, client: reqwest::Client) -> Self {
+        let mut resolvers: Vec<Box<dyn RouteResolver>> = Vec::new();
+        for method in &config.route_discovery_methods {
+            match method.as_str() {
+                "well_known" => resolvers.push(Box::new(WellKnownResolver::new(client.clone()))),
+                "dns_txt" => match DnsTxtResolver::from_system_conf() {
+                    Ok(resolver) => resolvers.push(Box::new(resolver)),
+                    Err(e) => tracing::warn!("DNS route discovery disabled: {}", e),
+                },
+                other => tracing::warn!("Ignoring unknown route discovery method {}", other),
+            }
+        }
+
+        Self::new(
+            resolvers,
+            Duration::from_secs(config.route_discovery_ttl),
+            Duration::from_secs(config.route_discovery_negative_ttl),
+        )
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.resolvers.is_empty()
+    }
+
+    /// Whether the next `discover` of `server_name` asks the resolvers again,
+    /// because nothing is cached for it or the cached answer expired.
+    pub async fn is_due(&self, server_name: &str) -> bool {
+        self.cache.lock().await
+            .get(server_name)
+            .is_none_or(|cached| cached.expires_at <= Instant::now())
+    }
+
+    /// Drop what was learned about `server_name`, e.g. once it's configured by hand.
+    pub async fn forget(&self, server_name: &str) {
+        self.cache.lock().await.remove(server_name);
+    }
+
+    /// `server_name`'s Mycelium public key if any resolver knows it, otherwise
+    /// whether the resolvers all said it has none or some couldn't be asked.
+    pub async fn discover(&self, server_name: &str) -> Discovery {
+        if let Some(cached) = self.cache.lock().await.get(server_name) {
+            if cached.expires_at > Instant::now() {
+                return cached.discovery.clone();
+            }
+        }
+
+        let mut found = None;
+        let mut failed = false;
+        for resolver in &self.resolvers {
+            match resolver.resolve(server_name).await {
+                Ok(Some(key)) => {
+                    tracing::info!("Discovered Mycelium key for {} via {}", server_name, resolver.name());
+                    found = Some(key);
+                    break;
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    tracing::debug!("Route discovery via {} failed for {}: {}", resolver.name(), server_name, e);
+                    failed = true;
+                }
+            }
+        }
+
+        // Never trust an answer longer than the configured TTL
+        let (discovery, ttl) = match found {
+            Some(key) => (Discovery::Found(key.mycelium_key), key.ttl.unwrap_or(self.default_ttl).min(self.default_ttl)),
+            None if failed => (Discovery::Failed, self.negative_ttl),
+            None => (Discovery::Absent, self.negative_ttl),
+        };
+
+        self.cache.lock().await.insert(server_name.to_string(), CachedDiscovery {
+            discovery: discovery.clone(),
+            expires_at: Instant::now() + ttl,
+        });
+
+        discovery
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    struct StaticResolver {
+        keys: HashMap<&'static str, &'static str>,
+        /// Servers the resolver can't reach.
+        down: Vec<&'static str>,
+        lookups: Arc<AtomicU32>,
+    }
+
+    impl RouteResolver for StaticResolver {
+        fn name(&self) -> &'static str {
+            "static"
+        }
+
+        fn resolve<'a>(&'a self, server_name: &'a str) -> BoxFuture<'a, Result<Option<DiscoveredKey>>> {
+            self.lookups.fetch_add(1, Ordering::Relaxed);
+            if self.down.contains(&server_name) {
+                return Box::pin(async move {
+                    Err(BridgeError::Unreachable { message: format!("{} is down", server_name) })
+                });
+            }
+            let key = self.keys.get(server_name).map(|key| DiscoveredKey { mycelium_key: key.to_string(), ttl: None });
+            Box::pin(async move { Ok(key) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_discovery_caches_hits_and_misses() {
+        let lookups = Arc::new(AtomicU32::new(0));
+        let resolver = StaticResolver {
+            keys: HashMap::from([("remote.example.com", "ab12")]),
+            down: vec!["down.example.com"],
+            lookups: lookups.clone(),
+        };
+        let discovery = RouteDiscovery::new(vec![Box::new(resolver)], Duration::from_secs(3600), Duration::from_millis(50));
+
+        assert!(discovery.is_due("remote.example.com").await);
+        let found = Discovery::Found("ab12".to_string());
+        assert_eq!(discovery.discover("remote.example.com").await, found);
+        assert_eq!(discovery.discover("remote.example.com").await, found);
+        assert!(!discovery.is_due("remote.example.com").await);
+        assert_eq!(discovery.discover("unknown.example.com").await, Discovery::Absent);
+        assert_eq!(discovery.discover("unknown.example.com").await, Discovery::Absent);
+        assert_eq!(lookups.load(Ordering::Relaxed), 2);
+
+        // A resolver that can't be asked is no answer either way
+        assert_eq!(discovery.discover("down.example.com").await, Discovery::Failed);
+        assert_eq!(discovery.discover("down.example.com").await, Discovery::Failed);
+        assert_eq!(lookups.load(Ordering::Relaxed), 3);
+
+        // Misses and failures are retried once the negative TTL passes
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(discovery.is_due("unknown.example.com").await);
+        assert_eq!(discovery.discover("unknown.example.com").await, Discovery::Absent);
+        assert_eq!(discovery.discover("down.example.com").await, Discovery::Failed);
+        assert_eq!(lookups.load(Ordering::Relaxed), 5);
+    }
+
+    #[tokio::test]
+    async fn test_well_known_resolver() {
+        let app = axum::Router::new().route("/.well-known/matrix/server", axum::routing::get(|| async {
+            (
+                [("cache-control", "public, max-age=600")],
+                axum::Json(serde_json::json!({
+                    "m.server": "matrix.example.com:443",
+                    "m.mycelium": {"public_key": "ab12"}
+                })),
+            )
+        }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let resolver = WellKnownResolver::new(reqwest::Client::new()).with_base_url(format!("http://{}", addr));
+        let key = resolver.resolve("example.com").await.unwrap().unwrap();
+        assert_eq!(key.mycelium_key, "ab12");
+        assert_eq!(key.ttl, Some(Duration::from_secs(600)));
+
+        assert_eq!(parse_txt_record("v=mycelium1 pk=ab12"), Some("ab12".to_string()));
+        assert_eq!(parse_txt_record("v=spf1 include:example.com"), None);
+    }
+}
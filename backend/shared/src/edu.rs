@@ -0,0 +1,201 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::{watch, Mutex};
+use tokio::time::{Duration, Instant};
+
+use crate::bridge::MatrixMyceliumBridge;
+use crate::types::Edu;
+
+/// Prefix of the topics EDUs travel on over Mycelium.
+pub const EDU_TOPIC_PREFIX: &str = "matrix.federation.edu.";
+
+/// Topics EDUs are relayed on, one per kind so receivers can poll them separately.
+pub const EDU_TOPICS: &[&str] = &[
+    "matrix.federation.edu.typing",
+    "matrix.federation.edu.receipt",
+    "matrix.federation.edu.presence",
+    "matrix.federation.edu.device_list",
+];
+
+/// The Mycelium topic EDUs of `edu_type` are relayed on. Other EDUs stay in
+/// their transaction and travel with it.
+pub fn edu_topic(edu_type: &str) -> Option<&'static str> {
+    match edu_type {
+        "m.typing" => Some(EDU_TOPICS[0]),
+        "m.receipt" => Some(EDU_TOPICS[1]),
+        "m.presence" => Some(EDU_TOPICS[2]),
+        "m.device_list_update" | "m.signing_key_update" => Some(EDU_TOPICS[3]),
+        _ => None,
+    }
+}
+
+/// Fold `new` into `pending` if it supersedes or extends it, so a burst of
+/// updates goes out as one. Returns false if both have to be sent.
+fn coalesce(pending: &mut Edu, new: &Edu) -> bool {
+    if pending.edu_type != new.edu_type {
+        return false;
+    }
+
+    match new.edu_type.as_str() {
+        // Only the latest typing state of a user in a room matters
+        "m.typing" => {
+            let same = |field: &str| pending.content.get(field) == new.content.get(field);
+            if !(same("room_id") && same("user_id")) {
+                return false;
+            }
+            pending.content = new.content.clone();
+            true
+        }
+        // Receipts are nested by room, type and user; later ones win where they overlap
+        "m.receipt" => {
+            merge_json(&mut pending.content, &new.content);
+            true
+        }
+        // Presence pushes are lists of per-user states
+        "m.presence" => {
+            let Some(updates) = new.content.get("push").and_then(|v| v.as_array()) else {
+                return false;
+            };
+            let Some(push) = pending.content.get_mut("push").and_then(|v| v.as_array_mut()) else {
+                return false;
+            };
+            for update in updates {
+                match push.iter_mut().find(|existing| existing.get("user_id") == update.get("user_id")) {
+                    Some(existing) => *existing = update.clone(),
+                    None => push.push(update.clone()),
+                }
+            }
+            true
+        }
+        "m.signing_key_update" if pending.content.get("user_id") == new.content.get("user_id") => {
+            pending.content = new.content.clone();
+            true
+        }
+        // Device list updates chain through prev_id and must all arrive
+        _ => false,
+    }
+}
+
+fn merge_json(target: &mut serde_json::Value, source: &serde_json::Value) {
+    match (target, source) {
+        (serde_json::Value::Object(target), serde_json::Value::Object(source)) => {
+            for (key, value) in source {
+                match target.get_mut(key) {
+                    Some(existing) => merge_json(existing, value),
+                    None => {
+                        target.insert(key.clone(), value.clone());
+                    }
+                }
+            }
+        }
+        (target, source) => *target = source.clone(),
+    }
+}
+
+struct PendingEdu {
+    edu: Edu,
+    queued_at: Instant,
+}
+
+/// EDUs waiting to be relayed, per destination server. EDUs are ephemeral:
+/// ones that wait longer than their TTL are dropped, and nothing is retried.
+#[derive(Default)]
+pub struct EduQueue {
+    pending: Mutex<HashMap<String, Vec<PendingEdu>>>,
+}
+
+impl EduQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub async fn push(&self, destination: &str, edu: Edu) {
+        let mut pending = self.pending.lock().await;
+        let queue = pending.entry(destination.to_string()).or_default();
+        if queue.iter_mut().any(|queued| coalesce(&mut queued.edu, &edu)) {
+            return;
+        }
+        queue.push(PendingEdu { edu, queued_at: Instant::now() });
+    }
+
+    /// Take every queued EDU that's younger than `ttl`, by destination.
+    pub async fn drain(&self, ttl: Duration) -> HashMap<String, Vec<Edu>> {
+        let pending = std::mem::take(&mut *self.pending.lock().await);
+        pending.into_iter()
+            .map(|(destination, queue)| {
+                let (fresh, stale): (Vec<_>, Vec<_>) = queue.into_iter()
+                    .partition(|queued| queued.queued_at.elapsed() < ttl);
+                if !stale.is_empty() {
+                    tracing::debug!("Dropping {} expired EDUs for {}", stale.len(), destination);
+                }
+                (destination, fresh.into_iter().map(|queued| queued.edu).collect::<Vec<_>>())
+            })
+            .filter(|(_, edus)| !edus.is_empty())
+            .collect()
+    }
+}
+
+/// Relay queued EDUs every `edu_flush_interval` milliseconds until `shutdown`
+/// flips to true. Updates arriving within one interval are coalesced.
+pub async fn run_edu_relay(bridge: Arc<MatrixMyceliumBridge>, mut shutdown: watch::Receiver<bool>) {
+    let interval = bridge.config.edu_flush_interval;
+    if interval == 0 || !bridge.config.mycelium_enabled {
+        tracing::info!("EDU relay disabled");
+        return;
+    }
+
+    tracing::info!("EDU relay started, flushing every {}ms", interval);
+
+    while !*shutdown.borrow() {
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_millis(interval)) => {},
+            _ = shutdown.changed() => {},
+        }
+
+        let sent = bridge.flush_edus().await;
+        if sent > 0 {
+            tracing::debug!("Relayed {} EDU batches", sent);
+        }
+    }
+
+    tracing::info!("EDU relay stopped");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn edu(edu_type: &str, content: serde_json::Value) -> Edu {
+        Edu { edu_type: edu_type.to_string(), content }
+    }
+
+    #[tokio::test]
+    async fn test_edu_coalescing() {
+        let queue = EduQueue::new();
+        let typing = |typing| edu("m.typing", json!({"room_id": "!r:a", "user_id": "@u:a", "typing": typing}));
+        queue.push("b.example.com", typing(true)).await;
+        queue.push("b.example.com", typing(false)).await;
+        queue.push("b.example.com", edu("m.receipt", json!({"!r:a": {"m.read": {"@u:a": {"event_ids": ["$1"]}}}}))).await;
+        queue.push("b.example.com", edu("m.receipt", json!({"!r:a": {"m.read": {"@v:a": {"event_ids": ["$2"]}}}}))).await;
+        queue.push("b.example.com", edu("m.presence", json!({"push": [{"user_id": "@u:a", "presence": "online"}]}))).await;
+        queue.push("b.example.com", edu("m.presence", json!({"push": [{"user_id": "@u:a", "presence": "offline"}]}))).await;
+        queue.push("b.example.com", edu("m.device_list_update", json!({"user_id": "@u:a", "stream_id": 1}))).await;
+        queue.push("b.example.com", edu("m.device_list_update", json!({"user_id": "@u:a", "stream_id": 2}))).await;
+
+        let drained = queue.drain(Duration::from_secs(30)).await;
+        let edus = &drained["b.example.com"];
+        assert_eq!(edus.len(), 5);
+        assert_eq!(edus[0].content["typing"], false);
+        assert_eq!(edus[1].content["!r:a"]["m.read"].as_object().unwrap().len(), 2);
+        assert_eq!(edus[2].content["push"], json!([{"user_id": "@u:a", "presence": "offline"}]));
+        assert!(queue.drain(Duration::from_secs(30)).await.is_empty());
+
+        // Expired EDUs are dropped rather than sent late
+        queue.push("b.example.com", typing(true)).await;
+        assert!(queue.drain(Duration::ZERO).await.is_empty());
+
+        assert_eq!(edu_topic("m.signing_key_update"), Some("matrix.federation.edu.device_list"));
+        assert_eq!(edu_topic("m.direct_to_device"), None);
+    }
+}
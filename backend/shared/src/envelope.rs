@@ -0,0 +1,357 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+use uuid::Uuid;
+
+use crate::error::{BridgeError, Result};
+
+/// Leading bytes of every enveloped Mycelium payload.
+pub const ENVELOPE_MAGIC: &[u8; 2] = b"MX";
+
+/// Envelope protocol version written by this bridge.
+pub const ENVELOPE_VERSION: u8 = 1;
+
+/// magic(2) version(1) kind(1) flags(2) message_id(16) correlation_id(16) body_len(4)
+pub const HEADER_LEN: usize = 2 + 1 + 1 + 2 + 16 + 16 + 4;
+
+/// Low flag bits naming the algorithm the body is compressed with.
+const ENCODING_MASK: u16 = 0x000f;
+
+/// Flag bits a sender sets to advertise which algorithms it can decompress.
+pub const FLAG_ACCEPTS_DEFLATE: u16 = 0x0100;
+pub const FLAG_ACCEPTS_ZSTD: u16 = 0x0200;
+
+/// Decompressed bodies larger than this are rejected rather than inflated.
+const MAX_DECOMPRESSED_SIZE: usize = 16 * 1024 * 1024;
+
+/// Body compression, negotiated per peer through the envelope flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    None,
+    Deflate,
+    Zstd,
+}
+
+impl Compression {
+    fn from_flags(flags: u16) -> Result<Self> {
+        match flags & ENCODING_MASK {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Deflate),
+            2 => Ok(Compression::Zstd),
+            other => Err(BridgeError::Serde {
+                message: format!("Unknown envelope body encoding {}", other)
+            }),
+        }
+    }
+
+    fn flag_bits(self) -> u16 {
+        match self {
+            Compression::None => 0,
+            Compression::Deflate => 1,
+            Compression::Zstd => 2,
+        }
+    }
+
+    fn accept_flag(self) -> u16 {
+        match self {
+            Compression::None => 0,
+            Compression::Deflate => FLAG_ACCEPTS_DEFLATE,
+            Compression::Zstd => FLAG_ACCEPTS_ZSTD,
+        }
+    }
+
+    fn compress(self, data: Vec<u8>) -> Result<Vec<u8>> {
+        let compression_failed = |e: std::io::Error| BridgeError::Serde {
+            message: format!("Failed to compress envelope body: {}", e)
+        };
+
+        match self {
+            Compression::None => Ok(data),
+            Compression::Deflate => {
+                let mut encoder = flate2::read::DeflateEncoder::new(data.as_slice(), flate2::Compression::default());
+                let mut compressed = Vec::new();
+                encoder.read_to_end(&mut compressed).map_err(compression_failed)?;
+                Ok(compressed)
+            }
+            Compression::Zstd => zstd::bulk::compress(&data, 0).map_err(compression_failed),
+        }
+    }
+
+    fn decompress(self, data: &[u8]) -> Result<Vec<u8>> {
+        let decompression_failed = |e: std::io::Error| BridgeError::Serde {
+            message: format!("Failed to decompress envelope body: {}", e)
+        };
+
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Deflate => {
+                let mut decompressed = Vec::new();
+                flate2::read::DeflateDecoder::new(data)
+                    .take(MAX_DECOMPRESSED_SIZE as u64 + 1)
+                    .read_to_end(&mut decompressed)
+                    .map_err(decompression_failed)?;
+                if decompressed.len() > MAX_DECOMPRESSED_SIZE {
+                    return Err(BridgeError::Serde {
+                        message: "Decompressed envelope body is too large".to_string()
+                    });
+                }
+                Ok(decompressed)
+            }
+            Compression::Zstd => zstd::bulk::decompress(data, MAX_DECOMPRESSED_SIZE).map_err(decompression_failed),
+        }
+    }
+}
+
+impl std::str::FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(Compression::None),
+            "deflate" => Ok(Compression::Deflate),
+            "zstd" => Ok(Compression::Zstd),
+            other => Err(format!("Unknown compression algorithm {}", other)),
+        }
+    }
+}
+
+/// What an envelope carries, so the receiver doesn't have to guess from the body.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum EnvelopeKind {
+    /// A federation request expecting a reply.
+    Request = 1,
+    /// The reply to a `Request`, correlated by its message id.
+    Response = 2,
+    /// A Matrix event pushed without expecting a reply.
+    Event = 3,
+    /// One piece of an encoded envelope too large for a single Mycelium message.
+    Fragment = 4,
+    /// A signed route announcement gossiped between bridges.
+    Announcement = 5,
+}
+
+impl TryFrom<u8> for EnvelopeKind {
+    type Error = BridgeError;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            1 => Ok(EnvelopeKind::Request),
+            2 => Ok(EnvelopeKind::Response),
+            3 => Ok(EnvelopeKind::Event),
+            4 => Ok(EnvelopeKind::Fragment),
+            5 => Ok(EnvelopeKind::Announcement),
+            _ => Err(BridgeError::Serde {
+                message: format!("Unknown envelope kind {}", value)
+            }),
+        }
+    }
+}
+
+/// Versioned binary frame for everything the bridge sends over Mycelium: a fixed
+/// header followed by a length-prefixed JSON body.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Envelope {
+    pub version: u8,
+    pub kind: EnvelopeKind,
+    /// Body encoding and the encodings the sender accepts; unknown flags are
+    /// preserved, not rejected.
+    pub flags: u16,
+    pub message_id: Uuid,
+    /// For responses, the `message_id` of the request being answered.
+    pub correlation_id: Option<Uuid>,
+    pub body: serde_json::Value,
+}
+
+impl Envelope {
+    pub fn new(kind: EnvelopeKind, body: serde_json::Value) -> Self {
+        Self {
+            version: ENVELOPE_VERSION,
+            kind,
+            flags: FLAG_ACCEPTS_DEFLATE | FLAG_ACCEPTS_ZSTD,
+            message_id: Uuid::new_v4(),
+            correlation_id: None,
+            body,
+        }
+    }
+
+    /// A response envelope answering the request `correlation_id`.
+    pub fn response(correlation_id: Option<Uuid>, body: serde_json::Value) -> Self {
+        Self {
+            correlation_id,
+            ..Self::new(EnvelopeKind::Response, body)
+        }
+    }
+
+    /// Compress the body with `compression` once it's larger than `threshold`
+    /// bytes. Only pick an algorithm the receiver has advertised.
+    pub fn compressed(mut self, compression: Compression, threshold: usize) -> Result<Self> {
+        self.flags &= !ENCODING_MASK;
+        if compression != Compression::None && serde_json::to_vec(&self.body)?.len() > threshold {
+            self.flags |= compression.flag_bits();
+        }
+        Ok(self)
+    }
+
+    /// Algorithm the body is compressed with on the wire.
+    pub fn compression(&self) -> Result<Compression> {
+        Compression::from_flags(self.flags)
+    }
+
+    /// Whether the sender of this envelope can decompress `compression`.
+    pub fn accepts(&self, compression: Compression) -> bool {
+        flags_accept(self.flags, compression)
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let body = self.compression()?.compress(serde_json::to_vec(&self.body)?)?;
+        let body_len = u32::try_from(body.len()).map_err(|_| BridgeError::InvalidRequest {
+            message: format!("Envelope body of {} bytes is too large", body.len())
+        })?;
+
+        let mut bytes = Vec::with_capacity(HEADER_LEN + body.len());
+        bytes.extend_from_slice(ENVELOPE_MAGIC);
+        bytes.push(self.version);
+        bytes.push(self.kind as u8);
+        bytes.extend_from_slice(&self.flags.to_be_bytes());
+        bytes.extend_from_slice(self.message_id.as_bytes());
+        bytes.extend_from_slice(self.correlation_id.unwrap_or(Uuid::nil()).as_bytes());
+        bytes.extend_from_slice(&body_len.to_be_bytes());
+        bytes.extend_from_slice(&body);
+        Ok(bytes)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        if !is_envelope(bytes) {
+            return Err(BridgeError::Serde {
+                message: "Payload is not an envelope".to_string()
+            });
+        }
+        if bytes.len() < HEADER_LEN {
+            return Err(BridgeError::Serde {
+                message: format!("Envelope header truncated at {} bytes", bytes.len())
+            });
+        }
+
+        let version = bytes[2];
+        if version != ENVELOPE_VERSION {
+            return Err(BridgeError::Serde {
+                message: format!("Unsupported envelope version {}", version)
+            });
+        }
+
+        let kind = EnvelopeKind::try_from(bytes[3])?;
+        let flags = u16::from_be_bytes([bytes[4], bytes[5]]);
+        let message_id = uuid_at(bytes, 6);
+        let correlation_id = Some(uuid_at(bytes, 22)).filter(|id| !id.is_nil());
+        let body_len = u32::from_be_bytes([bytes[38], bytes[39], bytes[40], bytes[41]]) as usize;
+
+        let body = &bytes[HEADER_LEN..];
+        if body.len() != body_len {
+            return Err(BridgeError::Serde {
+                message: format!("Envelope body is {} bytes, header says {}", body.len(), body_len)
+            });
+        }
+        let body = Compression::from_flags(flags)?.decompress(body)?;
+
+        Ok(Self {
+            version,
+            kind,
+            flags,
+            message_id,
+            correlation_id,
+            body: serde_json::from_slice(&body)?,
+        })
+    }
+
+    /// Base64 form expected in the `payload` field of the Mycelium HTTP API.
+    pub fn to_base64(&self) -> Result<String> {
+        Ok(STANDARD.encode(self.encode()?))
+    }
+
+    pub fn from_base64(payload: &str) -> Result<Self> {
+        Self::decode(&decode_base64("payload", payload)?)
+    }
+}
+
+/// Whether envelope `flags` advertise support for `compression`.
+pub fn flags_accept(flags: u16, compression: Compression) -> bool {
+    compression == Compression::None || flags & compression.accept_flag() != 0
+}
+
+/// Whether `bytes` start like an envelope rather than a bare JSON payload.
+pub fn is_envelope(bytes: &[u8]) -> bool {
+    bytes.starts_with(ENVELOPE_MAGIC)
+}
+
+/// Base64 form of a topic for the Mycelium HTTP API.
+pub fn encode_topic(topic: &str) -> String {
+    STANDARD.encode(topic)
+}
+
+pub fn decode_base64(field: &str, value: &str) -> Result<Vec<u8>> {
+    STANDARD.decode(value).map_err(|e| BridgeError::Serde {
+        message: format!("Invalid base64 {}: {}", field, e)
+    })
+}
+
+fn uuid_at(bytes: &[u8], offset: usize) -> Uuid {
+    let mut id = [0u8; 16];
+    id.copy_from_slice(&bytes[offset..offset + 16]);
+    Uuid::from_bytes(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_envelope_round_trip() {
+        let request = Envelope::new(EnvelopeKind::Request, serde_json::json!({
+            "method": "PUT",
+            "path": "/_matrix/federation/v1/send/txn1"
+        }));
+        let response = Envelope::response(Some(request.message_id), serde_json::json!({"status_code": 200}));
+
+        for envelope in [&request, &response] {
+            let decoded = Envelope::from_base64(&envelope.to_base64().unwrap()).unwrap();
+            assert_eq!(&decoded, envelope);
+        }
+        assert_eq!(Envelope::decode(&request.encode().unwrap()).unwrap().correlation_id, None);
+
+        // Bare JSON, truncation and unknown versions are all rejected
+        assert!(Envelope::decode(br#"{"method":"PUT"}"#).is_err());
+        let bytes = request.encode().unwrap();
+        assert!(Envelope::decode(&bytes[..bytes.len() - 1]).is_err());
+        let mut future = bytes.clone();
+        future[2] = ENVELOPE_VERSION + 1;
+        assert!(Envelope::decode(&future).is_err());
+    }
+
+    #[test]
+    fn test_envelope_compression() {
+        let state: Vec<_> = (0..200).map(|i| serde_json::json!({
+            "type": "m.room.member",
+            "state_key": format!("@user{}:example.com", i),
+            "content": {"membership": "join"}
+        })).collect();
+        let plain = Envelope::new(EnvelopeKind::Response, serde_json::json!({"state": state}));
+        let plain_len = plain.encode().unwrap().len();
+
+        for compression in [Compression::Deflate, Compression::Zstd] {
+            assert!(plain.accepts(compression));
+            let envelope = plain.clone().compressed(compression, 1024).unwrap();
+            assert_eq!(envelope.compression().unwrap(), compression);
+
+            let bytes = envelope.encode().unwrap();
+            assert!(bytes.len() < plain_len / 4);
+            assert_eq!(Envelope::decode(&bytes).unwrap().body, plain.body);
+        }
+
+        // Small bodies aren't worth compressing
+        let small = Envelope::new(EnvelopeKind::Request, serde_json::json!({"method": "GET"}));
+        assert_eq!(small.compressed(Compression::Zstd, 1024).unwrap().compression().unwrap(), Compression::None);
+    }
+}
@@ -34,6 +34,24 @@ pub enum BridgeError {
     #[error("Connection timeout")]
     Timeout,
 
+    #[error("Destination unreachable: {message}")]
+    Unreachable { message: String },
+
+    #[error("Invalid response from remote server: {message}")]
+    InvalidResponse { message: String },
+
+    #[error("Partial delivery: {message}")]
+    PartialDelivery { message: String },
+
+    #[error("Unresolvable Mycelium key: {message}")]
+    UnresolvableKey { message: String },
+
+    #[error("Blocked by transport policy: {message}")]
+    Blocked { message: String },
+
+    #[error("Forbidden: {message}")]
+    Forbidden { message: String },
+
     #[error("Resource not found")]
     NotFound,
 
@@ -41,23 +59,37 @@ pub enum BridgeError {
     InvalidRequest { message: String },
 }
 
-impl IntoResponse for BridgeError {
-    fn into_response(self) -> Response {
-        let (status, message) = match self {
-            BridgeError::MatrixApi { .. } => (StatusCode::BAD_GATEWAY, self.to_string()),
-            BridgeError::MyceliumNetwork { .. } => (StatusCode::SERVICE_UNAVAILABLE, self.to_string()),
-            BridgeError::MyceliumApi { .. } => (StatusCode::BAD_GATEWAY, self.to_string()),
-            BridgeError::Database { .. } => (StatusCode::INTERNAL_SERVER_ERROR, "Database error".to_string()),
-            BridgeError::Config { .. } => (StatusCode::INTERNAL_SERVER_ERROR, "Configuration error".to_string()),
-            BridgeError::Serde { .. } => (StatusCode::BAD_REQUEST, "Invalid data format".to_string()),
-            BridgeError::Auth { .. } => (StatusCode::UNAUTHORIZED, self.to_string()),
-            BridgeError::Federation { .. } => (StatusCode::BAD_REQUEST, self.to_string()),
-            BridgeError::Timeout => (StatusCode::REQUEST_TIMEOUT, "Request timeout".to_string()),
-            BridgeError::NotFound => (StatusCode::NOT_FOUND, "Resource not found".to_string()),
-            BridgeError::InvalidRequest { .. } => (StatusCode::BAD_REQUEST, self.to_string()),
+impl BridgeError {
+    /// HTTP status and Matrix error body describing this error to a client.
+    pub fn to_matrix_error(&self) -> (StatusCode, serde_json::Value) {
+        let (status, errcode, message) = match self {
+            BridgeError::MatrixApi { .. } => (StatusCode::BAD_GATEWAY, "M_UNKNOWN", self.to_string()),
+            BridgeError::MyceliumNetwork { .. } => (StatusCode::SERVICE_UNAVAILABLE, "M_UNKNOWN", self.to_string()),
+            BridgeError::MyceliumApi { .. } => (StatusCode::BAD_GATEWAY, "M_UNKNOWN", self.to_string()),
+            BridgeError::Database { .. } => (StatusCode::INTERNAL_SERVER_ERROR, "M_UNKNOWN", "Database error".to_string()),
+            BridgeError::Config { .. } => (StatusCode::INTERNAL_SERVER_ERROR, "M_UNKNOWN", "Configuration error".to_string()),
+            BridgeError::Serde { .. } => (StatusCode::BAD_REQUEST, "M_BAD_JSON", "Invalid data format".to_string()),
+            BridgeError::Auth { .. } => (StatusCode::UNAUTHORIZED, "M_UNAUTHORIZED", self.to_string()),
+            BridgeError::Federation { .. } => (StatusCode::BAD_REQUEST, "M_UNKNOWN", self.to_string()),
+            BridgeError::Timeout => (StatusCode::REQUEST_TIMEOUT, "M_UNKNOWN", "Request timeout".to_string()),
+            BridgeError::Unreachable { .. } => (StatusCode::BAD_GATEWAY, "M_UNKNOWN", self.to_string()),
+            BridgeError::InvalidResponse { .. } => (StatusCode::BAD_GATEWAY, "M_UNKNOWN", self.to_string()),
+            BridgeError::PartialDelivery { .. } => (StatusCode::BAD_GATEWAY, "M_UNKNOWN", self.to_string()),
+            BridgeError::UnresolvableKey { .. } => (StatusCode::BAD_GATEWAY, "M_UNKNOWN", self.to_string()),
+            BridgeError::Blocked { .. } => (StatusCode::FORBIDDEN, "M_FORBIDDEN", self.to_string()),
+            BridgeError::Forbidden { .. } => (StatusCode::FORBIDDEN, "M_FORBIDDEN", self.to_string()),
+            BridgeError::NotFound => (StatusCode::NOT_FOUND, "M_NOT_FOUND", "Resource not found".to_string()),
+            BridgeError::InvalidRequest { .. } => (StatusCode::BAD_REQUEST, "M_INVALID_PARAM", self.to_string()),
         };
 
-        (status, Json(json!({"error": message}))).into_response()
+        (status, json!({"errcode": errcode, "error": message}))
+    }
+}
+
+impl IntoResponse for BridgeError {
+    fn into_response(self) -> Response {
+        let (status, body) = self.to_matrix_error();
+        (status, Json(body)).into_response()
     }
 }
 
@@ -79,8 +111,20 @@ impl From<serde_json::Error> for BridgeError {
 
 impl From<reqwest::Error> for BridgeError {
     fn from(err: reqwest::Error) -> Self {
-        BridgeError::MatrixApi {
-            message: err.to_string(),
+        if err.is_timeout() {
+            BridgeError::Timeout
+        } else if err.is_connect() {
+            BridgeError::Unreachable {
+                message: err.to_string(),
+            }
+        } else if err.is_decode() {
+            BridgeError::InvalidResponse {
+                message: err.to_string(),
+            }
+        } else {
+            BridgeError::MatrixApi {
+                message: err.to_string(),
+            }
         }
     }
 }
@@ -0,0 +1,425 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::collections::HashMap;
+use tokio::sync::{oneshot, Mutex};
+use tokio::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crate::envelope::{decode_base64, Envelope, EnvelopeKind, HEADER_LEN};
+use crate::error::{BridgeError, Result};
+
+/// Topic carrying every fragment except the one a reply is waited on.
+pub const FRAGMENT_TOPIC: &str = "matrix.federation.fragment";
+
+/// Room left in each fragment for its JSON framing around the data.
+const FRAGMENT_OVERHEAD: usize = 256;
+
+/// Smallest data chunk per fragment, whatever the configured message size.
+const MIN_CHUNK_SIZE: usize = 1024;
+
+/// One numbered piece of an encoded envelope.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fragment {
+    /// Id of the envelope being reassembled.
+    pub message_id: Uuid,
+    /// Topic the reassembled envelope was sent on.
+    pub topic: String,
+    pub index: u32,
+    pub count: u32,
+    pub data: Vec<u8>,
+}
+
+impl Fragment {
+    pub fn to_envelope(&self) -> Envelope {
+        Envelope::new(EnvelopeKind::Fragment, serde_json::json!({
+            "message_id": self.message_id.to_string(),
+            "topic": self.topic,
+            "index": self.index,
+            "count": self.count,
+            "data": STANDARD.encode(&self.data),
+        }))
+    }
+
+    pub fn from_envelope(envelope: &Envelope) -> Result<Self> {
+        let invalid = |field: &str| BridgeError::Serde {
+            message: format!("Fragment {} has an invalid {}", envelope.message_id, field)
+        };
+
+        if envelope.kind != EnvelopeKind::Fragment {
+            return Err(BridgeError::Serde {
+                message: format!("Envelope {} is a {:?}, not a fragment", envelope.message_id, envelope.kind)
+            });
+        }
+
+        let body = &envelope.body;
+        let fragment = Self {
+            message_id: body.get("message_id")
+                .and_then(|v| v.as_str())
+                .and_then(|id| Uuid::parse_str(id).ok())
+                .ok_or_else(|| invalid("message_id"))?,
+            topic: body.get("topic").and_then(|v| v.as_str()).ok_or_else(|| invalid("topic"))?.to_string(),
+            index: body.get("index").and_then(|v| v.as_u64()).and_then(|v| u32::try_from(v).ok()).ok_or_else(|| invalid("index"))?,
+            count: body.get("count").and_then(|v| v.as_u64()).and_then(|v| u32::try_from(v).ok()).ok_or_else(|| invalid("count"))?,
+            data: decode_base64("fragment data", body.get("data").and_then(|v| v.as_str()).ok_or_else(|| invalid("data"))?)?,
+        };
+
+        if fragment.count == 0 || fragment.index >= fragment.count {
+            return Err(invalid("index"));
+        }
+
+        Ok(fragment)
+    }
+
+    /// The fragment the sender waits for a reply on, and the receiver answers.
+    pub fn is_last(&self) -> bool {
+        self.index + 1 == self.count
+    }
+}
+
+/// Split `envelope` into fragments that each encode to at most `max_message_size`
+/// bytes. `None` means it already fits in one message.
+pub fn fragment_envelope(envelope: &Envelope, topic: &str, max_message_size: usize) -> Result<Option<Vec<Envelope>>> {
+    let bytes = envelope.encode()?;
+    if bytes.len() <= max_message_size {
+        return Ok(None);
+    }
+
+    // Base64 inflates the data by a third inside the fragment's JSON body
+    let chunk_size = (max_message_size.saturating_sub(HEADER_LEN + FRAGMENT_OVERHEAD + topic.len()) / 4 * 3)
+        .max(MIN_CHUNK_SIZE);
+    let count = u32::try_from(bytes.len().div_ceil(chunk_size)).map_err(|_| BridgeError::InvalidRequest {
+        message: format!("Payload of {} bytes needs too many fragments", bytes.len())
+    })?;
+
+    let fragments = bytes.chunks(chunk_size)
+        .enumerate()
+        .map(|(index, chunk)| Fragment {
+            message_id: envelope.message_id,
+            topic: topic.to_string(),
+            index: index as u32,
+            count,
+            data: chunk.to_vec(),
+        }.to_envelope())
+        .collect();
+
+    Ok(Some(fragments))
+}
+
+/// Where to answer a reassembled request: the transport message the sender
+/// waits on for a reply.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplyTarget {
+    pub transport_id: String,
+    pub source_pubkey: String,
+}
+
+/// A message put back together from all of its fragments.
+#[derive(Debug)]
+pub struct Reassembled {
+    pub topic: String,
+    pub bytes: Vec<u8>,
+    pub reply_to: Option<ReplyTarget>,
+}
+
+/// A message given up on before all of its fragments arrived.
+#[derive(Debug)]
+pub struct Abandoned {
+    pub message_id: Uuid,
+    pub reason: BridgeError,
+    /// Status to report back to the sender, if it's waiting for a reply.
+    pub status_code: u16,
+    pub reply_to: Option<ReplyTarget>,
+}
+
+struct PartialMessage {
+    topic: String,
+    count: u32,
+    fragments: Vec<Option<Vec<u8>>>,
+    received: u32,
+    bytes: usize,
+    started: Instant,
+    reply_to: Option<ReplyTarget>,
+    waiter: Option<oneshot::Sender<Result<Reassembled>>>,
+}
+
+impl PartialMessage {
+    /// Memory held for this message: its data plus the slots awaiting fragments.
+    fn memory(&self) -> usize {
+        self.bytes + self.fragments.len() * std::mem::size_of::<Option<Vec<u8>>>()
+    }
+
+    fn assemble(self) -> Reassembled {
+        Reassembled {
+            topic: self.topic,
+            bytes: self.fragments.into_iter().flatten().flatten().collect(),
+            reply_to: self.reply_to,
+        }
+    }
+}
+
+/// Partial messages are keyed by the sending node as well as the message id,
+/// so a peer can't add fragments to another peer's message by reusing its id.
+type PartialKey = (String, Uuid);
+
+/// Buffers fragments until their message is complete, bounded in time and memory.
+pub struct Reassembler {
+    timeout: Duration,
+    max_message_size: usize,
+    memory_limit: usize,
+    partial: Mutex<HashMap<PartialKey, PartialMessage>>,
+}
+
+impl Reassembler {
+    pub fn new(timeout: Duration, max_message_size: usize, memory_limit: usize) -> Self {
+        Self {
+            timeout,
+            max_message_size,
+            memory_limit,
+            partial: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Buffer `fragment`, sent by the node with public key `source`. Returns the
+    /// whole message once its last missing piece arrives, unless somebody is
+    /// waiting for it through [`Self::wait_for`].
+    pub async fn insert(&self, source: &str, fragment: Fragment, reply_to: Option<ReplyTarget>) -> std::result::Result<Option<Reassembled>, Abandoned> {
+        let mut partial = self.partial.lock().await;
+        let message_id = fragment.message_id;
+
+        match self.insert_locked(&mut partial, source, fragment, reply_to)? {
+            Some(mut message) => match message.waiter.take() {
+                Some(waiter) => {
+                    let _ = waiter.send(Ok(message.assemble()));
+                    Ok(None)
+                }
+                None => Ok(Some(message.assemble())),
+            },
+            None => {
+                tracing::debug!("Buffered fragment of message {}", message_id);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Buffer `fragment`, sent by `source`, and wait up to the reassembly
+    /// timeout for the rest of its message, which arrives through [`Self::insert`].
+    pub async fn wait_for(&self, source: &str, fragment: Fragment) -> Result<Reassembled> {
+        let message_id = fragment.message_id;
+        let key = (source.to_string(), message_id);
+        let receiver = {
+            let mut partial = self.partial.lock().await;
+            match self.insert_locked(&mut partial, source, fragment, None).map_err(|abandoned| abandoned.reason)? {
+                Some(message) => return Ok(message.assemble()),
+                None => {
+                    let (sender, receiver) = oneshot::channel();
+                    if let Some(message) = partial.get_mut(&key) {
+                        message.waiter = Some(sender);
+                    }
+                    receiver
+                }
+            }
+        };
+
+        match tokio::time::timeout(self.timeout, receiver).await {
+            Ok(Ok(result)) => result,
+            _ => {
+                let missing = self.partial.lock().await.remove(&key);
+                Err(BridgeError::PartialDelivery {
+                    message: match missing {
+                        Some(message) => format!("Received {} of {} fragments of message {}", message.received, message.count, message_id),
+                        None => format!("Gave up waiting for fragments of message {}", message_id),
+                    }
+                })
+            }
+        }
+    }
+
+    /// Drop messages whose fragments stopped arriving, failing anyone waiting on them.
+    pub async fn expire(&self) -> Vec<Abandoned> {
+        let mut partial = self.partial.lock().await;
+        let expired: Vec<PartialKey> = partial.iter()
+            .filter(|(_, message)| message.started.elapsed() >= self.timeout)
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        expired.into_iter()
+            .filter_map(|key| partial.remove(&key).map(|message| (key.1, message)))
+            .map(|(id, message)| {
+                let reason = format!("Received {} of {} fragments of message {} before timing out", message.received, message.count, id);
+                abandon(id, message, 408, reason)
+            })
+            .collect()
+    }
+
+    /// Bytes currently buffered across all partial messages.
+    pub async fn buffered_bytes(&self) -> usize {
+        self.partial.lock().await.values().map(PartialMessage::memory).sum()
+    }
+
+    fn insert_locked(
+        &self,
+        partial: &mut HashMap<PartialKey, PartialMessage>,
+        source: &str,
+        fragment: Fragment,
+        reply_to: Option<ReplyTarget>,
+    ) -> std::result::Result<Option<PartialMessage>, Abandoned> {
+        let message_id = fragment.message_id;
+        let key = (source.to_string(), message_id);
+        let buffered: usize = partial.values().map(PartialMessage::memory).sum();
+
+        // The count comes from the peer, so check what its slots would cost before allocating them
+        if !partial.contains_key(&key) {
+            let max_count = self.max_message_size.div_ceil(MIN_CHUNK_SIZE);
+            let slots = fragment.count as usize * std::mem::size_of::<Option<Vec<u8>>>();
+            if fragment.count as usize > max_count || buffered + slots > self.memory_limit {
+                let reason = format!("Message {} claims {} fragments, more than can be reassembled", message_id, fragment.count);
+                tracing::warn!("{}", reason);
+                return Err(Abandoned {
+                    message_id,
+                    reason: BridgeError::PartialDelivery { message: reason },
+                    status_code: 413,
+                    reply_to: reply_to.filter(|_| fragment.is_last()),
+                });
+            }
+        }
+
+        let message = partial.entry(key.clone()).or_insert_with(|| PartialMessage {
+            topic: fragment.topic.clone(),
+            count: fragment.count,
+            fragments: vec![None; fragment.count as usize],
+            received: 0,
+            bytes: 0,
+            started: Instant::now(),
+            reply_to: None,
+            waiter: None,
+        });
+        if fragment.is_last() {
+            message.reply_to = reply_to.or(message.reply_to.take());
+        }
+
+        if fragment.count != message.count {
+            let message = partial.remove(&key).unwrap();
+            let reason = format!("Fragments of message {} disagree on their count", message_id);
+            return Err(abandon(message_id, message, 400, reason));
+        }
+        if message.bytes + fragment.data.len() > self.max_message_size || buffered + fragment.data.len() > self.memory_limit {
+            let message = partial.remove(&key).unwrap();
+            let reason = format!("Message {} exceeds the reassembly memory limit", message_id);
+            return Err(abandon(message_id, message, 413, reason));
+        }
+
+        let slot = &mut message.fragments[fragment.index as usize];
+        if slot.is_none() {
+            message.bytes += fragment.data.len();
+            message.received += 1;
+            *slot = Some(fragment.data);
+        }
+
+        if message.received < message.count {
+            return Ok(None);
+        }
+        Ok(partial.remove(&key))
+    }
+}
+
+fn abandon(message_id: Uuid, mut message: PartialMessage, status_code: u16, reason: String) -> Abandoned {
+    tracing::warn!("{}", reason);
+    if let Some(waiter) = message.waiter.take() {
+        let _ = waiter.send(Err(BridgeError::PartialDelivery { message: reason.clone() }));
+    }
+    Abandoned {
+        message_id,
+        reason: BridgeError::PartialDelivery { message: reason },
+        status_code,
+        reply_to: message.reply_to,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn large_envelope() -> Envelope {
+        let pdus: Vec<_> = (0..500).map(|i| serde_json::json!({"event_id": format!("$event{}", i), "content": "x".repeat(100)})).collect();
+        Envelope::new(EnvelopeKind::Request, serde_json::json!({"pdus": pdus}))
+    }
+
+    #[tokio::test]
+    async fn test_fragment_and_reassemble() {
+        let envelope = large_envelope();
+        assert!(fragment_envelope(&envelope, "matrix.federation.put", usize::MAX).unwrap().is_none());
+
+        let fragments = fragment_envelope(&envelope, "matrix.federation.put", 8 * 1024).unwrap().unwrap();
+        assert!(fragments.len() > 1);
+        for fragment in &fragments {
+            assert!(fragment.encode().unwrap().len() <= 8 * 1024);
+        }
+
+        // Out of order and duplicated fragments still reassemble once
+        let reassembler = Reassembler::new(Duration::from_secs(60), 16 * 1024 * 1024, 64 * 1024 * 1024);
+        let reply_to = ReplyTarget { transport_id: "0123456789abcdef".to_string(), source_pubkey: "aa".repeat(32) };
+        let mut complete = None;
+        for fragment in fragments.last().into_iter().chain(fragments.iter().rev()) {
+            let fragment = Fragment::from_envelope(&Envelope::decode(&fragment.encode().unwrap()).unwrap()).unwrap();
+            let target = fragment.is_last().then(|| reply_to.clone());
+            if let Some(message) = reassembler.insert("aa", fragment, target).await.unwrap() {
+                assert!(complete.is_none());
+                complete = Some(message);
+            }
+        }
+
+        let complete = complete.unwrap();
+        assert_eq!(complete.topic, "matrix.federation.put");
+        assert_eq!(complete.reply_to, Some(reply_to));
+        assert_eq!(Envelope::decode(&complete.bytes).unwrap(), envelope);
+        assert_eq!(reassembler.buffered_bytes().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_reassembly_limits() {
+        let envelope = large_envelope();
+        let fragments: Vec<Fragment> = fragment_envelope(&envelope, "matrix.federation.put", 8 * 1024).unwrap().unwrap()
+            .iter()
+            .map(|fragment| Fragment::from_envelope(fragment).unwrap())
+            .collect();
+
+        // Memory limit: the message is dropped, not half-buffered
+        let reassembler = Reassembler::new(Duration::from_secs(60), 16 * 1024, 64 * 1024 * 1024);
+        let mut abandoned = None;
+        for fragment in fragments.clone() {
+            if let Err(e) = reassembler.insert("aa", fragment, None).await {
+                abandoned = Some(e);
+                break;
+            }
+        }
+        assert_eq!(abandoned.unwrap().status_code, 413);
+        assert_eq!(reassembler.buffered_bytes().await, 0);
+
+        // Timeout: missing fragments fail the waiter and are reported as expired
+        let reassembler = Reassembler::new(Duration::from_millis(50), 16 * 1024 * 1024, 64 * 1024 * 1024);
+        let last = fragments.last().unwrap().clone();
+        reassembler.insert("aa", fragments[0].clone(), None).await.unwrap();
+        assert!(matches!(reassembler.wait_for("aa", last).await, Err(BridgeError::PartialDelivery { .. })));
+
+        reassembler.insert("aa", fragments[0].clone(), None).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        let expired = reassembler.expire().await;
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].status_code, 408);
+
+        // A claimed count is checked before any slots are allocated for it
+        let huge = Fragment { count: u32::MAX, index: 0, ..fragments[0].clone() };
+        assert_eq!(reassembler.insert("aa", huge, None).await.unwrap_err().status_code, 413);
+        assert_eq!(reassembler.buffered_bytes().await, 0);
+
+        // Another node reusing the message id starts its own message
+        let reassembler = Reassembler::new(Duration::from_secs(60), 16 * 1024 * 1024, 64 * 1024 * 1024);
+        let bogus = Fragment { count: 2, index: 1, ..fragments[0].clone() };
+        reassembler.insert("bb", bogus, None).await.unwrap();
+        let mut complete = None;
+        for fragment in fragments.clone() {
+            complete = reassembler.insert("aa", fragment, None).await.unwrap().or(complete);
+        }
+        assert_eq!(Envelope::decode(&complete.unwrap().bytes).unwrap(), envelope);
+    }
+}
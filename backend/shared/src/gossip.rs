@@ -0,0 +1,137 @@
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::watch;
+use tokio::time::Duration;
+
+use crate::bridge::MatrixMyceliumBridge;
+use crate::error::{BridgeError, Result};
+use crate::signing::ServerSigningKey;
+
+/// Topic route announcements are exchanged on.
+pub const ANNOUNCE_TOPIC: &str = "matrix.federation.announce";
+
+/// Transport features this bridge supports, advertised to peers.
+pub const CAPABILITIES: &[&str] = &["envelope.v1", "fragments", "compression.deflate", "compression.zstd"];
+
+/// Announcements older than this, or this far in the future, are rejected as replays.
+pub const MAX_ANNOUNCEMENT_AGE: Duration = Duration::from_secs(3600);
+
+/// Gossip intervals a peer may miss before the node it announced is dropped.
+pub const ANNOUNCEMENT_MISSES: u64 = 3;
+
+/// A server telling its peers which Mycelium node reaches it, signed with
+/// the server's Matrix signing key.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RouteAnnouncement {
+    pub server_name: String,
+    pub mycelium_pubkey: String,
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    /// Milliseconds since the epoch.
+    pub ts: u64,
+    #[serde(default)]
+    pub signatures: serde_json::Value,
+}
+
+impl RouteAnnouncement {
+    pub fn new(server_name: &str, mycelium_pubkey: &str, ts: u64) -> Self {
+        Self {
+            server_name: server_name.to_string(),
+            mycelium_pubkey: mycelium_pubkey.to_string(),
+            capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+            ts,
+            signatures: serde_json::json!({}),
+        }
+    }
+
+    pub fn signed(self, signing_key: &ServerSigningKey) -> Result<serde_json::Value> {
+        let server_name = self.server_name.clone();
+        let mut value = serde_json::to_value(self)?;
+        signing_key.add_signature(&mut value, &server_name)?;
+        Ok(value)
+    }
+
+    /// Key ids `server_name` signed this announcement with.
+    pub fn signing_key_ids(&self) -> Vec<String> {
+        self.signatures.get(&self.server_name)
+            .and_then(|v| v.as_object())
+            .map(|signatures| signatures.keys().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Reject announcements that are stale, from the future, or relayed by a
+    /// node other than the one they announce.
+    pub fn check_freshness(&self, source_pubkey: &str, now_ms: u64, max_age: Duration) -> Result<()> {
+        if !self.mycelium_pubkey.eq_ignore_ascii_case(source_pubkey) {
+            return Err(BridgeError::Auth {
+                message: format!("Announcement for {} was sent by another node", self.server_name)
+            });
+        }
+
+        let max_age_ms = max_age.as_millis() as u64;
+        if self.ts + max_age_ms < now_ms || self.ts > now_ms + max_age_ms {
+            return Err(BridgeError::Auth {
+                message: format!("Announcement for {} is not current", self.server_name)
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// Announce our route to every known peer every `gossip_interval` seconds,
+/// dropping nodes peers stopped announcing, until `shutdown` flips to true.
+pub async fn run_route_gossip(bridge: Arc<MatrixMyceliumBridge>, mut shutdown: watch::Receiver<bool>) {
+    let interval = bridge.config.gossip_interval;
+    if interval == 0 || !bridge.config.mycelium_enabled {
+        tracing::info!("Route gossip disabled");
+        return;
+    }
+
+    tracing::info!("Route gossip started, announcing every {}s", interval);
+
+    while !*shutdown.borrow() {
+        match bridge.announce_route().await {
+            Ok(peers) => tracing::debug!("Announced our route to {} peers", peers),
+            Err(e) => tracing::warn!("Failed to announce our route: {}", e),
+        }
+        let expired = bridge.expire_announced_routes().await;
+        if expired > 0 {
+            tracing::debug!("Dropped {} routes no longer announced", expired);
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(interval)) => {},
+            _ = shutdown.changed() => {},
+        }
+    }
+
+    tracing::info!("Route gossip stopped");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signing::verify_json;
+
+    #[test]
+    fn test_route_announcement() {
+        let signing_key = ServerSigningKey::generate();
+        let pubkey = "ab".repeat(32);
+        let signed = RouteAnnouncement::new("remote.example.com", &pubkey, 1_000_000).signed(&signing_key).unwrap();
+
+        let announcement: RouteAnnouncement = serde_json::from_value(signed.clone()).unwrap();
+        assert_eq!(announcement.signing_key_ids(), vec![signing_key.key_id()]);
+        assert!(verify_json(&signed, "remote.example.com", &signing_key.key_id(), &signing_key.verifying_key()).is_ok());
+
+        // Claiming somebody else's node is a forgery
+        let mut forged = signed.clone();
+        forged["mycelium_pubkey"] = serde_json::json!("cd".repeat(32));
+        assert!(verify_json(&forged, "remote.example.com", &signing_key.key_id(), &signing_key.verifying_key()).is_err());
+
+        let max_age = Duration::from_secs(900);
+        assert!(announcement.check_freshness(&pubkey, 1_000_000, max_age).is_ok());
+        assert!(announcement.check_freshness(&"CD".repeat(32), 1_000_000, max_age).is_err());
+        assert!(announcement.check_freshness(&pubkey, 1_000_000 + 901_000, max_age).is_err());
+    }
+}
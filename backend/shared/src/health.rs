@@ -0,0 +1,116 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use tokio::sync::watch;
+use tokio::time::Duration;
+
+use crate::bridge::MatrixMyceliumBridge;
+
+/// How many recent probes the rolling statistics are computed over.
+pub const PROBE_WINDOW: usize = 20;
+
+/// Federation endpoint probed over both transports; it needs no auth and is cheap to answer.
+pub const PROBE_PATH: &str = "/_matrix/federation/v1/version";
+
+/// Rolling probe results for one route. `None` entries are failed probes.
+#[derive(Debug, Clone, Default)]
+pub struct RouteHealth {
+    probes: VecDeque<Option<u64>>,
+    consecutive_failures: u32,
+}
+
+impl RouteHealth {
+    pub fn record_success(&mut self, latency_ms: u64) {
+        self.record(Some(latency_ms));
+        self.consecutive_failures = 0;
+    }
+
+    pub fn record_failure(&mut self) {
+        self.record(None);
+        self.consecutive_failures += 1;
+    }
+
+    fn record(&mut self, probe: Option<u64>) {
+        if self.probes.len() == PROBE_WINDOW {
+            self.probes.pop_front();
+        }
+        self.probes.push_back(probe);
+    }
+
+    pub fn consecutive_failures(&self) -> u32 {
+        self.consecutive_failures
+    }
+
+    /// Mean latency of the successful probes in the window.
+    pub fn average_latency_ms(&self) -> Option<u64> {
+        let latencies: Vec<u64> = self.probes.iter().flatten().copied().collect();
+        if latencies.is_empty() {
+            return None;
+        }
+        Some(latencies.iter().sum::<u64>() / latencies.len() as u64)
+    }
+
+    /// Share of probes in the window that succeeded, 1.0 before the first probe.
+    pub fn success_rate(&self) -> f64 {
+        if self.probes.is_empty() {
+            return 1.0;
+        }
+        self.probes.iter().filter(|probe| probe.is_some()).count() as f64 / self.probes.len() as f64
+    }
+}
+
+/// Probe every route each `route_probe_interval` seconds until `shutdown` flips to true.
+pub async fn run_route_prober(bridge: Arc<MatrixMyceliumBridge>, mut shutdown: watch::Receiver<bool>) {
+    let interval = bridge.config.route_probe_interval;
+    if interval == 0 || !bridge.config.mycelium_enabled {
+        tracing::info!("Route probing disabled");
+        return;
+    }
+
+    tracing::info!("Route prober started, probing every {}s", interval);
+
+    while !*shutdown.borrow() {
+        let routes = tokio::select! {
+            routes = bridge.probe_routes() => routes,
+            _ = shutdown.changed() => break,
+        };
+        let unhealthy = routes.iter().filter(|route| !route.healthy).count();
+        tracing::debug!("Probed {} routes, {} unhealthy", routes.len(), unhealthy);
+
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(interval)) => {},
+            _ = shutdown.changed() => {},
+        }
+    }
+
+    tracing::info!("Route prober stopped");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_route_health_window() {
+        let mut health = RouteHealth::default();
+        assert_eq!(health.average_latency_ms(), None);
+        assert_eq!(health.success_rate(), 1.0);
+
+        health.record_success(100);
+        health.record_success(200);
+        health.record_failure();
+        health.record_failure();
+        assert_eq!(health.consecutive_failures(), 2);
+        assert_eq!(health.average_latency_ms(), Some(150));
+        assert_eq!(health.success_rate(), 0.5);
+
+        health.record_success(30);
+        assert_eq!(health.consecutive_failures(), 0);
+
+        // Old probes fall out of the window
+        for _ in 0..PROBE_WINDOW {
+            health.record_success(10);
+        }
+        assert_eq!(health.average_latency_ms(), Some(10));
+        assert_eq!(health.success_rate(), 1.0);
+    }
+}
@@ -0,0 +1,319 @@
+use ed25519_dalek::VerifyingKey;
+use std::collections::HashMap;
+use tokio::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+use crate::error::{BridgeError, Result};
+use crate::signing::{parse_verify_key, verify_json};
+
+/// Don't ask a server for its keys again sooner than this after a failed fetch.
+const FETCH_FAILURE_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Upper bound on how long fetched keys are trusted, whatever `valid_until_ts` says.
+const MAX_KEY_CACHE_MS: u64 = 7 * 24 * 60 * 60 * 1000;
+
+#[derive(Debug, Clone)]
+struct CachedServerKeys {
+    verify_keys: HashMap<String, VerifyingKey>,
+    valid_until_ts: u64,
+    /// Keys the server has retired, each trusted only until its own `expired_ts`.
+    old_verify_keys: HashMap<String, (VerifyingKey, u64)>,
+}
+
+/// Fetches and caches remote servers' ed25519 verify keys, either directly from
+/// `/_matrix/key/v2/server` or through trusted notary servers.
+pub struct ServerKeyStore {
+    client: reqwest::Client,
+    trusted_key_servers: Vec<String>,
+    keys: Mutex<HashMap<String, CachedServerKeys>>,
+    failed_fetches: Mutex<HashMap<String, Instant>>,
+}
+
+impl ServerKeyStore {
+    pub fn new(client: reqwest::Client, trusted_key_servers: Vec<String>) -> Self {
+        Self {
+            client,
+            trusted_key_servers,
+            keys: Mutex::new(HashMap::new()),
+            failed_fetches: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Seed the cache with a known key, e.g. our own.
+    pub async fn insert_verify_key(&self, server_name: &str, key_id: &str, key: VerifyingKey, valid_until_ts: u64) {
+        let mut keys = self.keys.lock().await;
+        let entry = keys.entry(server_name.to_string()).or_insert_with(|| CachedServerKeys {
+            verify_keys: HashMap::new(),
+            valid_until_ts,
+            old_verify_keys: HashMap::new(),
+        });
+        entry.verify_keys.insert(key_id.to_string(), key);
+        entry.valid_until_ts = entry.valid_until_ts.max(valid_until_ts);
+    }
+
+    /// Look up `server_name`'s verify key `key_id`, fetching the server's keys if needed.
+    pub async fn get_verify_key(&self, server_name: &str, key_id: &str) -> Result<VerifyingKey> {
+        if let Some(key) = self.cached_key(server_name, key_id).await {
+            return Ok(key);
+        }
+
+        self.refresh(server_name).await?;
+
+        self.cached_key(server_name, key_id).await.ok_or_else(|| BridgeError::Auth {
+            message: format!("Unknown verify key {} for {}", key_id, server_name)
+        })
+    }
+
+    async fn cached_key(&self, server_name: &str, key_id: &str) -> Option<VerifyingKey> {
+        let now = now_ms();
+        let keys = self.keys.lock().await;
+        let cached = keys.get(server_name)?;
+        let current = cached.verify_keys.get(key_id).filter(|_| cached.valid_until_ts > now);
+        let old = cached.old_verify_keys.get(key_id)
+            .filter(|(_, expired_ts)| *expired_ts > now)
+            .map(|(key, _)| key);
+        current.or(old).copied()
+    }
+
+    async fn refresh(&self, server_name: &str) -> Result<()> {
+        {
+            let failed = self.failed_fetches.lock().await;
+            if failed.get(server_name).is_some_and(|at| at.elapsed() < FETCH_FAILURE_BACKOFF) {
+                return Err(BridgeError::Auth {
+                    message: format!("Keys for {} are temporarily unavailable", server_name)
+                });
+            }
+        }
+
+        let mut result = self.fetch_direct(server_name).await;
+
+        if result.is_err() {
+            for notary in &self.trusted_key_servers {
+                match self.fetch_via_notary(notary, server_name).await {
+                    Ok(keys) => {
+                        result = Ok(keys);
+                        break;
+                    }
+                    Err(e) => tracing::warn!("Notary {} failed to provide keys for {}: {}", notary, server_name, e),
+                }
+            }
+        }
+
+        match result {
+            Ok(fetched) => {
+                self.failed_fetches.lock().await.remove(server_name);
+                // The server's answer is authoritative: keys it no longer lists are dropped
+                self.keys.lock().await.insert(server_name.to_string(), fetched);
+                Ok(())
+            }
+            Err(e) => {
+                tracing::warn!("Failed to fetch server keys for {}: {}", server_name, e);
+                self.failed_fetches.lock().await.insert(server_name.to_string(), Instant::now());
+                Err(e)
+            }
+        }
+    }
+
+    async fn fetch_direct(&self, server_name: &str) -> Result<CachedServerKeys> {
+        let base_url = resolve_federation_url(&self.client, server_name).await;
+        let response: serde_json::Value = self.client
+            .get(format!("{}/_matrix/key/v2/server", base_url))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        parse_server_keys(server_name, &response)
+    }
+
+    async fn fetch_via_notary(&self, notary: &str, server_name: &str) -> Result<CachedServerKeys> {
+        // The notary's own keys are always fetched directly
+        let cached = self.keys.lock().await.get(notary).cloned();
+        let notary_keys = match cached {
+            Some(keys) if keys.valid_until_ts > now_ms() => keys,
+            _ => {
+                let keys = self.fetch_direct(notary).await?;
+                self.keys.lock().await.insert(notary.to_string(), keys.clone());
+                keys
+            }
+        };
+
+        // The name comes from the requester's X-Matrix header; keep it one path segment
+        let base_url = resolve_federation_url(&self.client, notary).await;
+        let encoded_name = percent_encoding::utf8_percent_encode(server_name, percent_encoding::NON_ALPHANUMERIC);
+        let response: serde_json::Value = self.client
+            .get(format!("{}/_matrix/key/v2/query/{}", base_url, encoded_name))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        let server_keys = response.get("server_keys")
+            .and_then(|v| v.as_array())
+            .ok_or_else(|| BridgeError::Auth {
+                message: format!("Notary {} returned no server_keys", notary)
+            })?;
+
+        for keys in server_keys {
+            let notary_signed = notary_keys.verify_keys.iter()
+                .any(|(key_id, key)| verify_json(keys, notary, key_id, key).is_ok());
+            if !notary_signed {
+                continue;
+            }
+            if let Ok(parsed) = parse_server_keys(server_name, keys) {
+                return Ok(parsed);
+            }
+        }
+
+        Err(BridgeError::Auth {
+            message: format!("Notary {} returned no valid keys for {}", notary, server_name)
+        })
+    }
+}
+
+/// Validate a `/_matrix/key/v2/server` response: it must be for `server_name`
+/// and self-signed by at least one of the current keys it lists.
+fn parse_server_keys(server_name: &str, response: &serde_json::Value) -> Result<CachedServerKeys> {
+    if response.get("server_name").and_then(|v| v.as_str()) != Some(server_name) {
+        return Err(BridgeError::Auth {
+            message: format!("Key response is not for {}", server_name)
+        });
+    }
+
+    let verify_keys: HashMap<String, VerifyingKey> = response.get("verify_keys")
+        .and_then(|v| v.as_object())
+        .map(|keys| keys.iter()
+            .filter_map(|(key_id, key)| {
+                let key = parse_verify_key(key.get("key")?.as_str()?).ok()?;
+                Some((key_id.clone(), key))
+            })
+            .collect())
+        .unwrap_or_default();
+
+    let self_signed = verify_keys.iter()
+        .any(|(key_id, key)| verify_json(response, server_name, key_id, key).is_ok());
+    if !self_signed {
+        return Err(BridgeError::Auth {
+            message: format!("Key response for {} is not self-signed", server_name)
+        });
+    }
+
+    let valid_until_ts = response.get("valid_until_ts")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(0)
+        .min(now_ms() + MAX_KEY_CACHE_MS);
+
+    let old_verify_keys: HashMap<String, (VerifyingKey, u64)> = response.get("old_verify_keys")
+        .and_then(|v| v.as_object())
+        .map(|keys| keys.iter()
+            .filter_map(|(key_id, key)| {
+                let expired_ts = key.get("expired_ts")?.as_u64()?.min(valid_until_ts);
+                let key = parse_verify_key(key.get("key")?.as_str()?).ok()?;
+                Some((key_id.clone(), (key, expired_ts)))
+            })
+            .collect())
+        .unwrap_or_default();
+
+    Ok(CachedServerKeys { verify_keys, valid_until_ts, old_verify_keys })
+}
+
+/// Base URL for federation traffic to `server_name`: an explicit port is used
+/// as-is, otherwise `.well-known/matrix/server` delegation is honoured before
+/// falling back to the default federation port.
+pub async fn resolve_federation_url(client: &reqwest::Client, server_name: &str) -> String {
+    let has_port = server_name.rsplit_once(':')
+        .is_some_and(|(host, port)| !host.ends_with(':') && port.chars().all(|c| c.is_ascii_digit()));
+    if has_port {
+        return format!("https://{}", server_name);
+    }
+
+    let delegated = async {
+        let well_known: serde_json::Value = client
+            .get(format!("https://{}/.well-known/matrix/server", server_name))
+            .send()
+            .await
+            .ok()?
+            .error_for_status()
+            .ok()?
+            .json()
+            .await
+            .ok()?;
+        well_known.get("m.server")?.as_str().map(str::to_string)
+    }
+    .await;
+
+    match delegated {
+        Some(server) if server.contains(':') => format!("https://{}", server),
+        Some(server) => format!("https://{}:8448", server),
+        None => format!("https://{}:8448", server_name),
+    }
+}
+
+fn now_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signing::ServerSigningKey;
+
+    #[test]
+    fn test_parse_server_keys() {
+        let key = ServerSigningKey::generate();
+        let response = key.server_keys_response("remote.example.com").unwrap();
+
+        let parsed = parse_server_keys("remote.example.com", &response).unwrap();
+        assert_eq!(parsed.verify_keys[&key.key_id()], key.verifying_key());
+        assert!(parsed.valid_until_ts > now_ms());
+
+        // Wrong server, or keys signed by somebody else
+        assert!(parse_server_keys("other.example.com", &response).is_err());
+        let mut forged = response.clone();
+        forged["verify_keys"][key.key_id()]["key"] = serde_json::json!(ServerSigningKey::generate().public_key_base64());
+        assert!(parse_server_keys("remote.example.com", &forged).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_cached_keys_skip_fetching() {
+        let store = ServerKeyStore::new(reqwest::Client::new(), Vec::new());
+        let key = ServerSigningKey::generate();
+
+        store.insert_verify_key("remote.example.com", &key.key_id(), key.verifying_key(), u64::MAX).await;
+        assert_eq!(store.get_verify_key("remote.example.com", &key.key_id()).await.unwrap(), key.verifying_key());
+
+        // Expired entries are not served from the cache
+        store.insert_verify_key("expired.invalid", "ed25519:old", key.verifying_key(), 1).await;
+        assert!(store.cached_key("expired.invalid", "ed25519:old").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_old_keys_expire_on_their_own() {
+        let store = ServerKeyStore::new(reqwest::Client::new(), Vec::new());
+        let key = ServerSigningKey::generate();
+        let retired = ServerSigningKey::generate();
+
+        let mut response = key.server_keys_response("remote.example.com").unwrap();
+        response["old_verify_keys"] = serde_json::json!({
+            "ed25519:retired": {"key": retired.public_key_base64(), "expired_ts": now_ms() + 60_000},
+            "ed25519:expired": {"key": retired.public_key_base64(), "expired_ts": 1}
+        });
+        response.as_object_mut().unwrap().remove("signatures");
+        key.add_signature(&mut response, "remote.example.com").unwrap();
+        let parsed = parse_server_keys("remote.example.com", &response).unwrap();
+        assert_eq!(parsed.old_verify_keys.len(), 2);
+
+        // A fetched set replaces what was cached, keeping retired keys only until they expire
+        store.insert_verify_key("remote.example.com", "ed25519:dropped", retired.verifying_key(), u64::MAX).await;
+        store.keys.lock().await.insert("remote.example.com".to_string(), parsed);
+        assert_eq!(store.cached_key("remote.example.com", &key.key_id()).await, Some(key.verifying_key()));
+        assert_eq!(store.cached_key("remote.example.com", "ed25519:retired").await, Some(retired.verifying_key()));
+        assert!(store.cached_key("remote.example.com", "ed25519:expired").await.is_none());
+        assert!(store.cached_key("remote.example.com", "ed25519:dropped").await.is_none());
+    }
+}
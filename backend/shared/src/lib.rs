@@ -4,6 +4,22 @@ pub mod server;
 pub mod types;
 pub mod error;
 pub mod database;
+pub mod signing;
+pub mod keys;
+pub mod outbox;
+pub mod consumer;
+pub mod envelope;
+pub mod fragment;
+pub mod routes;
+pub mod discovery;
+pub mod gossip;
+pub mod health;
+pub mod pubkey;
+pub mod policy;
+pub mod breaker;
+pub mod transaction;
+pub mod edu;
+pub mod state;
 
 // Re-export commonly used types
 pub use bridge::{MatrixMyceliumBridge};
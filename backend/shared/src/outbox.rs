@@ -0,0 +1,115 @@
+use std::sync::Arc;
+use tokio::sync::watch;
+use tokio::time::Duration;
+
+use crate::bridge::{unix_now, MatrixMyceliumBridge};
+use crate::error::Result;
+use crate::types::OutboxEntry;
+
+/// How often the worker looks for due outbox entries.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Maximum number of destinations delivered to concurrently per poll.
+const CLAIM_BATCH_SIZE: i64 = 32;
+
+const BASE_BACKOFF_SECS: i64 = 5;
+const MAX_BACKOFF_SECS: i64 = 60 * 60;
+
+/// Entries still failing after this many attempts are parked as `failed`.
+const MAX_ATTEMPTS: i32 = 20;
+
+/// Delay before retrying an entry that has failed `attempts` times.
+pub fn backoff_secs(attempts: i32) -> i64 {
+    let exponent = attempts.saturating_sub(1).clamp(0, 20) as u32;
+    BASE_BACKOFF_SECS.saturating_mul(1 << exponent).min(MAX_BACKOFF_SECS)
+}
+
+/// Deliver queued federation requests until `shutdown` flips to true.
+pub async fn run_outbox_worker(bridge: Arc<MatrixMyceliumBridge>, mut shutdown: watch::Receiver<bool>) {
+    // Entries claimed longer ago than this belonged to a worker that died mid-delivery
+    let lease_secs = bridge.config.federation_timeout as i64 * 2 + 60;
+
+    tracing::info!("Outbox worker started");
+
+    while !*shutdown.borrow() {
+        if let Err(e) = process_due_entries(&bridge, lease_secs).await {
+            tracing::warn!("Outbox worker iteration failed: {}", e);
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(POLL_INTERVAL) => {},
+            _ = shutdown.changed() => {},
+        }
+    }
+
+    tracing::info!("Outbox worker stopped");
+}
+
+async fn process_due_entries(bridge: &MatrixMyceliumBridge, lease_secs: i64) -> Result<()> {
+    let database = bridge.database()?;
+    let now = unix_now();
+
+    let released = database.release_stale_outbox_entries(now - lease_secs).await?;
+    if released > 0 {
+        tracing::warn!("Requeued {} outbox entries abandoned mid-delivery", released);
+    }
+
+    let entries = database.claim_due_outbox_entries(now, CLAIM_BATCH_SIZE).await?;
+    let results = futures::future::join_all(entries.iter().map(|entry| deliver(bridge, entry))).await;
+
+    for (entry, result) in entries.iter().zip(results) {
+        if let Err(e) = result {
+            tracing::warn!("Failed to record outcome of outbox entry {}: {}", entry.id, e);
+        }
+    }
+
+    Ok(())
+}
+
+async fn deliver(bridge: &MatrixMyceliumBridge, entry: &OutboxEntry) -> Result<()> {
+    let database = bridge.database()?;
+
+    let error = match bridge.deliver_outbox_entry(entry).await {
+        // The remote rejected the request itself; replaying it won't help
+        Ok(response) if (400..500).contains(&response.status_code) && response.status_code != 429 => {
+            tracing::warn!("Outbox entry {} rejected by {} with {}", entry.id, entry.destination, response.status_code);
+            let error = format!("Rejected with status {}: {}", response.status_code, response.body);
+            return database.fail_outbox_entry(entry.id, &error, None, unix_now()).await;
+        }
+        Ok(response) if response.status_code != 429 => {
+            tracing::info!("Delivered outbox entry {} to {} after {} attempt(s)", entry.id, entry.destination, entry.attempts);
+            return database.complete_outbox_entry(entry.id).await;
+        }
+        Ok(_) => "Rate limited".to_string(),
+        Err(e) => e.to_string(),
+    };
+
+    let now = unix_now();
+    if entry.attempts >= MAX_ATTEMPTS {
+        tracing::warn!("Giving up on outbox entry {} to {}: {}", entry.id, entry.destination, error);
+        return database.fail_outbox_entry(entry.id, &error, None, now).await;
+    }
+
+    // Back off the whole destination, not just this entry
+    let next_attempt_at = now + backoff_secs(entry.attempts);
+    tracing::info!(
+        "Outbox entry {} to {} failed (attempt {}), retrying in {}s: {}",
+        entry.id, entry.destination, entry.attempts, next_attempt_at - now, error
+    );
+    database.fail_outbox_entry(entry.id, &error, Some(next_attempt_at), now).await?;
+    database.defer_outbox_destination(&entry.destination, next_attempt_at).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_grows_exponentially_and_caps() {
+        assert_eq!(backoff_secs(1), 5);
+        assert_eq!(backoff_secs(2), 10);
+        assert_eq!(backoff_secs(5), 80);
+        assert_eq!(backoff_secs(MAX_ATTEMPTS), MAX_BACKOFF_SECS);
+        assert_eq!(backoff_secs(i32::MAX), MAX_BACKOFF_SECS);
+    }
+}
@@ -0,0 +1,231 @@
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use tokio::sync::RwLock;
+
+use crate::error::{BridgeError, Result};
+
+/// Which transports traffic to a destination server may use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransportPolicy {
+    /// Only over Mycelium; fails when there's no usable route.
+    MyceliumOnly,
+    /// Only over classic Matrix federation.
+    MatrixOnly,
+    /// Over Mycelium when there's a usable route, otherwise Matrix.
+    PreferMycelium,
+    /// No traffic at all.
+    Blocked,
+}
+
+impl TransportPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransportPolicy::MyceliumOnly => "mycelium_only",
+            TransportPolicy::MatrixOnly => "matrix_only",
+            TransportPolicy::PreferMycelium => "prefer_mycelium",
+            TransportPolicy::Blocked => "blocked",
+        }
+    }
+}
+
+impl FromStr for TransportPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().replace('-', "_").as_str() {
+            "mycelium_only" => Ok(TransportPolicy::MyceliumOnly),
+            "matrix_only" => Ok(TransportPolicy::MatrixOnly),
+            "prefer_mycelium" => Ok(TransportPolicy::PreferMycelium),
+            "blocked" => Ok(TransportPolicy::Blocked),
+            other => Err(format!("Unknown transport policy {}", other)),
+        }
+    }
+}
+
+/// A policy for every server matching `pattern`, a server name where `*`
+/// matches any run of characters and `?` exactly one.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PolicyRule {
+    pub pattern: String,
+    pub policy: TransportPolicy,
+}
+
+impl FromStr for PolicyRule {
+    type Err = String;
+
+    /// Parses the `pattern=policy` form used in configuration.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (pattern, policy) = s.split_once('=')
+            .ok_or_else(|| format!("Transport policy {} is not of the form pattern=policy", s))?;
+        let pattern = pattern.trim();
+        if pattern.is_empty() {
+            return Err(format!("Transport policy {} has an empty pattern", s));
+        }
+        Ok(Self { pattern: pattern.to_ascii_lowercase(), policy: policy.parse()? })
+    }
+}
+
+impl PolicyRule {
+    pub fn matches(&self, server_name: &str) -> bool {
+        glob_match(self.pattern.as_bytes(), server_name.to_ascii_lowercase().as_bytes())
+    }
+
+    /// Exact names beat globs, and longer globs beat shorter ones.
+    fn specificity(&self) -> (bool, usize) {
+        (!self.pattern.contains(['*', '?']), self.pattern.len())
+    }
+}
+
+pub(crate) fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    let mut backtrack = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                // Let the last `*` swallow one more character
+                Some((star, matched)) => {
+                    p = star + 1;
+                    n = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Per-destination transport policies. Rules come from configuration, and rules
+/// set through the bridge API override configured ones with the same pattern;
+/// the bridge persists those overrides. Servers no rule matches get the default.
+pub struct TransportPolicies {
+    default_policy: TransportPolicy,
+    configured: Vec<PolicyRule>,
+    overrides: RwLock<Vec<PolicyRule>>,
+}
+
+impl TransportPolicies {
+    pub fn new(default_policy: TransportPolicy, rules: Vec<PolicyRule>) -> Self {
+        Self { default_policy, configured: rules, overrides: RwLock::new(Vec::new()) }
+    }
+
+    pub fn default_policy(&self) -> TransportPolicy {
+        self.default_policy
+    }
+
+    /// The policy of the most specific rule matching `server_name`.
+    pub async fn policy_for(&self, server_name: &str) -> TransportPolicy {
+        self.rules().await
+            .iter()
+            .filter(|rule| rule.matches(server_name))
+            .max_by_key(|rule| rule.specificity())
+            .map_or(self.default_policy, |rule| rule.policy)
+    }
+
+    /// Every rule in effect: configured rules not overridden, then the overrides.
+    pub async fn rules(&self) -> Vec<PolicyRule> {
+        let overrides = self.overrides.read().await;
+        self.configured.iter()
+            .filter(|rule| !overrides.iter().any(|o| o.pattern == rule.pattern))
+            .chain(overrides.iter())
+            .cloned()
+            .collect()
+    }
+
+    /// Rules set at runtime rather than in configuration.
+    pub async fn overrides(&self) -> Vec<PolicyRule> {
+        self.overrides.read().await.clone()
+    }
+
+    /// Replace the overrides with `rules`, e.g. the ones stored in the database.
+    pub async fn replace_overrides(&self, rules: Vec<PolicyRule>) {
+        *self.overrides.write().await = rules;
+    }
+
+    /// Add an override, or change the policy of the override with the same
+    /// pattern. Returns the rule as stored, its pattern normalized.
+    pub async fn set(&self, pattern: &str, policy: TransportPolicy) -> Result<PolicyRule> {
+        let pattern = pattern.trim().to_ascii_lowercase();
+        if pattern.is_empty() {
+            return Err(BridgeError::InvalidRequest {
+                message: "Policy pattern must not be empty".to_string()
+            });
+        }
+
+        let mut rules = self.overrides.write().await;
+        match rules.iter_mut().find(|rule| rule.pattern == pattern) {
+            Some(rule) => rule.policy = policy,
+            None => rules.push(PolicyRule { pattern: pattern.clone(), policy }),
+        }
+        Ok(PolicyRule { pattern, policy })
+    }
+
+    /// Drop the override for `pattern`, bringing back the configured rule it
+    /// shadowed if any. Configured rules themselves can't be removed at runtime.
+    pub async fn remove(&self, pattern: &str) -> Result<()> {
+        let pattern = pattern.trim().to_ascii_lowercase();
+        let mut rules = self.overrides.write().await;
+        let before = rules.len();
+        rules.retain(|rule| rule.pattern != pattern);
+        if rules.len() < before {
+            return Ok(());
+        }
+
+        if self.configured.iter().any(|rule| rule.pattern == pattern) {
+            return Err(BridgeError::InvalidRequest {
+                message: format!("The policy for {} is set in the configuration file", pattern)
+            });
+        }
+        Err(BridgeError::NotFound)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_policy_matching() {
+        let rules = vec![
+            "*.example.com=matrix_only".parse().unwrap(),
+            "secure.example.com=mycelium-only".parse().unwrap(),
+            "spam?.net=blocked".parse().unwrap(),
+        ];
+        let policies = TransportPolicies::new(TransportPolicy::PreferMycelium, rules);
+
+        assert_eq!(policies.policy_for("chat.example.com").await, TransportPolicy::MatrixOnly);
+        assert_eq!(policies.policy_for("Secure.Example.com").await, TransportPolicy::MyceliumOnly);
+        assert_eq!(policies.policy_for("spam1.net").await, TransportPolicy::Blocked);
+        assert_eq!(policies.policy_for("spam12.net").await, TransportPolicy::PreferMycelium);
+        assert_eq!(policies.policy_for("example.com").await, TransportPolicy::PreferMycelium);
+
+        // Overrides shadow configured rules until they're removed
+        policies.set("*.example.com", TransportPolicy::Blocked).await.unwrap();
+        assert_eq!(policies.policy_for("chat.example.com").await, TransportPolicy::Blocked);
+        assert_eq!(policies.rules().await.len(), 3);
+        policies.remove("*.example.com").await.unwrap();
+        assert_eq!(policies.policy_for("chat.example.com").await, TransportPolicy::MatrixOnly);
+        assert!(matches!(policies.remove("*.example.com").await, Err(BridgeError::InvalidRequest { .. })));
+
+        let rule = policies.set(" Other.NET ", TransportPolicy::Blocked).await.unwrap();
+        assert_eq!(rule.pattern, "other.net");
+        assert_eq!(policies.policy_for("other.net").await, TransportPolicy::Blocked);
+        policies.replace_overrides(Vec::new()).await;
+        assert_eq!(policies.policy_for("other.net").await, TransportPolicy::PreferMycelium);
+        assert!(matches!(policies.remove("other.net").await, Err(BridgeError::NotFound)));
+
+        assert!("example.com".parse::<PolicyRule>().is_err());
+        assert!("example.com=sometimes".parse::<PolicyRule>().is_err());
+    }
+}
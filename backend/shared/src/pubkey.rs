@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+use std::net::Ipv6Addr;
+use tokio::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+use crate::error::{BridgeError, Result};
+
+/// An overlay address is derived from its node's key, so a resolved pair only
+/// goes stale when the node at the address is replaced.
+const RESOLVED_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How long an address the node doesn't know isn't asked about again.
+const UNKNOWN_TTL: Duration = Duration::from_secs(60);
+
+/// `key` as a lowercase hex Mycelium public key: 32 bytes, with or without `0x`.
+pub fn parse_public_key(key: &str) -> Option<String> {
+    let hex = key.trim();
+    let hex = hex.strip_prefix("0x").unwrap_or(hex);
+    (hex.len() == 64 && hex.chars().all(|c| c.is_ascii_hexdigit())).then(|| hex.to_ascii_lowercase())
+}
+
+/// `addr` as a Mycelium overlay address, which always lies in `400::/7`.
+pub fn parse_overlay_address(addr: &str) -> Option<Ipv6Addr> {
+    let addr = addr.trim();
+    let addr = addr.strip_prefix('[').and_then(|a| a.strip_suffix(']')).unwrap_or(addr);
+    addr.parse::<Ipv6Addr>().ok().filter(|ip| ip.segments()[0] & 0xfe00 == 0x0400)
+}
+
+#[derive(Debug, Clone)]
+struct CachedPubkey {
+    pubkey: Option<String>,
+    expires_at: Instant,
+}
+
+/// Turns route keys into Mycelium public keys: keys are validated, overlay
+/// addresses are looked up through the local node's `/api/v1/pubkey` API.
+#[derive(Default)]
+pub struct PubkeyResolver {
+    cache: Mutex<HashMap<Ipv6Addr, CachedPubkey>>,
+}
+
+impl PubkeyResolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub async fn resolve(&self, client: &reqwest::Client, mycelium_url: &str, key: &str) -> Result<String> {
+        if let Some(pubkey) = parse_public_key(key) {
+            return Ok(pubkey);
+        }
+        let Some(ip) = parse_overlay_address(key) else {
+            return Err(BridgeError::UnresolvableKey {
+                message: format!("{} is neither a Mycelium public key nor an overlay address", key)
+            });
+        };
+
+        if let Some(cached) = self.cache.lock().await.get(&ip) {
+            if cached.expires_at > Instant::now() {
+                return cached.pubkey.clone().ok_or_else(|| unknown_address(ip));
+            }
+        }
+
+        let pubkey = self.lookup(client, mycelium_url, ip).await?;
+        let ttl = if pubkey.is_some() { RESOLVED_TTL } else { UNKNOWN_TTL };
+        self.cache.lock().await.insert(ip, CachedPubkey {
+            pubkey: pubkey.clone(),
+            expires_at: Instant::now() + ttl,
+        });
+
+        pubkey.ok_or_else(|| unknown_address(ip))
+    }
+
+    async fn lookup(&self, client: &reqwest::Client, mycelium_url: &str, ip: Ipv6Addr) -> Result<Option<String>> {
+        let response = client
+            .get(format!("{}/api/v1/pubkey/{}", mycelium_url, ip))
+            .send()
+            .await
+            .map_err(|e| BridgeError::MyceliumApi {
+                message: format!("Public key lookup for {} failed: {}", ip, e)
+            })?;
+
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if !response.status().is_success() {
+            return Err(BridgeError::MyceliumApi {
+                message: format!("Public key lookup for {} returned {}", ip, response.status())
+            });
+        }
+
+        let body: serde_json::Value = response.json().await.map_err(|e| BridgeError::MyceliumApi {
+            message: format!("Invalid public key lookup response for {}: {}", ip, e)
+        })?;
+        let pubkey = body.get("NodePubKey")
+            .and_then(|v| v.as_str())
+            .and_then(parse_public_key)
+            .ok_or_else(|| BridgeError::MyceliumApi {
+                message: format!("Public key lookup for {} returned no valid key", ip)
+            })?;
+
+        Ok(Some(pubkey))
+    }
+}
+
+fn unknown_address(ip: Ipv6Addr) -> BridgeError {
+    BridgeError::UnresolvableKey {
+        message: format!("The Mycelium node knows no peer at {}", ip)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_key_and_address_validation() {
+        let key = "AB".repeat(32);
+        assert_eq!(parse_public_key(&key), Some("ab".repeat(32)));
+        assert_eq!(parse_public_key(&format!("0x{}", key)), Some("ab".repeat(32)));
+        assert_eq!(parse_public_key(&"ab".repeat(31)), None);
+        assert_eq!(parse_public_key(&"zz".repeat(32)), None);
+
+        assert!(parse_overlay_address("400:8f3a:8d0e:3503:db8e:6a02:2e9:83dd").is_some());
+        assert!(parse_overlay_address("[5ff:1::1]").is_some());
+        assert!(parse_overlay_address("2001:db8::1").is_none());
+        assert!(parse_overlay_address("test_key_123").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_overlay_address_resolution() {
+        let lookups = Arc::new(AtomicU32::new(0));
+        let counter = lookups.clone();
+        let app = axum::Router::new().route("/api/v1/pubkey/:ip", axum::routing::get(move |axum::extract::Path(ip): axum::extract::Path<String>| {
+            counter.fetch_add(1, Ordering::Relaxed);
+            async move {
+                match ip.as_str() {
+                    "400::1" => Ok(axum::Json(serde_json::json!({"NodePubKey": "cd".repeat(32)}))),
+                    _ => Err(axum::http::StatusCode::NOT_FOUND),
+                }
+            }
+        }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let resolver = PubkeyResolver::new();
+        let client = reqwest::Client::new();
+        assert_eq!(resolver.resolve(&client, &url, "400::1").await.unwrap(), "cd".repeat(32));
+        assert_eq!(resolver.resolve(&client, &url, "400::1").await.unwrap(), "cd".repeat(32));
+        assert!(matches!(resolver.resolve(&client, &url, "400::2").await, Err(BridgeError::UnresolvableKey { .. })));
+        assert!(matches!(resolver.resolve(&client, &url, "400::2").await, Err(BridgeError::UnresolvableKey { .. })));
+        assert!(matches!(resolver.resolve(&client, &url, "not-a-key").await, Err(BridgeError::UnresolvableKey { .. })));
+        assert_eq!(lookups.load(Ordering::Relaxed), 2);
+    }
+}
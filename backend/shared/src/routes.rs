@@ -0,0 +1,152 @@
+use std::sync::Arc;
+use tokio::sync::watch;
+use tokio::time::Duration;
+
+use crate::bridge::MatrixMyceliumBridge;
+use crate::database::TRANSPORT_POLICIES_CHANNEL;
+use crate::error::{BridgeError, Result};
+use crate::types::RouteTable;
+
+const MIN_RECONNECT_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Keep the bridge's route cache and transport policies in step with the
+/// database as other instances change them, until `shutdown` flips to true.
+pub async fn run_route_sync(bridge: Arc<MatrixMyceliumBridge>, mut shutdown: watch::Receiver<bool>) {
+    let mut backoff = MIN_RECONNECT_BACKOFF;
+
+    tracing::info!("Route sync started");
+
+    while !*shutdown.borrow() {
+        let result = tokio::select! {
+            result = follow_route_changes(&bridge) => result,
+            _ = shutdown.changed() => break,
+        };
+
+        if let Err(e) = result {
+            tracing::warn!("Route change listener failed: {}", e);
+            tokio::select! {
+                _ = tokio::time::sleep(backoff) => {},
+                _ = shutdown.changed() => break,
+            }
+            backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
+        } else {
+            backoff = MIN_RECONNECT_BACKOFF;
+        }
+    }
+
+    tracing::info!("Route sync stopped");
+}
+
+/// Listen for route and policy changes until the connection drops. Notifications
+/// sent while disconnected are lost, so every (re)connect reloads both tables.
+async fn follow_route_changes(bridge: &MatrixMyceliumBridge) -> Result<()> {
+    let mut listener = bridge.database()?.listen_for_route_changes().await?;
+
+    let count = bridge.load_federation_routes().await?;
+    tracing::debug!("Loaded {} federation routes", count);
+    let count = bridge.load_transport_policies().await?;
+    tracing::debug!("Loaded {} stored transport policies", count);
+
+    while let Some(notification) = listener.try_recv().await? {
+        // Policies are few, so any change reloads them all
+        if notification.channel() == TRANSPORT_POLICIES_CHANNEL {
+            if let Err(e) = bridge.load_transport_policies().await {
+                tracing::warn!("Failed to reload transport policies: {}", e);
+            }
+            continue;
+        }
+
+        let server_name = notification.payload();
+        if let Err(e) = bridge.refresh_federation_route(server_name).await {
+            tracing::warn!("Failed to refresh route for {}: {}", server_name, e);
+        }
+    }
+
+    tracing::warn!("Route change listener disconnected, reloading routes");
+    Ok(())
+}
+
+/// Serialization of route tables for import and export.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteTableFormat {
+    Json,
+    Toml,
+}
+
+impl std::str::FromStr for RouteTableFormat {
+    type Err = BridgeError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "json" => Ok(RouteTableFormat::Json),
+            "toml" => Ok(RouteTableFormat::Toml),
+            other => Err(BridgeError::InvalidRequest {
+                message: format!("Unknown route table format {}, expected json or toml", other)
+            }),
+        }
+    }
+}
+
+impl RouteTableFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            RouteTableFormat::Json => "application/json",
+            RouteTableFormat::Toml => "application/toml",
+        }
+    }
+
+    pub fn parse(self, body: &str) -> Result<RouteTable> {
+        let parsed = match self {
+            RouteTableFormat::Json => serde_json::from_str(body).map_err(|e| e.to_string()),
+            RouteTableFormat::Toml => toml::from_str(body).map_err(|e| e.to_string()),
+        };
+        parsed.map_err(|e| BridgeError::InvalidRequest {
+            message: format!("Invalid route table: {}", e)
+        })
+    }
+
+    pub fn render(self, table: &RouteTable) -> Result<String> {
+        match self {
+            RouteTableFormat::Json => Ok(serde_json::to_string_pretty(table)?),
+            RouteTableFormat::Toml => toml::to_string(table).map_err(|e| BridgeError::Serde {
+                message: format!("Failed to render route table: {}", e)
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{MyceliumEndpoint, RouteSpec};
+
+    #[test]
+    fn test_route_table_round_trip() {
+        let table = RouteTable {
+            routes: vec![RouteSpec {
+                server_name: "remote.example.com".to_string(),
+                mycelium_key: None,
+                endpoints: Some(vec![
+                    MyceliumEndpoint { mycelium_key: "aa".repeat(32), priority: 0, weight: 3, expires_at: None },
+                    MyceliumEndpoint::new("bb".repeat(32)),
+                ]),
+            }],
+        };
+
+        for format in [RouteTableFormat::Json, RouteTableFormat::Toml] {
+            let rendered = format.render(&table).unwrap();
+            assert_eq!(format.parse(&rendered).unwrap(), table);
+        }
+
+        let handwritten = format!(
+            "[[routes]]\nserver_name = \"other.example.com\"\nmycelium_key = \"{}\"\n",
+            "cc".repeat(32)
+        );
+        let parsed = RouteTableFormat::Toml.parse(&handwritten).unwrap();
+        assert_eq!(parsed.routes[0].clone().into_endpoints().unwrap(), vec![MyceliumEndpoint::new("cc".repeat(32))]);
+
+        assert!("yaml".parse::<RouteTableFormat>().is_err());
+        assert!(RouteTableFormat::Json.parse("{\"routes\": 1}").is_err());
+    }
+}
@@ -1,7 +1,9 @@
 use axum::{
-    extract::{Path, Query, State},
-    http::StatusCode,
-    response::Json,
+    body::Body,
+    extract::{Extension, Path, Query, Request, State},
+    http::{header::{AUTHORIZATION, CONTENT_TYPE}, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Json, Response},
     routing::{delete, get, post, put},
     Router,
 };
@@ -13,10 +15,15 @@ use tower_http::trace::TraceLayer;
 
 use crate::bridge::MatrixMyceliumBridge;
 use crate::config::BridgeConfig;
-use crate::error::Result;
+use crate::error::{BridgeError, Result};
+use crate::routes::RouteTableFormat;
 use crate::types::*;
 
-pub async fn start_bridge_server(bridge: MatrixMyceliumBridge) -> Result<()> {
+/// Largest inbound federation request body we'll buffer for signature checks.
+const MAX_FEDERATION_BODY_SIZE: usize = 16 * 1024 * 1024;
+
+pub async fn start_bridge_server(bridge: impl Into<std::sync::Arc<MatrixMyceliumBridge>>) -> Result<()> {
+    let bridge = bridge.into();
     let config = bridge.config.clone();
 
     let app = create_router(bridge);
@@ -39,13 +46,29 @@ pub async fn start_bridge_server(bridge: MatrixMyceliumBridge) -> Result<()> {
     Ok(())
 }
 
-fn create_router(bridge: MatrixMyceliumBridge) -> Router {
+fn create_router(bridge_state: std::sync::Arc<MatrixMyceliumBridge>) -> Router {
     let cors = CorsLayer::new()
         .allow_origin(Any)
         .allow_methods(Any)
         .allow_headers(Any);
 
-    let bridge_state = std::sync::Arc::new(bridge);
+    // Matrix Server-Server API endpoints, all authenticated with X-Matrix signatures
+    let federation_routes = Router::new()
+        .route("/_matrix/federation/v1/send/:txn_id", put(send_pdu))
+        .route("/_matrix/federation/v1/state/:room_id", get(get_room_state))
+        .route("/_matrix/federation/v1/state_ids/:room_id", get(get_room_state_ids))
+        .route("/_matrix/federation/v1/backfill/:room_id", get(backfill_room))
+        .route("/_matrix/federation/v1/get_missing_events/:room_id", post(get_missing_events))
+        .route("/_matrix/federation/v1/query/:query_type", get(query_federation))
+        .route("/_matrix/federation/v1/user/devices/:user_id", get(get_user_devices))
+        .route("/_matrix/federation/v1/make_join/:room_id/:user_id", get(make_join))
+        .route("/_matrix/federation/v1/send_join/:room_id/:event_id", put(send_join))
+        .route("/_matrix/federation/v1/make_leave/:room_id/:user_id", get(make_leave))
+        .route("/_matrix/federation/v1/send_leave/:room_id/:event_id", put(send_leave))
+        .route("/_matrix/federation/v1/invite/:room_id/:event_id", put(send_invite))
+        .route("/_matrix/federation/v1/make_knock/:room_id/:user_id", get(make_knock))
+        .route("/_matrix/federation/v1/send_knock/:room_id/:event_id", put(send_knock))
+        .route_layer(middleware::from_fn_with_state(bridge_state.clone(), verify_federation_request));
 
     Router::new()
         .route("/health", get(health_check))
@@ -53,28 +76,27 @@ fn create_router(bridge: MatrixMyceliumBridge) -> Router {
         .route("/api/v1/bridge/federation/send", post(send_federation_message))
         .route("/api/v1/bridge/events/translate/matrix", post(translate_matrix_event))
         .route("/api/v1/bridge/events/translate/mycelium", post(translate_mycelium_event))
+        .route("/api/v1/bridge/events/send", post(send_room_event))
         .route("/api/v1/bridge/servers", get(get_federation_servers))
         .route("/api/v1/bridge/routes", get(get_federation_routes))
         .route("/api/v1/bridge/routes", post(add_federation_route))
-        .route("/api/v1/bridge/routes/:server_name", delete(remove_federation_route))
+        .route("/api/v1/bridge/routes/export", get(export_federation_routes))
+        .route("/api/v1/bridge/routes/import", post(import_federation_routes))
+        .route("/api/v1/bridge/routes/:server_name", delete(remove_federation_route).patch(update_federation_route))
+        .route("/api/v1/bridge/routes/:server_name/endpoints", put(set_federation_route_endpoints))
+        .route("/api/v1/bridge/policies", get(list_transport_policies))
+        .route("/api/v1/bridge/policies/:pattern", put(set_transport_policy))
+        .route("/api/v1/bridge/policies/:pattern", delete(remove_transport_policy))
         .route("/api/v1/bridge/mycelium/incoming", post(receive_mycelium_message))
+        .route("/api/v1/bridge/outbox", get(list_outbox_entries))
+        .route("/api/v1/bridge/outbox/:id", delete(drop_outbox_entry))
+        .route("/api/v1/bridge/outbox/:id/requeue", post(requeue_outbox_entry))
         .route("/api/v1/bridge/test/federation/:server_name", post(test_federation))
         .route("/api/v1/bridge/test/end-to-end", post(run_end_to_end_test))
         .route("/api/v1/bridge/test/p2p-benefits", get(analyze_p2p_benefits))
-        // Matrix Server-Server API endpoints
-        .route("/_matrix/federation/v1/send/:txn_id", put(send_pdu))
-        .route("/_matrix/federation/v1/state/:room_id", get(get_room_state))
-        .route("/_matrix/federation/v1/state_ids/:room_id", get(get_room_state_ids))
-        .route("/_matrix/federation/v1/backfill/:room_id", get(backfill_room))
-        .route("/_matrix/federation/v1/query/:query_type", get(query_federation))
-        .route("/_matrix/federation/v1/user/devices/:user_id", get(get_user_devices))
-        .route("/_matrix/federation/v1/make_join/:room_id/:user_id", get(make_join))
-        .route("/_matrix/federation/v1/send_join/:room_id/:event_id", put(send_join))
-        .route("/_matrix/federation/v1/make_leave/:room_id/:user_id", get(make_leave))
-        .route("/_matrix/federation/v1/send_leave/:room_id/:event_id", put(send_leave))
-        .route("/_matrix/federation/v1/invite/:room_id/:event_id", put(send_invite))
-        .route("/_matrix/federation/v1/make_knock/:room_id/:user_id", get(make_knock))
-        .route("/_matrix/federation/v1/send_knock/:room_id/:event_id", put(send_knock))
+        // Matrix key server, fetched unauthenticated by remote servers
+        .route("/_matrix/key/v2/server", get(get_server_keys))
+        .merge(federation_routes)
         .layer(cors)
         .layer(TraceLayer::new_for_http())
         .with_state(bridge_state)
@@ -102,9 +124,22 @@ async fn send_federation_message(
 async fn translate_matrix_event(
     axum::extract::State(bridge): axum::extract::State<std::sync::Arc<MatrixMyceliumBridge>>,
     axum::extract::Json(event): axum::extract::Json<crate::types::MatrixEvent>,
-) -> Result<axum::response::Json<crate::types::MyceliumFederationMessage>> {
-    let mycelium_msg = bridge.translate_matrix_to_mycelium(event).await?;
-    Ok(axum::response::Json(mycelium_msg))
+) -> Result<axum::response::Json<Vec<crate::types::MyceliumFederationMessage>>> {
+    let messages = bridge.translate_matrix_to_mycelium(event).await?;
+    Ok(axum::response::Json(messages))
+}
+
+async fn send_room_event(
+    State(bridge): State<std::sync::Arc<MatrixMyceliumBridge>>,
+    Json(event): Json<MatrixEvent>,
+) -> Result<Json<serde_json::Value>> {
+    let results = bridge.send_event_to_room(event).await?;
+    let delivered = results.iter().filter(|result| result.status == "delivered").count();
+    Ok(Json(json!({
+        "destinations": results,
+        "delivered": delivered,
+        "count": results.len()
+    })))
 }
 
 async fn translate_mycelium_event(
@@ -140,32 +175,62 @@ async fn get_federation_servers(
 
 async fn get_federation_routes(
     State(bridge): State<std::sync::Arc<MatrixMyceliumBridge>>,
-) -> Result<Json<serde_json::Value>> {
-    let routes = bridge.get_all_federation_routes().await;
-    Ok(Json(serde_json::json!({
-        "routes": routes,
-        "count": routes.len()
-    })))
+    Query(query): Query<RouteListQuery>,
+) -> Result<Json<RouteList>> {
+    Ok(Json(bridge.list_federation_routes(&query).await))
 }
 
 async fn add_federation_route(
     State(bridge): State<std::sync::Arc<MatrixMyceliumBridge>>,
-    Json(route_data): Json<serde_json::Value>,
+    Json(spec): Json<RouteSpec>,
 ) -> Result<StatusCode> {
-    let server_name = route_data.get("server_name")
-        .and_then(|v| v.as_str())
-        .ok_or_else(|| crate::error::BridgeError::InvalidRequest {
-            message: "server_name is required".to_string()
-        })?;
+    let server_name = spec.server_name.clone();
+    bridge.set_federation_route_endpoints(server_name, spec.into_endpoints()?).await?;
+    Ok(StatusCode::CREATED)
+}
 
-    let mycelium_key = route_data.get("mycelium_key")
-        .and_then(|v| v.as_str())
-        .ok_or_else(|| crate::error::BridgeError::InvalidRequest {
-            message: "mycelium_key is required".to_string()
-        })?;
+async fn update_federation_route(
+    State(bridge): State<std::sync::Arc<MatrixMyceliumBridge>>,
+    Path(server_name): Path<String>,
+    Json(update): Json<RouteUpdate>,
+) -> Result<Json<RouteView>> {
+    let route = bridge.update_federation_route(&server_name, update).await?;
+    let circuit = bridge.circuit_status(&server_name).await;
+    Ok(Json(RouteView { route, circuit }))
+}
 
-    bridge.add_federation_route(server_name.to_string(), mycelium_key.to_string()).await?;
-    Ok(StatusCode::CREATED)
+async fn export_federation_routes(
+    State(bridge): State<std::sync::Arc<MatrixMyceliumBridge>>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Response> {
+    let format = route_table_format(&params)?;
+    let body = format.render(&bridge.export_route_table().await)?;
+    Ok(([(CONTENT_TYPE, format.content_type())], body).into_response())
+}
+
+async fn import_federation_routes(
+    State(bridge): State<std::sync::Arc<MatrixMyceliumBridge>>,
+    Query(params): Query<HashMap<String, String>>,
+    body: String,
+) -> Result<Json<RouteImportResult>> {
+    let format = route_table_format(&params)?;
+    let replace = params.get("replace").is_some_and(|replace| replace == "true");
+    let table = format.parse(&body)?;
+    Ok(Json(bridge.import_route_table(table, replace).await?))
+}
+
+/// The `?format=` of a route import or export, JSON unless asked otherwise.
+fn route_table_format(params: &HashMap<String, String>) -> Result<RouteTableFormat> {
+    params.get("format").map_or(Ok(RouteTableFormat::Json), |format| format.parse())
+}
+
+async fn set_federation_route_endpoints(
+    State(bridge): State<std::sync::Arc<MatrixMyceliumBridge>>,
+    Path(server_name): Path<String>,
+    Json(endpoints): Json<Vec<MyceliumEndpoint>>,
+) -> Result<StatusCode> {
+    bridge.set_federation_route_endpoints(server_name, endpoints).await?;
+    Ok(StatusCode::NO_CONTENT)
 }
 
 async fn remove_federation_route(
@@ -176,6 +241,52 @@ async fn remove_federation_route(
     Ok(StatusCode::NO_CONTENT)
 }
 
+async fn list_transport_policies(
+    State(bridge): State<std::sync::Arc<MatrixMyceliumBridge>>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<serde_json::Value>> {
+    let policies = bridge.transport_policies();
+
+    // ?server= answers which policy applies to one server
+    if let Some(server) = params.get("server") {
+        return Ok(Json(json!({
+            "server": server,
+            "policy": policies.policy_for(server).await
+        })));
+    }
+
+    let rules = policies.rules().await;
+    Ok(Json(json!({
+        "default_policy": policies.default_policy(),
+        "rules": rules,
+        "count": rules.len()
+    })))
+}
+
+async fn set_transport_policy(
+    State(bridge): State<std::sync::Arc<MatrixMyceliumBridge>>,
+    Path(pattern): Path<String>,
+    Json(body): Json<serde_json::Value>,
+) -> Result<StatusCode> {
+    let policy = body.get("policy")
+        .cloned()
+        .and_then(|v| serde_json::from_value::<crate::policy::TransportPolicy>(v).ok())
+        .ok_or_else(|| BridgeError::InvalidRequest {
+            message: "policy must be one of mycelium_only, matrix_only, prefer_mycelium, blocked".to_string()
+        })?;
+
+    bridge.set_transport_policy(&pattern, policy).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn remove_transport_policy(
+    State(bridge): State<std::sync::Arc<MatrixMyceliumBridge>>,
+    Path(pattern): Path<String>,
+) -> Result<StatusCode> {
+    bridge.remove_transport_policy(&pattern).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
 async fn receive_mycelium_message(
     State(bridge): State<std::sync::Arc<MatrixMyceliumBridge>>,
     Json(mycelium_msg): Json<crate::types::MyceliumFederationMessage>,
@@ -186,6 +297,50 @@ async fn receive_mycelium_message(
     Ok(StatusCode::NO_CONTENT)
 }
 
+async fn list_outbox_entries(
+    State(bridge): State<std::sync::Arc<MatrixMyceliumBridge>>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<serde_json::Value>> {
+    let limit = params.get("limit")
+        .and_then(|v| v.parse::<i64>().ok())
+        .unwrap_or(100)
+        .clamp(1, 1000);
+    let offset = params.get("offset")
+        .and_then(|v| v.parse::<i64>().ok())
+        .unwrap_or(0)
+        .max(0);
+
+    let entries = bridge.list_outbox_entries(
+        params.get("state").map(String::as_str),
+        params.get("destination").map(String::as_str),
+        limit,
+        offset,
+    ).await?;
+
+    Ok(Json(json!({
+        "entries": entries,
+        "count": entries.len(),
+        "limit": limit,
+        "offset": offset
+    })))
+}
+
+async fn requeue_outbox_entry(
+    State(bridge): State<std::sync::Arc<MatrixMyceliumBridge>>,
+    Path(id): Path<i64>,
+) -> Result<StatusCode> {
+    bridge.requeue_outbox_entry(id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn drop_outbox_entry(
+    State(bridge): State<std::sync::Arc<MatrixMyceliumBridge>>,
+    Path(id): Path<i64>,
+) -> Result<StatusCode> {
+    bridge.drop_outbox_entry(id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
 async fn test_federation(
     State(bridge): State<std::sync::Arc<MatrixMyceliumBridge>>,
     Path(server_name): Path<String>,
@@ -271,6 +426,7 @@ async fn run_end_to_end_test(
                 "msgtype": "m.text"
             }),
             state_key: None,
+            ..crate::types::MatrixEvent::default()
         };
 
         // Test Matrix routing
@@ -429,7 +585,12 @@ async fn analyze_p2p_benefits(
                 "server": route.destination_server,
                 "mycelium_key": route.mycelium_key,
                 "latency_ms": route.latency_ms,
-                "last_success": route.last_successful
+                "last_success": route.last_successful,
+                "healthy": route.healthy,
+                "consecutive_failures": route.consecutive_failures,
+                "matrix_latency_ms": route.matrix_latency_ms,
+                "last_probed": route.last_probed,
+                "endpoints": route.endpoints
             })
         }).collect::<Vec<_>>(),
         "recommendations": {
@@ -450,75 +611,146 @@ async fn analyze_p2p_benefits(
 
 // Matrix Server-Server API Handlers
 
-async fn send_pdu(
+/// Reject federation requests without a valid X-Matrix signature. The verified
+/// origin is passed on to handlers as a `FederationOrigin` extension.
+async fn verify_federation_request(
+    State(bridge): State<std::sync::Arc<MatrixMyceliumBridge>>,
+    request: Request,
+    next: Next,
+) -> Result<Response> {
+    let (mut parts, body) = request.into_parts();
+
+    let bytes = axum::body::to_bytes(body, MAX_FEDERATION_BODY_SIZE).await
+        .map_err(|e| BridgeError::InvalidRequest {
+            message: format!("Failed to read request body: {}", e)
+        })?;
+    let content: Option<serde_json::Value> = if bytes.is_empty() {
+        None
+    } else {
+        Some(serde_json::from_slice(&bytes)?)
+    };
+
+    let authorizations: Vec<&str> = parts.headers.get_all(AUTHORIZATION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect();
+    let uri = parts.uri.path_and_query()
+        .map(|p| p.as_str())
+        .unwrap_or_else(|| parts.uri.path());
+
+    let origin = bridge
+        .verify_federation_auth(parts.method.as_str(), uri, &authorizations, content.as_ref())
+        .await
+        .inspect_err(|e| tracing::warn!("Rejected federation request to {}: {}", uri, e))?;
+
+    parts.extensions.insert(FederationOrigin(origin));
+    Ok(next.run(Request::from_parts(parts, Body::from(bytes))).await)
+}
+
+async fn get_server_keys(
     State(bridge): State<std::sync::Arc<MatrixMyceliumBridge>>,
-    Path(txn_id): Path<String>,
-    Json(pdus): Json<Vec<serde_json::Value>>,
 ) -> Result<Json<serde_json::Value>> {
-    tracing::info!("Received PDUs for transaction {}", txn_id);
-
-    // Process each PDU (Persistent Data Unit)
-    for pdu in &pdus {
-        if let Some(room_id) = pdu.get("room_id").and_then(|v| v.as_str()) {
-            let matrix_event: MatrixEvent = serde_json::from_value(pdu.clone())
-                .map_err(|e| crate::error::BridgeError::Serde {
-                    message: format!("Failed to parse PDU: {}", e)
-                })?;
-
-            // Route through Mycelium if available
-            let _ = bridge.translate_matrix_to_mycelium(matrix_event).await;
-        }
-    }
+    Ok(Json(bridge.server_keys()?))
+}
 
-    // Return empty object as per Matrix spec
-    Ok(Json(json!({})))
+async fn send_pdu(
+    State(bridge): State<std::sync::Arc<MatrixMyceliumBridge>>,
+    Extension(FederationOrigin(origin)): Extension<FederationOrigin>,
+    Path(txn_id): Path<String>,
+    Json(transaction): Json<Transaction>,
+) -> Result<Json<TransactionResponse>> {
+    tracing::info!(
+        "Received transaction {} from {} with {} PDUs and {} EDUs",
+        txn_id, origin, transaction.pdus.len(), transaction.edus.len()
+    );
+
+    let response = bridge.process_transaction(&origin, &txn_id, transaction).await?;
+    Ok(Json(response))
 }
 
 async fn get_room_state(
     State(bridge): State<std::sync::Arc<MatrixMyceliumBridge>>,
+    Extension(FederationOrigin(origin)): Extension<FederationOrigin>,
     Path(room_id): Path<String>,
     Query(params): Query<HashMap<String, String>>,
 ) -> Result<Json<serde_json::Value>> {
     tracing::info!("Getting room state for {}", room_id);
 
-    // Extract event IDs from query parameters
-    let event_ids: Vec<String> = params.get("event_id")
-        .map(|ids| ids.split(',').map(|s| s.to_string()).collect())
-        .unwrap_or_default();
-
-    // TODO: Implement actual room state retrieval
-    // For now, return empty state
+    let snapshot = bridge.room_state_at(&origin, &room_id, required_event_id(&params)?).await?;
     Ok(Json(json!({
-        "pdus": [],
-        "auth_chain": []
+        "pdus": snapshot.state.iter().map(MatrixEvent::to_pdu).collect::<Vec<_>>(),
+        "auth_chain": snapshot.auth_chain.iter().map(MatrixEvent::to_pdu).collect::<Vec<_>>()
     })))
 }
 
 async fn get_room_state_ids(
     State(bridge): State<std::sync::Arc<MatrixMyceliumBridge>>,
+    Extension(FederationOrigin(origin)): Extension<FederationOrigin>,
     Path(room_id): Path<String>,
     Query(params): Query<HashMap<String, String>>,
 ) -> Result<Json<serde_json::Value>> {
     tracing::info!("Getting room state IDs for {}", room_id);
 
-    // TODO: Implement actual state ID retrieval
+    let snapshot = bridge.room_state_at(&origin, &room_id, required_event_id(&params)?).await?;
     Ok(Json(json!({
-        "pdu_ids": [],
-        "auth_chain_ids": []
+        "pdu_ids": snapshot.state.iter().map(|event| &event.event_id).collect::<Vec<_>>(),
+        "auth_chain_ids": snapshot.auth_chain.iter().map(|event| &event.event_id).collect::<Vec<_>>()
     })))
 }
 
+/// The `?event_id=` the state endpoints compute state at.
+fn required_event_id(params: &HashMap<String, String>) -> Result<&str> {
+    params.get("event_id")
+        .map(String::as_str)
+        .ok_or_else(|| BridgeError::InvalidRequest {
+            message: "event_id is required".to_string()
+        })
+}
+
 async fn backfill_room(
     State(bridge): State<std::sync::Arc<MatrixMyceliumBridge>>,
+    Extension(FederationOrigin(origin)): Extension<FederationOrigin>,
     Path(room_id): Path<String>,
-    Query(params): Query<HashMap<String, String>>,
+    Query(params): Query<Vec<(String, String)>>,
+) -> Result<Json<serde_json::Value>> {
+    tracing::info!("Backfilling room {} for {}", room_id, origin);
+
+    // `v` repeats, once per event to walk back from
+    let from: Vec<String> = params.iter()
+        .filter(|(key, _)| key == "v")
+        .map(|(_, value)| value.clone())
+        .collect();
+    if from.is_empty() {
+        return Err(BridgeError::InvalidRequest {
+            message: "At least one v is required".to_string()
+        });
+    }
+    let limit = match params.iter().find(|(key, _)| key == "limit") {
+        Some((_, limit)) => limit.parse().map_err(|_| BridgeError::InvalidRequest {
+            message: format!("Invalid limit {}", limit)
+        })?,
+        None => 10,
+    };
+
+    let events = bridge.backfill(&origin, &room_id, &from, limit).await?;
+    Ok(Json(json!({
+        "origin": bridge.config.server_name,
+        "origin_server_ts": chrono::Utc::now().timestamp_millis(),
+        "pdus": events.iter().map(MatrixEvent::to_pdu).collect::<Vec<_>>()
+    })))
+}
+
+async fn get_missing_events(
+    State(bridge): State<std::sync::Arc<MatrixMyceliumBridge>>,
+    Extension(FederationOrigin(origin)): Extension<FederationOrigin>,
+    Path(room_id): Path<String>,
+    Json(request): Json<MissingEventsRequest>,
 ) -> Result<Json<serde_json::Value>> {
-    tracing::info!("Backfilling room {}", room_id);
+    tracing::info!("Getting missing events in {} for {}", room_id, origin);
 
-    // TODO: Implement backfill logic
+    let events = bridge.missing_events(&origin, &room_id, request).await?;
     Ok(Json(json!({
-        "pdus": [],
-        "auth_chain": []
+        "events": events.iter().map(MatrixEvent::to_pdu).collect::<Vec<_>>()
     })))
 }
 
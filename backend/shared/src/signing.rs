@@ -0,0 +1,674 @@
+use base64::engine::general_purpose::{STANDARD_NO_PAD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use std::path::Path;
+
+use crate::error::{BridgeError, Result};
+
+/// How long remote servers may cache our published verify keys.
+const KEY_VALIDITY_MS: u64 = 24 * 60 * 60 * 1000;
+
+/// Largest integer allowed in Matrix canonical JSON (2^53 - 1).
+const CANONICAL_JSON_MAX_INT: i64 = (1 << 53) - 1;
+
+/// Top-level PDU keys the redaction algorithm keeps in every room version.
+const REDACTION_KEPT_KEYS: &[&str] = &[
+    "event_id", "type", "room_id", "sender", "state_key", "content", "hashes", "signatures",
+    "depth", "prev_events", "auth_events", "origin_server_ts",
+];
+
+/// Top-level PDU keys redaction also keeps before room version 11.
+const PRE_V11_REDACTION_KEPT_KEYS: &[&str] = &["prev_state", "origin", "membership"];
+
+/// Newest room version whose event format and redaction rules we implement.
+const NEWEST_ROOM_VERSION: u8 = 11;
+
+/// The bridge's ed25519 server signing key, stored on disk in the same
+/// `ed25519 <version> <unpadded base64 seed>` format Synapse uses.
+pub struct ServerSigningKey {
+    version: String,
+    key: SigningKey,
+}
+
+impl ServerSigningKey {
+    /// Generate a fresh key with a random `a_XXXX` version.
+    pub fn generate() -> Self {
+        let mut rng = rand::thread_rng();
+        let version = format!(
+            "a_{}",
+            (0..4).map(|_| rng.sample(rand::distributions::Alphanumeric) as char).collect::<String>()
+        );
+
+        Self {
+            version,
+            key: SigningKey::generate(&mut rand::rngs::OsRng),
+        }
+    }
+
+    /// Load the key at `path`, generating and persisting a new one if the file doesn't exist.
+    pub fn load_or_generate(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+
+        if path.exists() {
+            let contents = std::fs::read_to_string(path).map_err(|e| BridgeError::Config {
+                message: format!("Failed to read signing key {}: {}", path.display(), e)
+            })?;
+            return Self::parse(&contents);
+        }
+
+        let key = Self::generate();
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent).map_err(|e| BridgeError::Config {
+                message: format!("Failed to create signing key directory {}: {}", parent.display(), e)
+            })?;
+        }
+        std::fs::write(path, key.to_file_contents()).map_err(|e| BridgeError::Config {
+            message: format!("Failed to write signing key {}: {}", path.display(), e)
+        })?;
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let _ = std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600));
+        }
+
+        tracing::info!("Generated new server signing key {} at {}", key.key_id(), path.display());
+        Ok(key)
+    }
+
+    /// Parse a key from its on-disk representation.
+    pub fn parse(contents: &str) -> Result<Self> {
+        let invalid = |reason: &str| BridgeError::Config {
+            message: format!("Invalid signing key: {}", reason)
+        };
+
+        let mut parts = contents.split_whitespace();
+        if parts.next() != Some("ed25519") {
+            return Err(invalid("only ed25519 keys are supported"));
+        }
+        let version = parts.next().ok_or_else(|| invalid("missing key version"))?;
+        let seed = parts.next().ok_or_else(|| invalid("missing key material"))?;
+
+        let seed: [u8; 32] = STANDARD_NO_PAD
+            .decode(seed.trim_end_matches('='))
+            .map_err(|e| invalid(&e.to_string()))?
+            .try_into()
+            .map_err(|_| invalid("seed must be 32 bytes"))?;
+
+        Ok(Self {
+            version: version.to_string(),
+            key: SigningKey::from_bytes(&seed),
+        })
+    }
+
+    pub fn to_file_contents(&self) -> String {
+        format!("ed25519 {} {}\n", self.version, STANDARD_NO_PAD.encode(self.key.to_bytes()))
+    }
+
+    /// Key identifier as used in signatures, e.g. `ed25519:a_AbCd`.
+    pub fn key_id(&self) -> String {
+        format!("ed25519:{}", self.version)
+    }
+
+    pub fn verifying_key(&self) -> VerifyingKey {
+        self.key.verifying_key()
+    }
+
+    /// Unpadded base64 of the public key, as published in `verify_keys`.
+    pub fn public_key_base64(&self) -> String {
+        STANDARD_NO_PAD.encode(self.verifying_key().as_bytes())
+    }
+
+    /// Sign raw bytes, returning the unpadded base64 signature.
+    pub fn sign_bytes(&self, bytes: &[u8]) -> String {
+        STANDARD_NO_PAD.encode(self.key.sign(bytes).to_bytes())
+    }
+
+    /// Sign a JSON object per the Matrix spec: `signatures` and `unsigned` are
+    /// removed before computing the canonical form.
+    pub fn sign_json(&self, value: &serde_json::Value) -> Result<String> {
+        let mut value = value.clone();
+        if let Some(object) = value.as_object_mut() {
+            object.remove("signatures");
+            object.remove("unsigned");
+        }
+
+        Ok(self.sign_bytes(canonical_json(&value)?.as_bytes()))
+    }
+
+    /// Sign a JSON object and merge the signature into its `signatures` field.
+    pub fn add_signature(&self, value: &mut serde_json::Value, server_name: &str) -> Result<()> {
+        let signature = self.sign_json(value)?;
+
+        let object = value.as_object_mut().ok_or_else(|| BridgeError::InvalidRequest {
+            message: "Only JSON objects can be signed".to_string()
+        })?;
+        let signatures = object
+            .entry("signatures")
+            .or_insert_with(|| serde_json::json!({}));
+        let server_signatures = signatures
+            .as_object_mut()
+            .ok_or_else(|| BridgeError::InvalidRequest {
+                message: "signatures must be an object".to_string()
+            })?
+            .entry(server_name.to_string())
+            .or_insert_with(|| serde_json::json!({}));
+        server_signatures[self.key_id()] = serde_json::Value::String(signature);
+
+        Ok(())
+    }
+
+    /// Build the `Authorization` header value for an outgoing federation request.
+    pub fn sign_request(
+        &self,
+        method: &str,
+        uri: &str,
+        origin: &str,
+        destination: Option<&str>,
+        content: Option<&serde_json::Value>,
+    ) -> Result<String> {
+        let request_json = federation_request_json(method, uri, origin, destination, content);
+
+        let auth = XMatrixAuth {
+            origin: origin.to_string(),
+            destination: destination.map(str::to_string),
+            key: self.key_id(),
+            sig: self.sign_json(&request_json)?,
+        };
+
+        Ok(auth.to_header())
+    }
+
+    /// The signed `/_matrix/key/v2/server` response body for `server_name`.
+    pub fn server_keys_response(&self, server_name: &str) -> Result<serde_json::Value> {
+        let valid_until_ts = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64
+            + KEY_VALIDITY_MS;
+
+        let mut response = serde_json::json!({
+            "server_name": server_name,
+            "valid_until_ts": valid_until_ts,
+            "verify_keys": {
+                self.key_id(): { "key": self.public_key_base64() }
+            },
+            "old_verify_keys": {}
+        });
+        self.add_signature(&mut response, server_name)?;
+
+        Ok(response)
+    }
+}
+
+/// The JSON object covered by an X-Matrix request signature.
+pub fn federation_request_json(
+    method: &str,
+    uri: &str,
+    origin: &str,
+    destination: Option<&str>,
+    content: Option<&serde_json::Value>,
+) -> serde_json::Value {
+    let mut request_json = serde_json::json!({
+        "method": method.to_uppercase(),
+        "uri": uri,
+        "origin": origin,
+    });
+    if let Some(destination) = destination {
+        request_json["destination"] = serde_json::json!(destination);
+    }
+    if let Some(content) = content {
+        request_json["content"] = content.clone();
+    }
+
+    request_json
+}
+
+/// Check `server_name`'s signature with `key_id` on a signed JSON object.
+pub fn verify_json(
+    value: &serde_json::Value,
+    server_name: &str,
+    key_id: &str,
+    verify_key: &VerifyingKey,
+) -> Result<()> {
+    let unauthorized = |reason: &str| BridgeError::Auth {
+        message: format!("Signature from {} ({}) {}", server_name, key_id, reason)
+    };
+
+    let signature = value
+        .get("signatures")
+        .and_then(|s| s.get(server_name))
+        .and_then(|s| s.get(key_id))
+        .and_then(|s| s.as_str())
+        .ok_or_else(|| unauthorized("is missing"))?;
+    let signature = decode_base64(signature)
+        .ok()
+        .and_then(|bytes| Signature::from_slice(&bytes).ok())
+        .ok_or_else(|| unauthorized("is malformed"))?;
+
+    let mut value = value.clone();
+    if let Some(object) = value.as_object_mut() {
+        object.remove("signatures");
+        object.remove("unsigned");
+    }
+
+    verify_key
+        .verify_strict(canonical_json(&value)?.as_bytes(), &signature)
+        .map_err(|_| unauthorized("does not match"))
+}
+
+/// Check the signature of an incoming request's `X-Matrix` header.
+pub fn verify_request(
+    auth: &XMatrixAuth,
+    method: &str,
+    uri: &str,
+    content: Option<&serde_json::Value>,
+    verify_key: &VerifyingKey,
+) -> Result<()> {
+    let mut request_json = federation_request_json(method, uri, &auth.origin, auth.destination.as_deref(), content);
+    request_json["signatures"] = serde_json::json!({
+        auth.origin.clone(): { auth.key.clone(): auth.sig.clone() }
+    });
+
+    verify_json(&request_json, &auth.origin, &auth.key, verify_key)
+}
+
+/// Parse an unpadded (or padded) base64 ed25519 public key.
+pub fn parse_verify_key(key: &str) -> Result<VerifyingKey> {
+    let bytes: [u8; 32] = decode_base64(key)?
+        .try_into()
+        .map_err(|_| BridgeError::Auth {
+            message: "Verify key must be 32 bytes".to_string()
+        })?;
+
+    VerifyingKey::from_bytes(&bytes).map_err(|e| BridgeError::Auth {
+        message: format!("Invalid verify key: {}", e)
+    })
+}
+
+fn decode_base64(value: &str) -> Result<Vec<u8>> {
+    STANDARD_NO_PAD
+        .decode(value.trim_end_matches('='))
+        .map_err(|e| BridgeError::Auth {
+            message: format!("Invalid base64: {}", e)
+        })
+}
+
+/// A room version, as far as it decides how events are redacted and how
+/// their IDs are derived. Only the stable versions 1 to 11 are known; events
+/// of any other room can't be checked, so they're refused rather than guessed at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RoomVersion(u8);
+
+impl RoomVersion {
+    pub fn parse(version: &str) -> Result<Self> {
+        version.parse::<u8>()
+            .ok()
+            .filter(|v| (1..=NEWEST_ROOM_VERSION).contains(v) && v.to_string() == version)
+            .map(Self)
+            .ok_or_else(|| BridgeError::InvalidRequest {
+                message: format!("Unsupported room version {}", version)
+            })
+    }
+
+    /// The version named by the content of a room's `m.room.create` event,
+    /// which is "1" when it names none.
+    pub fn of_create_content(content: &serde_json::Value) -> Result<Self> {
+        match content.get("room_version") {
+            None => Ok(Self(1)),
+            Some(serde_json::Value::String(version)) => Self::parse(version),
+            Some(other) => Err(BridgeError::InvalidRequest {
+                message: format!("Invalid room version {}", other)
+            }),
+        }
+    }
+
+    /// Whether events carry no `event_id` and are named by their reference
+    /// hash instead, as from room version 3 on.
+    pub fn has_hashed_event_ids(self) -> bool {
+        self.0 >= 3
+    }
+}
+
+impl std::fmt::Display for RoomVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// `pdu` as the redaction algorithm of room `version` leaves it: only the keys
+/// events need for auth, and only the content keys of a few state events.
+pub fn redact_pdu(pdu: &serde_json::Value, version: RoomVersion) -> serde_json::Value {
+    let Some(fields) = pdu.as_object() else {
+        return pdu.clone();
+    };
+    let RoomVersion(v) = version;
+    let event_type = fields.get("type").and_then(|v| v.as_str()).unwrap_or_default();
+    let mut kept_content: Vec<&str> = match event_type {
+        "m.room.member" => vec!["membership"],
+        "m.room.create" if v < 11 => vec!["creator"],
+        "m.room.join_rules" => vec!["join_rule"],
+        "m.room.power_levels" => vec![
+            "ban", "events", "events_default", "kick", "redact", "state_default", "users", "users_default",
+        ],
+        "m.room.aliases" if v < 6 => vec!["aliases"],
+        "m.room.history_visibility" => vec!["history_visibility"],
+        "m.room.redaction" if v >= 11 => vec!["redacts"],
+        _ => Vec::new(),
+    };
+    match event_type {
+        "m.room.member" if v >= 9 => kept_content.push("join_authorised_via_users_server"),
+        "m.room.join_rules" if v >= 8 => kept_content.push("allow"),
+        "m.room.power_levels" if v >= 11 => kept_content.push("invite"),
+        _ => {}
+    }
+
+    let mut redacted: serde_json::Map<String, serde_json::Value> = fields.iter()
+        .filter(|(key, _)| {
+            REDACTION_KEPT_KEYS.contains(&key.as_str())
+                || (v < 11 && PRE_V11_REDACTION_KEPT_KEYS.contains(&key.as_str()))
+        })
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+    let original_content = fields.get("content").and_then(|v| v.as_object());
+    let mut content: serde_json::Map<String, serde_json::Value> = original_content
+        .into_iter()
+        .flatten()
+        .filter(|(key, _)| (event_type == "m.room.create" && v >= 11) || kept_content.contains(&key.as_str()))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+    // Room version 11 keeps the signed part of a third-party invite's membership
+    if event_type == "m.room.member" && v >= 11 {
+        if let Some(signed) = original_content.and_then(|c| c.get("third_party_invite")).and_then(|i| i.get("signed")) {
+            content.insert("third_party_invite".to_string(), serde_json::json!({"signed": signed}));
+        }
+    }
+    redacted.insert("content".to_string(), serde_json::Value::Object(content));
+    serde_json::Value::Object(redacted)
+}
+
+/// The event ID of a PDU in room version 3 or later, which it doesn't carry:
+/// `$` and the unpadded base64 sha256 of its redacted canonical JSON, in the
+/// URL-safe alphabet from room version 4 on.
+pub fn reference_event_id(pdu: &serde_json::Value, version: RoomVersion) -> Result<String> {
+    if !version.has_hashed_event_ids() {
+        return Err(BridgeError::InvalidRequest {
+            message: format!("Events of room version {} carry their own event IDs", version)
+        });
+    }
+    let mut redacted = redact_pdu(pdu, version);
+    if let Some(fields) = redacted.as_object_mut() {
+        fields.remove("signatures");
+        fields.remove("unsigned");
+    }
+    let hash = Sha256::digest(canonical_json(&redacted)?.as_bytes());
+    let encoded = match version {
+        RoomVersion(3) => STANDARD_NO_PAD.encode(hash),
+        _ => URL_SAFE_NO_PAD.encode(hash),
+    };
+    Ok(format!("${}", encoded))
+}
+
+/// The content hash a PDU's `hashes.sha256` should hold: the unpadded base64
+/// sha256 of its canonical JSON without `unsigned`, `signatures` and `hashes`.
+pub fn content_hash(pdu: &serde_json::Value) -> Result<String> {
+    let mut hashed = pdu.clone();
+    if let Some(fields) = hashed.as_object_mut() {
+        fields.remove("unsigned");
+        fields.remove("signatures");
+        fields.remove("hashes");
+    }
+    Ok(STANDARD_NO_PAD.encode(Sha256::digest(canonical_json(&hashed)?.as_bytes())))
+}
+
+/// Parsed `Authorization: X-Matrix ...` header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct XMatrixAuth {
+    pub origin: String,
+    pub destination: Option<String>,
+    pub key: String,
+    pub sig: String,
+}
+
+impl XMatrixAuth {
+    /// Parse an `X-Matrix` authorization header; values may be quoted or bare.
+    pub fn parse(header: &str) -> Option<Self> {
+        let (scheme, params) = header.trim().split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("X-Matrix") {
+            return None;
+        }
+
+        let mut origin = None;
+        let mut destination = None;
+        let mut key = None;
+        let mut sig = None;
+
+        for pair in params.split(',') {
+            let Some((name, value)) = pair.split_once('=') else { continue };
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .unwrap_or(value)
+                .replace("\\\"", "\"")
+                .replace("\\\\", "\\");
+
+            match name.trim().to_ascii_lowercase().as_str() {
+                "origin" => origin = Some(value),
+                "destination" => destination = Some(value),
+                "key" => key = Some(value),
+                "sig" => sig = Some(value),
+                _ => {}
+            }
+        }
+
+        Some(Self {
+            origin: origin.filter(|v| !v.is_empty())?,
+            destination: destination.filter(|v| !v.is_empty()),
+            key: key.filter(|v| !v.is_empty())?,
+            sig: sig.filter(|v| !v.is_empty())?,
+        })
+    }
+
+    pub fn to_header(&self) -> String {
+        match &self.destination {
+            Some(destination) => format!(
+                "X-Matrix origin=\"{}\",destination=\"{}\",key=\"{}\",sig=\"{}\"",
+                self.origin, destination, self.key, self.sig
+            ),
+            None => format!(
+                "X-Matrix origin=\"{}\",key=\"{}\",sig=\"{}\"",
+                self.origin, self.key, self.sig
+            ),
+        }
+    }
+}
+
+/// Encode a value as Matrix canonical JSON: object keys sorted by codepoint,
+/// no insignificant whitespace, and only integers in the IEEE-754 safe range.
+pub fn canonical_json(value: &serde_json::Value) -> Result<String> {
+    let mut out = String::new();
+    write_canonical(value, &mut out)?;
+    Ok(out)
+}
+
+fn write_canonical(value: &serde_json::Value, out: &mut String) -> Result<()> {
+    match value {
+        serde_json::Value::Null | serde_json::Value::Bool(_) | serde_json::Value::String(_) => {
+            out.push_str(&serde_json::to_string(value)?);
+        }
+        serde_json::Value::Number(number) => {
+            let in_range = number
+                .as_i64()
+                .is_some_and(|n| (-CANONICAL_JSON_MAX_INT..=CANONICAL_JSON_MAX_INT).contains(&n));
+            if !in_range {
+                return Err(BridgeError::Serde {
+                    message: format!("Number {} is not allowed in canonical JSON", number)
+                });
+            }
+            out.push_str(&number.to_string());
+        }
+        serde_json::Value::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_canonical(item, out)?;
+            }
+            out.push(']');
+        }
+        serde_json::Value::Object(object) => {
+            let mut entries: Vec<_> = object.iter().collect();
+            entries.sort_by_key(|(key, _)| *key);
+
+            out.push('{');
+            for (i, (key, item)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&serde_json::to_string(key)?);
+                out.push(':');
+                write_canonical(item, out)?;
+            }
+            out.push('}');
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_canonical_json() {
+        let value = serde_json::json!({
+            "b": "2",
+            "a": 1,
+            "日本語": "日本語",
+            "nested": {"z": [3, {"y": null, "x": true}], "m": "\u{1}"}
+        });
+
+        assert_eq!(
+            canonical_json(&value).unwrap(),
+            r#"{"a":1,"b":"2","nested":{"m":"\u0001","z":[3,{"x":true,"y":null}]},"日本語":"日本語"}"#
+        );
+
+        assert!(canonical_json(&serde_json::json!({"a": 1.5})).is_err());
+        assert!(canonical_json(&serde_json::json!({"a": 1u64 << 53})).is_err());
+    }
+
+    #[test]
+    fn test_signing_key_roundtrip_and_signatures() {
+        let key = ServerSigningKey::generate();
+        let parsed = ServerSigningKey::parse(&key.to_file_contents()).unwrap();
+        assert_eq!(parsed.key_id(), key.key_id());
+        assert_eq!(parsed.public_key_base64(), key.public_key_base64());
+
+        // Signatures don't depend on key order or existing signatures
+        let a = key.sign_json(&serde_json::json!({"one": 1, "two": "Two"})).unwrap();
+        let b = key.sign_json(&serde_json::json!({"two": "Two", "one": 1, "signatures": {}})).unwrap();
+        assert_eq!(a, b);
+
+        let keys = key.server_keys_response("bridge.example.com").unwrap();
+        assert_eq!(keys["server_name"], "bridge.example.com");
+        assert!(keys["signatures"]["bridge.example.com"][key.key_id()].is_string());
+        assert_eq!(keys["verify_keys"][key.key_id()]["key"], key.public_key_base64());
+        let verify_key = parse_verify_key(keys["verify_keys"][key.key_id()]["key"].as_str().unwrap()).unwrap();
+        verify_json(&keys, "bridge.example.com", &key.key_id(), &verify_key).unwrap();
+    }
+
+    #[test]
+    fn test_x_matrix_header() {
+        let key = ServerSigningKey::generate();
+        let header = key
+            .sign_request("put", "/_matrix/federation/v1/send/1", "origin.example.com", Some("dest.example.com"), Some(&serde_json::json!({"pdus": []})))
+            .unwrap();
+
+        let auth = XMatrixAuth::parse(&header).unwrap();
+        assert_eq!(auth.origin, "origin.example.com");
+        assert_eq!(auth.destination.as_deref(), Some("dest.example.com"));
+        assert_eq!(auth.key, key.key_id());
+        assert_eq!(auth.to_header(), header);
+
+        let content = serde_json::json!({"pdus": []});
+        verify_request(&auth, "PUT", "/_matrix/federation/v1/send/1", Some(&content), &key.verifying_key()).unwrap();
+        assert!(verify_request(&auth, "PUT", "/_matrix/federation/v1/send/2", Some(&content), &key.verifying_key()).is_err());
+        assert!(verify_request(&auth, "PUT", "/_matrix/federation/v1/send/1", None, &key.verifying_key()).is_err());
+        let other = ServerSigningKey::generate();
+        assert!(verify_request(&auth, "PUT", "/_matrix/federation/v1/send/1", Some(&content), &other.verifying_key()).is_err());
+
+        let unquoted = XMatrixAuth::parse("X-Matrix origin=origin.example.com,key=ed25519:1,sig=abc").unwrap();
+        assert_eq!(unquoted.destination, None);
+        assert!(XMatrixAuth::parse("Bearer token").is_none());
+    }
+
+    #[test]
+    fn test_reference_event_id() {
+        let v10 = RoomVersion::parse("10").unwrap();
+        let pdu = serde_json::json!({
+            "type": "m.room.member", "room_id": "!r:example.com", "sender": "@a:example.com",
+            "state_key": "@a:example.com", "origin_server_ts": 1, "depth": 2, "origin": "example.com",
+            "prev_events": ["$1"], "auth_events": [], "hashes": {"sha256": "abc"},
+            "content": {"membership": "join", "displayname": "A"}
+        });
+        let redacted = redact_pdu(&pdu, v10);
+        assert_eq!(redacted["content"], serde_json::json!({"membership": "join"}));
+        assert_eq!(redacted["hashes"], pdu["hashes"]);
+        assert_eq!(redacted["origin"], pdu["origin"]);
+
+        let event_id = reference_event_id(&pdu, v10).unwrap();
+        assert_eq!(event_id.len(), 44);
+        assert!(event_id.starts_with('$') && !event_id.contains(['+', '/', '=']));
+
+        // Signatures, unsigned data and redacted content don't change the ID
+        let mut signed = pdu.clone();
+        signed["signatures"] = serde_json::json!({"example.com": {"ed25519:1": "sig"}});
+        signed["unsigned"] = serde_json::json!({"age": 5});
+        signed["content"]["displayname"] = serde_json::json!("B");
+        assert_eq!(reference_event_id(&signed, v10).unwrap(), event_id);
+
+        let mut left = pdu.clone();
+        left["content"]["membership"] = serde_json::json!("leave");
+        assert_ne!(reference_event_id(&left, v10).unwrap(), event_id);
+
+        // Room version 3 hashes the same way but spells the hash in standard base64
+        let v3_id = reference_event_id(&pdu, RoomVersion::parse("3").unwrap()).unwrap();
+        let standard = v3_id[1..].replace('+', "-").replace('/', "_");
+        assert_eq!(standard, event_id[1..]);
+        assert!(reference_event_id(&pdu, RoomVersion::parse("2").unwrap()).is_err());
+
+        // Room version 11 drops `origin`, so the same event hashes differently
+        let v11 = RoomVersion::parse("11").unwrap();
+        assert!(redact_pdu(&pdu, v11).get("origin").is_none());
+        assert_ne!(reference_event_id(&pdu, v11).unwrap(), event_id);
+    }
+
+    #[test]
+    fn test_redaction_by_room_version() {
+        let create = serde_json::json!({
+            "type": "m.room.create", "state_key": "",
+            "content": {"creator": "@a:example.com", "room_version": "11", "m.federate": false}
+        });
+        assert_eq!(redact_pdu(&create, RoomVersion::parse("10").unwrap())["content"], serde_json::json!({"creator": "@a:example.com"}));
+        assert_eq!(redact_pdu(&create, RoomVersion::parse("11").unwrap())["content"], create["content"]);
+
+        let join_rules = serde_json::json!({"type": "m.room.join_rules", "content": {"join_rule": "restricted", "allow": []}});
+        assert_eq!(redact_pdu(&join_rules, RoomVersion::parse("7").unwrap())["content"], serde_json::json!({"join_rule": "restricted"}));
+        assert_eq!(redact_pdu(&join_rules, RoomVersion::parse("8").unwrap())["content"], join_rules["content"]);
+
+        let aliases = serde_json::json!({"type": "m.room.aliases", "content": {"aliases": ["#a:example.com"]}});
+        assert_eq!(redact_pdu(&aliases, RoomVersion::parse("5").unwrap())["content"], aliases["content"]);
+        assert_eq!(redact_pdu(&aliases, RoomVersion::parse("6").unwrap())["content"], serde_json::json!({}));
+
+        assert_eq!(RoomVersion::of_create_content(&serde_json::json!({})).unwrap(), RoomVersion::parse("1").unwrap());
+        assert_eq!(RoomVersion::of_create_content(&create["content"]).unwrap(), RoomVersion::parse("11").unwrap());
+        for unsupported in ["12", "0", "org.example.custom", "010"] {
+            assert!(RoomVersion::parse(unsupported).is_err(), "{}", unsupported);
+        }
+    }
+}
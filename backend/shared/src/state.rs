@@ -0,0 +1,95 @@
+use std::collections::HashSet;
+
+use crate::types::MatrixEvent;
+
+/// Whether `server_name` has a joined member according to `state`.
+pub fn server_in_room(state: &[MatrixEvent], server_name: &str) -> bool {
+    state.iter().any(|event| {
+        event.event_type == "m.room.member"
+            && event.content.get("membership").and_then(|v| v.as_str()) == Some("join")
+            && event.state_key.as_deref()
+                .and_then(|user_id| user_id.split_once(':'))
+                .is_some_and(|(_, server)| server == server_name)
+    })
+}
+
+/// The events of `state` that authorize it: the create event, power levels,
+/// the membership of every sender and, for memberships, the join rules. For
+/// events stored before their `auth_events` were kept, the chain is taken
+/// from the state itself rather than followed event by event.
+pub fn auth_chain_from_state(state: &[MatrixEvent]) -> Vec<MatrixEvent> {
+    let mut needed: HashSet<(&str, &str)> = HashSet::new();
+    for event in state {
+        needed.insert(("m.room.create", ""));
+        needed.insert(("m.room.power_levels", ""));
+        needed.insert(("m.room.member", event.sender.as_str()));
+        if event.event_type == "m.room.member" {
+            needed.insert(("m.room.join_rules", ""));
+            needed.insert(("m.room.third_party_invite", ""));
+        }
+    }
+
+    let mut chain: Vec<MatrixEvent> = state.iter()
+        .filter(|event| {
+            let state_key = event.state_key.as_deref().unwrap_or_default();
+            needed.contains(&(event.event_type.as_str(), state_key))
+        })
+        .cloned()
+        .collect();
+    chain.sort_by_key(|event| event.origin_server_ts);
+    chain
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn state_event(event_type: &str, state_key: &str, sender: &str, ts: u64, content: serde_json::Value) -> MatrixEvent {
+        MatrixEvent {
+            event_id: format!("${}", ts),
+            event_type: event_type.to_string(),
+            room_id: "!room:a.example.com".to_string(),
+            sender: sender.to_string(),
+            origin_server_ts: ts,
+            content,
+            state_key: Some(state_key.to_string()),
+            ..MatrixEvent::default()
+        }
+    }
+
+    #[test]
+    fn test_auth_chain_from_state() {
+        let state = vec![
+            state_event("m.room.create", "", "@alice:a.example.com", 1, json!({"creator": "@alice:a.example.com"})),
+            state_event("m.room.member", "@alice:a.example.com", "@alice:a.example.com", 2, json!({"membership": "join"})),
+            state_event("m.room.power_levels", "", "@alice:a.example.com", 3, json!({})),
+            state_event("m.room.join_rules", "", "@alice:a.example.com", 4, json!({"join_rule": "public"})),
+            state_event("m.room.member", "@bob:b.example.com", "@bob:b.example.com", 5, json!({"membership": "join"})),
+            state_event("m.room.member", "@carol:c.example.com", "@carol:c.example.com", 6, json!({"membership": "leave"})),
+            state_event("m.room.topic", "", "@alice:a.example.com", 7, json!({"topic": "hi"})),
+        ];
+
+        let ids: Vec<String> = auth_chain_from_state(&state).into_iter().map(|event| event.event_id).collect();
+        assert_eq!(ids, vec!["$1", "$2", "$3", "$4", "$5", "$6"]);
+
+        assert!(server_in_room(&state, "b.example.com"));
+        assert!(!server_in_room(&state, "c.example.com"));
+        assert!(!server_in_room(&state, "d.example.com"));
+    }
+
+    #[test]
+    fn test_pdu_event_references() {
+        // Room versions 1 and 2 pair references with hashes, later ones don't
+        let pdu = json!({
+            "event_id": "$3", "type": "m.room.message", "room_id": "!room:a.example.com",
+            "sender": "@alice:a.example.com", "origin_server_ts": 3, "content": {"body": "hi"},
+            "prev_events": [["$2", {"sha256": "abc"}]], "auth_events": ["$1"], "depth": 3
+        });
+        let event: MatrixEvent = serde_json::from_value(pdu).unwrap();
+        assert_eq!(event.prev_events, vec!["$2"]);
+        assert_eq!(event.auth_events, vec!["$1"]);
+        assert_eq!(event.to_pdu()["prev_events"], json!(["$2"]));
+        assert_eq!(event.to_pdu()["depth"], 3);
+    }
+}
@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::{Mutex, OnceCell};
+use tokio::time::{Duration, Instant};
+
+use crate::error::{BridgeError, Result};
+use crate::types::{Transaction, TransactionResponse};
+
+/// Most PDUs and EDUs a single federation transaction may carry.
+pub const MAX_TRANSACTION_PDUS: usize = 50;
+pub const MAX_TRANSACTION_EDUS: usize = 100;
+
+/// How long a processed transaction's response is kept to answer retries with.
+const TRANSACTION_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Processed transactions remembered at most, oldest dropped first.
+const MAX_CACHED_TRANSACTIONS: usize = 10_000;
+
+/// Reject transactions over the spec's size limits.
+pub fn check_transaction_limits(transaction: &Transaction) -> Result<()> {
+    if transaction.pdus.len() > MAX_TRANSACTION_PDUS {
+        return Err(BridgeError::InvalidRequest {
+            message: format!("Transaction has {} PDUs, at most {} are allowed", transaction.pdus.len(), MAX_TRANSACTION_PDUS)
+        });
+    }
+    if transaction.edus.len() > MAX_TRANSACTION_EDUS {
+        return Err(BridgeError::InvalidRequest {
+            message: format!("Transaction has {} EDUs, at most {} are allowed", transaction.edus.len(), MAX_TRANSACTION_EDUS)
+        });
+    }
+    Ok(())
+}
+
+struct CachedTransaction {
+    response: Arc<OnceCell<TransactionResponse>>,
+    received_at: Instant,
+}
+
+/// Responses to recently processed transactions, keyed by origin and txn id.
+/// A retried transaction gets the original response without being processed
+/// again, and one retried while still in flight waits for the first attempt.
+#[derive(Default)]
+pub struct TransactionCache {
+    entries: Mutex<HashMap<(String, String), CachedTransaction>>,
+}
+
+impl TransactionCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The response to transaction `txn_id` from `origin`, running `process`
+    /// only if it hasn't been seen before.
+    pub async fn get_or_process<F, Fut>(&self, origin: &str, txn_id: &str, process: F) -> TransactionResponse
+    where
+        F: FnOnce() -> Fut,
+        Fut: std::future::Future<Output = TransactionResponse>,
+    {
+        let response = {
+            let mut entries = self.entries.lock().await;
+            let now = Instant::now();
+            entries.retain(|_, cached| now.duration_since(cached.received_at) < TRANSACTION_TTL);
+            if entries.len() >= MAX_CACHED_TRANSACTIONS {
+                let oldest = entries.iter()
+                    .min_by_key(|(_, cached)| cached.received_at)
+                    .map(|(key, _)| key.clone());
+                if let Some(oldest) = oldest {
+                    entries.remove(&oldest);
+                }
+            }
+
+            entries.entry((origin.to_string(), txn_id.to_string()))
+                .or_insert_with(|| CachedTransaction {
+                    response: Arc::new(OnceCell::new()),
+                    received_at: now,
+                })
+                .response
+                .clone()
+        };
+
+        response.get_or_init(process).await.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    #[tokio::test]
+    async fn test_transactions_processed_once_per_origin() {
+        let cache = TransactionCache::new();
+        let processed = AtomicU32::new(0);
+        let process = || async {
+            processed.fetch_add(1, Ordering::Relaxed);
+            TransactionResponse::default()
+        };
+
+        cache.get_or_process("a.example.com", "txn1", process).await;
+        cache.get_or_process("a.example.com", "txn1", process).await;
+        cache.get_or_process("b.example.com", "txn1", process).await;
+        assert_eq!(processed.load(Ordering::Relaxed), 2);
+
+        let transaction = Transaction {
+            origin: "a.example.com".to_string(),
+            origin_server_ts: 0,
+            pdus: vec![serde_json::json!({}); MAX_TRANSACTION_PDUS + 1],
+            edus: Vec::new(),
+        };
+        assert!(check_transaction_limits(&transaction).is_err());
+    }
+}
@@ -1,9 +1,11 @@
+use rand::Rng;
 use serde::{Deserialize, Serialize};
 use std::time::{SystemTime, UNIX_EPOCH};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct MatrixEvent {
     pub event_id: String,
+    #[serde(alias = "type")]
     pub event_type: String,
     pub room_id: String,
     pub sender: String,
@@ -11,6 +13,83 @@ pub struct MatrixEvent {
     pub content: serde_json::Value,
     #[serde(default)]
     pub state_key: Option<String>,
+    /// The room's latest events when this one was created, its parents in the DAG.
+    #[serde(default, deserialize_with = "event_references")]
+    pub prev_events: Vec<String>,
+    /// The state events authorizing this one.
+    #[serde(default, deserialize_with = "event_references")]
+    pub auth_events: Vec<String>,
+    #[serde(default)]
+    pub depth: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hashes: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signatures: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unsigned: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub redacts: Option<String>,
+}
+
+/// Event IDs referenced by a PDU. Room versions 1 and 2 pair each ID with the
+/// event's hashes as `[event_id, {hashes}]`; later versions list bare IDs.
+fn event_references<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Reference {
+        Id(String),
+        WithHashes(String, serde::de::IgnoredAny),
+    }
+
+    let references = Vec::<Reference>::deserialize(deserializer)?;
+    Ok(references.into_iter()
+        .map(|reference| match reference {
+            Reference::Id(id) | Reference::WithHashes(id, _) => id,
+        })
+        .collect())
+}
+
+impl MatrixEvent {
+    /// The event in federation PDU form.
+    pub fn to_pdu(&self) -> serde_json::Value {
+        let mut pdu = serde_json::json!({
+            "event_id": self.event_id,
+            "type": self.event_type,
+            "room_id": self.room_id,
+            "sender": self.sender,
+            "origin_server_ts": self.origin_server_ts,
+            "content": self.content
+        });
+        if let Some(state_key) = &self.state_key {
+            pdu["state_key"] = serde_json::json!(state_key);
+        }
+        pdu["prev_events"] = serde_json::json!(self.prev_events);
+        pdu["auth_events"] = serde_json::json!(self.auth_events);
+        pdu["depth"] = serde_json::json!(self.depth);
+        for (key, value) in [("hashes", &self.hashes), ("signatures", &self.signatures), ("unsigned", &self.unsigned)] {
+            if let Some(value) = value {
+                pdu[key] = value.clone();
+            }
+        }
+        if let Some(redacts) = &self.redacts {
+            pdu["redacts"] = serde_json::json!(redacts);
+        }
+        pdu
+    }
+
+    /// Parse a PDU. Ones relayed over Mycelium also carry the type as
+    /// `event_type`, which would otherwise clash with `type`.
+    pub fn from_pdu(mut pdu: serde_json::Value) -> serde_json::Result<Self> {
+        if let Some(fields) = pdu.as_object_mut() {
+            if fields.contains_key("type") {
+                fields.remove("event_type");
+            }
+        }
+        serde_json::from_value(pdu)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -21,14 +100,19 @@ pub struct MyceliumMessage {
     pub timeout: u32, // seconds
 }
 
+/// A message popped from the local Mycelium node's `/api/v1/messages` endpoint.
+/// Topic and payload are base64 encoded.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct MatrixFederationMessage {
-    pub message_type: String,
-    pub room_id: Option<String>,
-    pub sender: String,
-    pub origin_server_ts: u64,
-    pub content: serde_json::Value,
-    pub signature: String,
+#[serde(rename_all = "camelCase")]
+pub struct MyceliumInboundMessage {
+    pub id: String,
+    pub src_ip: String,
+    pub src_pk: String,
+    pub dst_ip: String,
+    pub dst_pk: String,
+    #[serde(default)]
+    pub topic: Option<String>,
+    pub payload: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -39,6 +123,18 @@ pub struct MyceliumFederationMessage {
     pub origin_server_ts: u64,
     pub payload: serde_json::Value,
     pub destination: String, // Mycelium public key
+    /// Transport message id assigned by the Mycelium node, used to reply.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
+    /// Public key of the node that sent the message.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_pubkey: Option<String>,
+    /// Id from the message's envelope header, echoed as the correlation id of replies.
+    #[serde(skip)]
+    pub envelope_id: Option<uuid::Uuid>,
+    /// Envelope flags, advertising which compression the sender accepts.
+    #[serde(skip)]
+    pub envelope_flags: u16,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -62,6 +158,208 @@ pub struct FederationRoute {
     pub mycelium_key: String,
     pub last_successful: i64, // timestamp
     pub latency_ms: i64,
+    /// Probes that failed in a row since the last successful one.
+    #[serde(default)]
+    pub consecutive_failures: i32,
+    /// Cleared once too many probes fail in a row; unhealthy routes aren't used.
+    #[serde(default = "default_healthy")]
+    pub healthy: bool,
+    /// Latency of classic federation to the same server, for comparison.
+    #[serde(default)]
+    pub matrix_latency_ms: Option<i64>,
+    #[serde(default)]
+    pub last_probed: Option<i64>,
+    /// Every Mycelium node serving the destination; `mycelium_key` is the preferred one.
+    #[serde(default)]
+    pub endpoints: Vec<MyceliumEndpoint>,
+    /// Learned through route discovery or announcements rather than configured,
+    /// so checked against what the server advertises once that answer expires.
+    #[serde(default)]
+    pub discovered: bool,
+}
+
+fn default_healthy() -> bool {
+    true
+}
+
+impl FederationRoute {
+    /// A route to a single Mycelium node that hasn't been probed yet.
+    pub fn new(destination_server: String, mycelium_key: String, last_successful: i64) -> Self {
+        let endpoints = vec![MyceliumEndpoint::new(mycelium_key)];
+        Self::with_endpoints(destination_server, endpoints, last_successful)
+    }
+
+    /// A route to several Mycelium nodes that hasn't been probed yet.
+    /// `endpoints` must not be empty.
+    pub fn with_endpoints(destination_server: String, endpoints: Vec<MyceliumEndpoint>, last_successful: i64) -> Self {
+        let mut route = Self {
+            destination_server,
+            mycelium_key: String::new(),
+            last_successful,
+            latency_ms: 0,
+            consecutive_failures: 0,
+            healthy: true,
+            matrix_latency_ms: None,
+            last_probed: None,
+            endpoints: Vec::new(),
+            discovered: false,
+        };
+        route.set_endpoints(endpoints);
+        route
+    }
+
+    /// A route to the single Mycelium node route discovery found for the destination.
+    pub fn discovered(destination_server: String, mycelium_key: String, last_successful: i64) -> Self {
+        Self {
+            discovered: true,
+            ..Self::new(destination_server, mycelium_key, last_successful)
+        }
+    }
+
+    /// Replace the endpoint set, keeping health and latency. `endpoints` must not be empty.
+    pub fn set_endpoints(&mut self, mut endpoints: Vec<MyceliumEndpoint>) {
+        endpoints.sort_by(|a, b| a.priority.cmp(&b.priority).then(b.weight.cmp(&a.weight)));
+        self.mycelium_key = endpoints[0].mycelium_key.clone();
+        self.endpoints = endpoints;
+    }
+
+    pub fn has_endpoint(&self, mycelium_key: &str) -> bool {
+        self.mycelium_key.eq_ignore_ascii_case(mycelium_key)
+            || self.endpoints.iter().any(|endpoint| endpoint.mycelium_key.eq_ignore_ascii_case(mycelium_key))
+    }
+
+    /// Keys to try for one request: lower priorities first, and within a
+    /// priority a random order weighted so heavier endpoints tend to go first.
+    pub fn endpoint_order(&self) -> Vec<String> {
+        if self.endpoints.is_empty() {
+            return vec![self.mycelium_key.clone()];
+        }
+
+        let mut rng = rand::thread_rng();
+        let mut ranked: Vec<(i32, f64, &str)> = self.endpoints.iter()
+            .map(|endpoint| {
+                let draw: f64 = rng.gen_range(f64::EPSILON..1.0);
+                (endpoint.priority, draw.powf(1.0 / endpoint.weight.max(1) as f64), endpoint.mycelium_key.as_str())
+            })
+            .collect();
+        ranked.sort_by(|a, b| a.0.cmp(&b.0).then(b.1.total_cmp(&a.1)));
+
+        ranked.into_iter().map(|(_, _, key)| key.to_string()).collect()
+    }
+}
+
+/// One Mycelium node serving a destination server. Lower priorities are tried
+/// first; endpoints sharing a priority split the load by weight.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MyceliumEndpoint {
+    pub mycelium_key: String,
+    #[serde(default)]
+    pub priority: i32,
+    #[serde(default = "default_weight")]
+    pub weight: i32,
+    /// Unix time after which a node learned from its server's announcements
+    /// is dropped unless announced again. Configured nodes never expire.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<i64>,
+}
+
+fn default_weight() -> i32 {
+    1
+}
+
+impl MyceliumEndpoint {
+    pub fn new(mycelium_key: String) -> Self {
+        Self { mycelium_key, priority: 0, weight: 1, expires_at: None }
+    }
+
+    pub fn has_expired(&self, now: i64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// A route as operators write it: either a single `mycelium_key` or a set of
+/// weighted `endpoints`. Used to add routes and in imported and exported route tables.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RouteSpec {
+    pub server_name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mycelium_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub endpoints: Option<Vec<MyceliumEndpoint>>,
+}
+
+impl RouteSpec {
+    pub fn into_endpoints(self) -> crate::error::Result<Vec<MyceliumEndpoint>> {
+        match (self.mycelium_key, self.endpoints) {
+            (Some(mycelium_key), None) => Ok(vec![MyceliumEndpoint::new(mycelium_key)]),
+            (None, Some(endpoints)) => Ok(endpoints),
+            _ => Err(crate::error::BridgeError::InvalidRequest {
+                message: format!("Route for {} needs either mycelium_key or endpoints", self.server_name)
+            }),
+        }
+    }
+}
+
+/// Changes to an existing route; fields left out stay as they are.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RouteUpdate {
+    /// Replace the endpoint set with this single node.
+    #[serde(default)]
+    pub mycelium_key: Option<String>,
+    #[serde(default)]
+    pub endpoints: Option<Vec<MyceliumEndpoint>>,
+    /// Override the health the prober determined until its next probe.
+    #[serde(default)]
+    pub healthy: Option<bool>,
+}
+
+/// Filters and pagination for listing routes.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RouteListQuery {
+    /// Server name glob, where `*` matches any run of characters and `?` one.
+    #[serde(default)]
+    pub server: Option<String>,
+    #[serde(default)]
+    pub healthy: Option<bool>,
+    #[serde(default)]
+    pub circuit: Option<crate::breaker::CircuitState>,
+    #[serde(default)]
+    pub offset: Option<usize>,
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+/// A route with the state of its circuit breaker.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RouteView {
+    #[serde(flatten)]
+    pub route: FederationRoute,
+    pub circuit: Option<crate::breaker::CircuitStatus>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RouteList {
+    pub routes: Vec<RouteView>,
+    /// Routes on this page.
+    pub count: usize,
+    /// Routes matching the filters across all pages.
+    pub total: usize,
+    pub offset: usize,
+    pub limit: usize,
+}
+
+/// Every configured route, in the form routes are imported and exported in.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RouteTable {
+    #[serde(default)]
+    pub routes: Vec<RouteSpec>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RouteImportResult {
+    pub imported: usize,
+    /// Routes dropped because a replacing import didn't list them.
+    pub removed: usize,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -78,6 +376,9 @@ pub struct BridgeStatus {
     pub pending_messages: u32,
     pub last_sync: i64,
     pub mycelium_connected: bool,
+    /// Circuit breakers of destinations Mycelium has failed for at some point.
+    #[serde(default)]
+    pub circuit_breakers: std::collections::HashMap<String, crate::breaker::CircuitStatus>,
 }
 
 impl Default for BridgeStatus {
@@ -90,6 +391,7 @@ impl Default for BridgeStatus {
                 .unwrap()
                 .as_secs() as i64,
             mycelium_connected: false,
+            circuit_breakers: std::collections::HashMap::new(),
         }
     }
 }
@@ -102,8 +404,135 @@ pub struct FederationRequest {
     pub headers: std::collections::HashMap<String, String>,
 }
 
+/// How delivering something to one destination server went.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DestinationResult {
+    pub destination: String,
+    /// `delivered`, `queued` for retry, or `failed`.
+    pub status: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status_code: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl DestinationResult {
+    pub fn from_response(destination: String, response: &FederationResponse) -> Self {
+        let queued = response.status_code == 202
+            && response.body.get("status").and_then(|v| v.as_str()) == Some("queued");
+        let status = match response.status_code {
+            _ if queued => "queued",
+            200..=299 => "delivered",
+            _ => "failed",
+        };
+        let error = (status == "failed").then(|| {
+            response.body.get("error")
+                .and_then(|v| v.as_str())
+                .map(str::to_string)
+                .unwrap_or_else(|| format!("Destination responded with {}", response.status_code))
+        });
+
+        Self { destination, status: status.to_string(), status_code: Some(response.status_code), error }
+    }
+
+    pub fn failed(destination: String, error: &crate::error::BridgeError) -> Self {
+        Self { destination, status: "failed".to_string(), status_code: None, error: Some(error.to_string()) }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FederationResponse {
     pub status_code: u16,
     pub body: serde_json::Value,
+    /// Response headers worth passing back to the caller, e.g. `Retry-After`.
+    #[serde(default, skip_serializing_if = "std::collections::HashMap::is_empty")]
+    pub headers: std::collections::HashMap<String, String>,
+}
+
+/// Room state at an event along with the events authorizing it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomSnapshot {
+    pub state: Vec<MatrixEvent>,
+    pub auth_chain: Vec<MatrixEvent>,
+}
+
+/// The body of a federation `POST /get_missing_events/{roomId}`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MissingEventsRequest {
+    /// Events the requester already has; the walk stops at them.
+    pub earliest_events: Vec<String>,
+    /// Events whose missing ancestors are wanted.
+    pub latest_events: Vec<String>,
+    #[serde(default = "default_missing_events_limit")]
+    pub limit: usize,
+    #[serde(default)]
+    pub min_depth: u64,
+}
+
+fn default_missing_events_limit() -> usize {
+    10
+}
+
+/// The body of a federation `PUT /send/{txnId}`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Transaction {
+    pub origin: String,
+    pub origin_server_ts: u64,
+    #[serde(default)]
+    pub pdus: Vec<serde_json::Value>,
+    #[serde(default)]
+    pub edus: Vec<Edu>,
+}
+
+/// An ephemeral event, such as a typing notification or read receipt.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Edu {
+    pub edu_type: String,
+    #[serde(default)]
+    pub content: serde_json::Value,
+}
+
+/// How processing one PDU of a transaction went; no error means it was accepted.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PduResult {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// The response to a transaction: a result for each of its PDUs by event ID.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransactionResponse {
+    pub pdus: std::collections::BTreeMap<String, PduResult>,
+}
+
+/// The authenticated origin server of an inbound federation request, attached
+/// to the request extensions once its X-Matrix signature has been verified.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FederationOrigin(pub String);
+
+/// A federation request persisted in the outbox until it's been delivered.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutboxEntry {
+    pub id: i64,
+    pub destination: String,
+    pub method: String,
+    pub path: String,
+    pub body: Option<serde_json::Value>,
+    pub headers: serde_json::Value,
+    pub state: String,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub next_attempt_at: i64, // timestamp
+    pub updated_at: i64,      // timestamp
+}
+
+impl OutboxEntry {
+    pub fn to_request(&self) -> FederationRequest {
+        FederationRequest {
+            method: self.method.clone(),
+            path: self.path.clone(),
+            body: self.body.clone(),
+            headers: serde_json::from_value(self.headers.clone()).unwrap_or_default(),
+        }
+    }
 }